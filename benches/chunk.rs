@@ -0,0 +1,37 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rlox::{Chunk, OpCode};
+
+/// A chunk of `len` one-byte instructions spread `per_line` to a line.
+fn chunk(len: usize, per_line: usize) -> Chunk {
+    let mut chunk = Chunk::new_chunk();
+    for offset in 0..len {
+        chunk.write_op_code(OpCode::Nil, 1 + offset / per_line);
+    }
+    chunk
+}
+
+fn get_line(c: &mut Criterion) {
+    for (len, per_line) in [(1_000, 4), (100_000, 4), (100_000, 1_000)] {
+        let chunk = chunk(len, per_line);
+        c.bench_function(&format!("get_line {} / {} per line", len, per_line), |b| {
+            b.iter(|| {
+                for offset in (0..len).step_by(len / 100) {
+                    black_box(chunk.get_line(offset));
+                }
+            })
+        });
+    }
+}
+
+fn put_line(c: &mut Criterion) {
+    c.bench_function("write 1 instruction on line 60000", |b| {
+        b.iter(|| {
+            let mut chunk = Chunk::new_chunk();
+            chunk.write_op_code(OpCode::Nil, black_box(60_000));
+            chunk
+        })
+    });
+}
+
+criterion_group!(benches, get_line, put_line);
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, rc::Rc};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rlox::{
+    internals::{intern, Obj, Table},
+    Value,
+};
+
+/// Names of the kind a script's globals or an instance's fields have.
+fn names(count: usize) -> Vec<String> {
+    (0..count).map(|n| format!("name{}", n)).collect()
+}
+
+/// Looking up every global of a script, as `GetGlobal` does.
+fn globals(c: &mut Criterion) {
+    let mut group = c.benchmark_group("globals");
+    let names = names(32);
+    let keys: Vec<Rc<Obj>> = names.iter().map(|name| intern(name)).collect();
+    let mut table = Table::new();
+    let mut map = HashMap::new();
+    for (n, (name, key)) in names.iter().zip(keys.iter()).enumerate() {
+        table.set(key.clone(), Value::number(n as f64));
+        map.insert(Box::<str>::from(name.as_str()), Value::number(n as f64));
+    }
+    group.bench_function("Table", |b| {
+        b.iter(|| {
+            for key in keys.iter() {
+                black_box(table.get(key));
+            }
+        })
+    });
+    group.bench_function("HashMap", |b| {
+        b.iter(|| {
+            for name in names.iter() {
+                black_box(map.get(name.as_str()));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Creating an instance and setting then reading a few fields, as an
+/// initializer followed by property accesses does.
+fn fields(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fields");
+    let names = names(4);
+    let keys: Vec<Rc<Obj>> = names.iter().map(|name| intern(name)).collect();
+    group.bench_function("Table", |b| {
+        b.iter(|| {
+            let mut fields = Table::new();
+            for key in keys.iter() {
+                fields.set(key.clone(), Value::nil());
+            }
+            for key in keys.iter() {
+                black_box(fields.get(key));
+            }
+        })
+    });
+    group.bench_function("HashMap", |b| {
+        b.iter(|| {
+            let mut fields = HashMap::new();
+            for name in names.iter() {
+                fields.insert(Box::<str>::from(name.as_str()), Value::nil());
+            }
+            for name in names.iter() {
+                black_box(fields.get(name.as_str()));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Overwriting and deleting entries, which leaves tombstones behind.
+fn churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("churn");
+    let names = names(64);
+    let keys: Vec<Rc<Obj>> = names.iter().map(|name| intern(name)).collect();
+    group.bench_function("Table", |b| {
+        let mut table = Table::new();
+        b.iter(|| {
+            for key in keys.iter() {
+                table.set(key.clone(), Value::nil());
+            }
+            for key in keys.iter().step_by(2) {
+                table.delete(key);
+            }
+        })
+    });
+    group.bench_function("HashMap", |b| {
+        let mut map = HashMap::new();
+        b.iter(|| {
+            for name in names.iter() {
+                map.insert(Box::<str>::from(name.as_str()), Value::nil());
+            }
+            for name in names.iter().step_by(2) {
+                map.remove(name.as_str());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, globals, fields, churn);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+//! Compare with and without `--features nan-boxing`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rlox::{
+    internals::{Stack, STACK_MAX},
+    Lowered, Value,
+};
+
+/// Numbers with a string every fourth slot, so objects' reference counts
+/// are part of the cost.
+fn values(count: usize) -> Vec<Value> {
+    let string = Value::string("s");
+    (0..count)
+        .map(|n| match n % 4 {
+            3 => string.clone(),
+            _ => Value::number(n as f64),
+        })
+        .collect()
+}
+
+/// Filling the stack, then folding it down pairwise as binary operators do.
+fn stack(c: &mut Criterion) {
+    let values = values(1024);
+    let mut stack = Stack::new(STACK_MAX);
+    c.bench_function("value stack", |b| {
+        b.iter(|| {
+            for value in values.iter() {
+                stack.push(value.clone()).unwrap();
+            }
+            while stack.len() > 1 {
+                let sum = match (stack.peek(1).as_number(), stack.peek(0).as_number()) {
+                    (Some(a), Some(b)) => Value::number(a + b),
+                    _ => Value::nil(),
+                };
+                stack.replace(2, sum);
+            }
+            black_box(stack.pop());
+        })
+    });
+}
+
+/// Loading every constant of a pool as `Constant` instructions do.
+fn constant_pool(c: &mut Criterion) {
+    let source: String = (0..1024)
+        .map(|n| match n % 4 {
+            3 => format!("print \"s{}\";\n", n),
+            _ => format!("print {};\n", n),
+        })
+        .collect();
+    let chunk = source.as_str().lower().unwrap();
+    c.bench_function("constant pool load", |b| {
+        b.iter(|| {
+            for constant in chunk.constants() {
+                black_box(constant.clone());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, stack, constant_pool);
+criterion_main!(benches);
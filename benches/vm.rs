@@ -0,0 +1,45 @@
+use std::io;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::{
+    internals::{InterpretResult, VM},
+    Chunk, Lowered,
+};
+
+const FIB: &str = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                   var result = fib(30);";
+
+const CONCAT: &str = "var s = \"\"; for (var i = 0; i < 10000; i = i + 1) { s = s + \"ab\"; }";
+
+const LOOPS: &str = "var total = 0;\n\
+                     for (var i = 0; i < 1000; i = i + 1) {\n\
+                         var j = 0;\n\
+                         while (j < 1000) { total = total + j; j = j + 1; }\n\
+                     }";
+
+const FIELDS: &str = "class Point { init(x, y) { this.x = x; this.y = y; } }\n\
+                      var p = Point(0, 0);\n\
+                      for (var i = 0; i < 100000; i = i + 1) { p.x = p.x + p.y; p.y = i; }";
+
+fn run(chunk: &Chunk) {
+    let mut vm = VM::with_output(Box::new(io::sink()), Box::new(io::sink()));
+    assert_eq!(vm.interpret_chunk(chunk), InterpretResult::Ok);
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    group.sample_size(10);
+    for (name, source) in [
+        ("fib(30)", FIB),
+        ("string concat", CONCAT),
+        ("loops", LOOPS),
+        ("fields", FIELDS),
+    ] {
+        let chunk = source.lower().unwrap();
+        group.bench_function(name, |b| b.iter(|| run(&chunk)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{
+    internals::{optimize, Checked},
+    verify, Lowered,
+};
+
+fuzz_target!(|source: &str| {
+    source.check();
+    if let Ok(chunk) = source.lower() {
+        // Whatever the compiler and optimizer emit, the VM must accept.
+        verify(&chunk).expect("compiled bytecode verifies");
+        verify(&optimize(&chunk)).expect("optimized bytecode verifies");
+    }
+});
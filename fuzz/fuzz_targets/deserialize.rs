@@ -0,0 +1,18 @@
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{internals::VM, Capabilities, Chunk};
+
+fuzz_target!(|bytes: &[u8]| {
+    // Anything that loads has been verified, so it must run without
+    // crashing, however it was made.
+    if let Ok(chunk) = Chunk::deserialize(bytes) {
+        VM::with_output(Box::new(io::sink()), Box::new(io::sink()))
+            .with_capabilities(Capabilities::sandboxed())
+            .with_fuel(100_000)
+            .with_max_heap(1 << 20)
+            .interpret_chunk(&chunk);
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{
+    internals::{optimize, VM},
+    Capabilities, Lowered,
+};
+
+fuzz_target!(|source: &str| {
+    let Ok(chunk) = source.lower() else {
+        return;
+    };
+    for chunk in [optimize(&chunk), chunk] {
+        VM::with_output(Box::new(io::sink()), Box::new(io::sink()))
+            .with_capabilities(Capabilities::sandboxed())
+            .with_fuel(100_000)
+            .with_max_heap(1 << 20)
+            .interpret_chunk(&chunk);
+    }
+});
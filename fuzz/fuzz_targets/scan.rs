@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{internals::Scanned, Formatted};
+
+fuzz_target!(|source: &str| {
+    source.scan();
+    let _ = source.format();
+});
@@ -0,0 +1,217 @@
+use strum_macros::Display;
+
+use crate::token::Span;
+
+/// A parsed Lox source file: the top-level declarations in order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Class(Class),
+    Fun(Function),
+    Var {
+        name: Identifier,
+        initializer: Option<Expr>,
+    },
+    Expression(Expr),
+    Print(Expr),
+    Return(Option<Expr>),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    Block(Vec<Stmt>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub name: Identifier,
+    pub superclass: Option<Identifier>,
+    pub methods: Vec<Function>,
+}
+
+/// A function declaration or a class method.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    /// The expression a chain of binary or logical operators, calls or
+    /// property accesses continues through: the left operand, callee or
+    /// object. The parser doesn't limit how long chains get, so passes walk
+    /// them with this in a loop rather than recursing down them.
+    pub fn chained(&self) -> Option<&Expr> {
+        match &self.kind {
+            ExprKind::Binary { left, .. } | ExprKind::Logical { left, .. } => Some(left),
+            ExprKind::Call { callee, .. } => Some(callee),
+            ExprKind::Get { object, .. } => Some(object),
+            _ => None,
+        }
+    }
+
+    /// The innermost expression of this one's chain, and the links around
+    /// it from the innermost out, in the order they are evaluated.
+    pub fn chain(&self) -> (&Expr, Vec<&Expr>) {
+        let mut links = vec![];
+        let mut innermost = self;
+        while let Some(operand) = innermost.chained() {
+            links.push(innermost);
+            innermost = operand;
+        }
+        links.reverse();
+        (innermost, links)
+    }
+}
+
+impl Drop for Expr {
+    /// Takes a chain apart one link at a time, so that dropping a long one
+    /// doesn't recurse once per link.
+    fn drop(&mut self) {
+        let mut next = self.kind.take_chained();
+        while let Some(mut kind) = next {
+            next = kind.take_chained();
+        }
+    }
+}
+
+impl ExprKind {
+    /// Moves out what `Expr::chained` would return, leaving `nil` behind.
+    fn take_chained(&mut self) -> Option<ExprKind> {
+        let operand = match self {
+            ExprKind::Binary { left, .. } | ExprKind::Logical { left, .. } => left,
+            ExprKind::Call { callee, .. } => callee,
+            ExprKind::Get { object, .. } => object,
+            _ => return None,
+        };
+        Some(std::mem::replace(
+            &mut operand.kind,
+            ExprKind::Literal(Literal::Nil),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Grouping(Box<Expr>),
+    Unary {
+        operator: UnaryOperator,
+        operand: Box<Expr>,
+    },
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Logical {
+        operator: LogicalOperator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Variable(Identifier),
+    Assign {
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Identifier,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    This,
+    Super {
+        method: Identifier,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    #[strum(serialize = "-")]
+    Negate,
+    #[strum(serialize = "!")]
+    Not,
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    #[strum(serialize = "+")]
+    Add,
+    #[strum(serialize = "-")]
+    Subtract,
+    #[strum(serialize = "*")]
+    Multiply,
+    #[strum(serialize = "/")]
+    Divide,
+    #[strum(serialize = "==")]
+    Equal,
+    #[strum(serialize = "!=")]
+    NotEqual,
+    #[strum(serialize = "<")]
+    Less,
+    #[strum(serialize = "<=")]
+    LessEqual,
+    #[strum(serialize = ">")]
+    Greater,
+    #[strum(serialize = ">=")]
+    GreaterEqual,
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOperator {
+    #[strum(serialize = "and")]
+    And,
+    #[strum(serialize = "or")]
+    Or,
+}
@@ -1,8 +1,13 @@
 // Strum contains all the trait definitions
+use std::{collections::HashMap, fmt, ops::Range};
+
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use strum_macros::Display;
 
-#[derive(IntoPrimitive, TryFromPrimitive, Display, PartialEq)]
+use crate::object::Obj;
+pub use crate::value::Value;
+
+#[derive(IntoPrimitive, TryFromPrimitive, Display, PartialEq, Eq, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
@@ -13,38 +18,172 @@ pub enum OpCode {
     Subtract,
     ConstantLong,
     Return,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    Class,
+    Inherit,
+    Method,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    PopN,
+}
+
+impl OpCode {
+    /// Number of operand bytes that always follow this op code. `Closure`
+    /// is additionally followed by two bytes per captured upvalue.
+    pub fn operand_len(self) -> usize {
+        match self {
+            OpCode::ConstantLong => 3,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => 2,
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::PopN => 1,
+            _ => 0,
+        }
+    }
 }
-pub type Value = f64;
 type Code = u8;
 type Line = usize;
-#[derive(Clone)]
+
+/// `ConstantLong` addresses the pool with a 24-bit big-endian operand.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyConstants;
+
+impl fmt::Display for TooManyConstants {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Too many constants in one chunk.")
+    }
+}
+#[derive(Clone, Debug)]
 pub struct Chunk {
-    pub code: Vec<Code>,
-    pub constants: Vec<Value>,
-    pub lines: LineEncoding,
+    pub(crate) code: Vec<Code>,
+    pub(crate) constants: Vec<Value>,
+    pub(crate) lines: LineEncoding,
+    /// Debug info for naming local variables. The lowering pass fills it
+    /// in and the optimizer carries it over; deserialized chunks have none.
+    pub(crate) locals: Vec<LocalName>,
+    /// Debug info naming the function's upvalues by index, kept like
+    /// `locals`.
+    pub(crate) upvalue_names: Vec<Box<str>>,
+    /// Where each deduplicable constant already sits in `constants`.
+    constant_index: HashMap<ConstantKey, usize>,
 }
 
-type LineEncoding = Vec<Line>;
-trait LineEncoder {
-    fn put(&mut self, line: Line);
-    fn get(&self, offset: Line) -> Line;
+/// A local variable's name, the frame slot holding it and the code offsets
+/// it is in scope for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalName {
+    pub name: Box<str>,
+    pub slot: u8,
+    pub live: Range<usize>,
 }
-impl LineEncoder for LineEncoding {
-    fn put(&mut self, line: Line) {
-        if line >= self.len() {
-            self.resize(1 + line, 0);
+
+/// Identity of a constant for deduplication. Numbers compare by bits, so
+/// `0.0` and `-0.0` keep separate slots while identical NaNs share one;
+/// strings compare by content, just as interned strings would.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(Box<str>),
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<Self> {
+        match value.as_number() {
+            Some(number) => Some(Self::Number(number.to_bits())),
+            None => value.as_str().map(|string| Self::String(string.into())),
         }
-        self[line] += 1;
     }
+}
 
-    fn get(&self, offset: Line) -> Line {
-        let mut tracker = 0;
-        let mut index = 0;
-        while tracker <= offset {
-            tracker += self[index];
-            index += 1;
+/// A run of consecutive instructions from the same source line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LineRun {
+    line: Line,
+    /// Offset just past the run's last byte.
+    end: usize,
+}
+
+/// Run-length encoded source lines, one run per change of line. Lines need
+/// not increase: a closure body or a `for` increment can jump back.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineEncoding {
+    runs: Vec<LineRun>,
+}
+
+impl LineEncoding {
+    fn put(&mut self, line: Line) {
+        match self.runs.last_mut() {
+            Some(run) if run.line == line => run.end += 1,
+            last => {
+                let end = last.map_or(0, |run| run.end) + 1;
+                self.runs.push(LineRun { line, end });
+            }
         }
-        return index - 1;
+    }
+
+    pub(crate) fn push_run(&mut self, line: Line, count: usize) {
+        let end = self.code_len() + count;
+        self.runs.push(LineRun { line, end });
+    }
+
+    /// Number of code bytes the table covers.
+    pub(crate) fn code_len(&self) -> usize {
+        self.runs.last().map_or(0, |run| run.end)
+    }
+
+    fn get(&self, offset: usize) -> Option<Line> {
+        let run = self.runs.partition_point(|run| run.end <= offset);
+        self.runs.get(run).map(|run| run.line)
+    }
+
+    /// The `(line, count)` pairs making up the table.
+    pub fn runs(&self) -> impl Iterator<Item = (Line, usize)> + '_ {
+        let starts = std::iter::once(0).chain(self.runs.iter().map(|run| run.end));
+        self.runs
+            .iter()
+            .zip(starts)
+            .map(|(run, start)| (run.line, run.end - start))
+    }
+
+    pub fn len(&self) -> usize {
+        self.runs.len()
     }
 }
 
@@ -53,10 +192,33 @@ impl Chunk {
         Chunk {
             code: vec![],
             constants: vec![],
-            lines: vec![],
+            lines: LineEncoding::default(),
+            locals: vec![],
+            upvalue_names: vec![],
+            constant_index: HashMap::new(),
         }
     }
 
+    /// Size in bytes of the instruction starting at `offset`, or `None` if
+    /// there is no valid instruction there.
+    pub fn instruction_len(&self, offset: usize) -> Option<usize> {
+        let op_code = OpCode::try_from(*self.code.get(offset)?).ok()?;
+        let mut len = 1 + op_code.operand_len();
+        if op_code == OpCode::Closure {
+            let constant = self.constants.get(*self.code.get(offset + 1)? as usize)?;
+            match constant.as_obj()? {
+                Obj::Function(function) => len += 2 * function.upvalue_count,
+                _ => return None,
+            }
+        }
+        Some(len)
+    }
+
+    /// The constant pool, in index order.
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
     pub fn write_op_code(&mut self, op_code: OpCode, line: Line) {
         self.write_code(op_code.into(), line);
     }
@@ -64,72 +226,187 @@ impl Chunk {
         self.code.push(code);
         self.lines.put(line);
     }
-    pub(crate) fn get_line(&self, line: Line) -> Line {
-        self.lines.get(line)
+    /// The source line of the byte at `offset`, or `None` past the end of
+    /// the code.
+    pub fn get_line(&self, offset: usize) -> Option<Line> {
+        self.lines.get(offset)
+    }
+
+    /// The name of the local variable in `slot` at `offset`, if the chunk
+    /// has debug info for it.
+    pub fn local_name(&self, slot: usize, offset: usize) -> Option<&str> {
+        let local = self
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.slot as usize == slot && local.live.contains(&offset))?;
+        Some(&local.name)
+    }
+
+    pub(crate) fn write_constant(
+        &mut self,
+        value: Value,
+        line: Line,
+    ) -> Result<(), TooManyConstants> {
+        let constant_offset = self.put_constant(value)?;
+        self.write_constant_index(constant_offset, line)
     }
-    pub(crate)  fn write_constant(&mut self, value: Value, line: Line) {
-        let constant_offset = self.put_constant(value);
-        if constant_offset < u8::MAX.into() {
+    /// Loads the constant at `constant_offset`, using `Constant` for indices
+    /// that fit in a byte and `ConstantLong` for the rest.
+    pub(crate) fn write_constant_index(
+        &mut self,
+        constant_offset: usize,
+        line: Line,
+    ) -> Result<(), TooManyConstants> {
+        if let Ok(index) = u8::try_from(constant_offset) {
             self.write_op_code(OpCode::Constant, line);
-            self.write_operand(constant_offset as u8, line);
-        } else {
+            self.write_operand(index, line);
+        } else if constant_offset < MAX_CONSTANTS {
             self.write_op_code(OpCode::ConstantLong, line);
-            self.write_operand((constant_offset >> 16) as u8, line);
-            self.write_operand((constant_offset >> 8) as u8, line);
-            self.write_operand(constant_offset as u8, line);
+            for byte in &constant_offset.to_be_bytes()[size_of::<usize>() - 3..] {
+                self.write_operand(*byte, line);
+            }
+        } else {
+            return Err(TooManyConstants);
         }
+        Ok(())
     }
-    fn put_constant(&mut self, value: Value) -> usize {
-        self.constants.push(value);
-        return self.constants.len() - 1;
+    /// Decodes the 24-bit constant index of the `ConstantLong` at `offset`.
+    pub fn long_constant_index(&self, offset: usize) -> Option<usize> {
+        let bytes = self.code.get(offset + 1..offset + 4)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |index, byte| (index << 8) | *byte as usize),
+        )
     }
-    fn free_chunk(&mut self) {
-        self.lines.truncate(0);
-        self.code.truncate(0);
-        self.constants.truncate(0);
+    /// Adds `value` to the pool unless an identical constant is already
+    /// there, returning its index either way.
+    pub(crate) fn put_constant(&mut self, value: Value) -> Result<usize, TooManyConstants> {
+        let key = ConstantKey::of(&value);
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_index.get(key)) {
+            return Ok(*index);
+        }
+        if self.constants.len() >= MAX_CONSTANTS {
+            return Err(TooManyConstants);
+        }
+        self.constants.push(value);
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_index.insert(key, index);
+        }
+        Ok(index)
     }
-    fn write_operand(&mut self, operand: Code, line: Line) {
+    pub(crate) fn write_operand(&mut self, operand: Code, line: Line) {
         self.write_code(operand, line);
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
 
     use super::*;
+
+    /// Loads the constants at indices 254, 255, 256 and 65536, either side
+    /// of where `Constant` gives way to `ConstantLong`, from a pool holding
+    /// each index as a number.
+    pub(crate) fn long_constants_chunk() -> Chunk {
+        let mut chunk = Chunk::new_chunk();
+        for n in 0..=65536 {
+            chunk.put_constant((n as f64).into()).unwrap();
+        }
+        for index in [254, 255, 256, 65536] {
+            chunk.write_constant_index(index, 1).unwrap();
+        }
+        chunk
+    }
+
     #[test]
     fn new_chunk() {
         assert_eq!(Chunk::new_chunk().code.len(), 0);
     }
     #[test]
     fn line_encoder() {
-        let mut le: LineEncoding = vec![];
-        le.put(0);
-        le.put(0);
-        le.put(0);
-        le.put(1);
-        le.put(1);
-        le.put(2);
-        le.put(3);
-        le.put(3);
-        le.put(3);
-        le.put(3);
-        assert_eq!(le.get(0), 0);
-        assert_eq!(le.get(1), 0);
-        assert_eq!(le.get(2), 0);
-        assert_eq!(le.get(3), 1);
-        assert_eq!(le.get(4), 1);
-        assert_eq!(le.get(5), 2);
-        assert_eq!(le.get(6), 3);
-        assert_eq!(le.len(), 4);
+        let mut le = LineEncoding::default();
+        for line in [1, 1, 1, 2, 2, 3, 60000, 60000, 2, 2] {
+            le.put(line);
+        }
+        assert_eq!(
+            le.runs().collect::<Vec<_>>(),
+            vec![(1, 3), (2, 2), (3, 1), (60000, 2), (2, 2)]
+        );
+        let lines: Vec<_> = (0..10).map(|offset| le.get(offset).unwrap()).collect();
+        assert_eq!(lines, vec![1, 1, 1, 2, 2, 3, 60000, 60000, 2, 2]);
+        assert_eq!(le.get(10), None);
+        assert_eq!(LineEncoding::default().get(0), None);
     }
     #[test]
     fn disassemble() {
         let mut chunk = Chunk::new_chunk();
         chunk.write_op_code(OpCode::Return, 0);
         for n in 0..260 {
-            chunk.write_constant(n as f64 * 2.0, (n as f64).sqrt().floor() as usize);
+            chunk
+                .write_constant((n as f64 * 2.0).into(), (n as f64).sqrt().floor() as usize)
+                .unwrap();
         }
         assert!(chunk.lines.len() < 20);
+        let mut output = vec![];
+        crate::chunk_printer::print_chunk(&chunk, &mut output, "test").unwrap();
+        let output = String::from_utf8(output).unwrap();
+        // After the header, the `Return` and the first 255 constants.
+        let tail: Vec<_> = output.lines().skip(257).collect();
+        assert_eq!(
+            tail,
+            [
+                "0511    | Constant          255 '510.0'",
+                "0513   16 ConstantLong      256 '512.0'",
+                "0517    | ConstantLong      257 '514.0'",
+                "0521    | ConstantLong      258 '516.0'",
+                "0525    | ConstantLong      259 '518.0'",
+            ]
+        );
+    }
+
+    #[test]
+    fn deduplicates_constants() {
+        let mut chunk = Chunk::new_chunk();
+        for _ in 0..100 {
+            chunk.write_constant(0.0.into(), 1).unwrap();
+            chunk.write_constant(Value::string("count"), 1).unwrap();
+        }
+        assert_eq!(chunk.constants.len(), 2);
+        assert_eq!(
+            chunk.code,
+            [OpCode::Constant as u8, 0, OpCode::Constant as u8, 1].repeat(100)
+        );
+    }
+    #[test]
+    fn deduplicates_numbers_bitwise() {
+        let mut chunk = Chunk::new_chunk();
+        assert_eq!(chunk.put_constant((-0.0).into()), Ok(0));
+        assert_eq!(chunk.put_constant(0.0.into()), Ok(1));
+        assert_eq!(chunk.put_constant(f64::NAN.into()), Ok(2));
+        assert_eq!(chunk.put_constant(f64::NAN.into()), Ok(2));
+        assert_eq!(chunk.put_constant(Value::nil()), Ok(3));
+        assert_eq!(chunk.put_constant(Value::nil()), Ok(4));
+    }
+    #[test]
+    fn long_constants() {
+        let mut chunk = long_constants_chunk();
+        assert_eq!(
+            chunk.code[..4],
+            [OpCode::Constant as u8, 254, OpCode::Constant as u8, 255]
+        );
+        for (offset, index) in [(4, 256), (8, 65536)] {
+            assert_eq!(chunk.code[offset], OpCode::ConstantLong as u8);
+            assert_eq!(chunk.long_constant_index(offset), Some(index));
+        }
+        assert_eq!(chunk.code.len(), 2 + 2 + 4 + 4);
+        assert_eq!(
+            chunk.write_constant_index(MAX_CONSTANTS, 1),
+            Err(TooManyConstants)
+        );
+        assert_eq!(chunk.write_constant_index(MAX_CONSTANTS - 1, 1), Ok(()));
+        assert_eq!(chunk.long_constant_index(12), Some(MAX_CONSTANTS - 1));
     }
 }
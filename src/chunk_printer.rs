@@ -1,16 +1,27 @@
-use std::fs::File;
-use std::io::{Write, Result};
+use std::io::{Result, Write};
 
 use crate::chunk::{Chunk, OpCode, Value};
+use crate::object::Obj;
 
-pub fn print_chunk(chunk: &Chunk, file: &mut File, description: &str) -> Result<()> {
+pub fn print_chunk(chunk: &Chunk, file: &mut dyn Write, description: &str) -> Result<()> {
     ChunkPrinter::new(chunk, file).disassemble(description)?;
+    for constant in chunk.constants.iter() {
+        if let Some(Obj::Function(function)) = constant.as_obj() {
+            print_chunk(&function.chunk, file, &function.to_string())?;
+        }
+    }
     OK
 }
 
+/// Disassembles the single instruction at `offset`, returning the offset of
+/// the next one.
+pub fn print_instruction(chunk: &Chunk, offset: usize, file: &mut dyn Write) -> Result<usize> {
+    ChunkPrinter::new(chunk, file).disassemble_instruction(offset)
+}
+
 struct ChunkPrinter<'a> {
     chunk: &'a Chunk,
-    file: &'a mut File,
+    file: &'a mut dyn Write,
 }
 
 type R = Result<()>;
@@ -28,43 +39,108 @@ impl<'a> ChunkPrinter<'a> {
     }
     pub fn disassemble_instruction(&mut self, offset: usize) -> RU {
         write!(self.file, "{:04} ", offset)?;
-        if offset > 0 && self.chunk.get_line(offset) == self.chunk.get_line(offset - 1) {
-            write!(self.file, "   | ")?;
-        } else {
-            write!(self.file, "{:4} ", self.chunk.get_line(offset))?;
+        let line = self.chunk.get_line(offset);
+        match line {
+            Some(_) if offset > 0 && line == self.chunk.get_line(offset - 1) => {
+                write!(self.file, "   | ")?
+            }
+            Some(line) => write!(self.file, "{:4} ", line)?,
+            None => write!(self.file, "   ? ")?,
         }
         let op_code = self.chunk.code[offset].try_into().unwrap();
         Ok(match op_code {
             OpCode::Constant => self.disassemble_constant(offset)?,
             OpCode::ConstantLong => self.disassemble_constant_long(offset)?,
+            OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper => self.constant_instruction(op_code, offset)?,
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::PopN => self.byte_instruction(op_code, offset)?,
+            OpCode::Jump | OpCode::JumpIfFalse => self.jump_instruction(op_code, 1, offset)?,
+            OpCode::Loop => self.jump_instruction(op_code, -1, offset)?,
+            OpCode::Closure => self.disassemble_closure(offset)?,
             _ => self.simple_instruction(op_code.to_string().as_str(), offset)?,
         })
     }
     fn simple_instruction(&mut self, value: &str, offset: usize) -> RU {
         writeln!(self.file, "{}", value)?;
-        return Ok(offset + 1);
+        Ok(offset + 1)
     }
-    fn disassemble_constant(&mut self, offset: usize) -> RU {
+    fn byte_instruction(&mut self, op_code: OpCode, offset: usize) -> RU {
+        writeln!(
+            self.file,
+            "{:16} {:4}",
+            op_code,
+            self.chunk.code[offset + 1]
+        )?;
+        Ok(offset + 2)
+    }
+    fn jump_instruction(&mut self, op_code: OpCode, sign: isize, offset: usize) -> RU {
+        let jump =
+            ((self.chunk.code[offset + 1] as isize) << 8) + self.chunk.code[offset + 2] as isize;
+        let target = offset as isize + 3 + sign * jump;
+        writeln!(self.file, "{:16} {:4} -> {}", op_code, offset, target)?;
+        Ok(offset + 3)
+    }
+    fn constant_instruction(&mut self, op_code: OpCode, offset: usize) -> RU {
         let constant_offset = self.chunk.code[offset + 1];
-        write!(self.file, "{:16} {:4} '", OpCode::Constant, constant_offset,)?;
+        write!(self.file, "{:16} {:4} '", op_code, constant_offset)?;
         self.print_value(&self.chunk.constants[constant_offset as usize])?;
-        write!(self.file, "'\n")?;
-        return Ok(offset + 2);
+        writeln!(self.file, "'")?;
+        Ok(offset + 2)
+    }
+    fn disassemble_constant(&mut self, offset: usize) -> RU {
+        self.constant_instruction(OpCode::Constant, offset)
     }
     fn disassemble_constant_long(&mut self, offset: usize) -> RU {
-        let constant_offset = ((((self.chunk.code[offset + 1] as usize) << 8)
-            + (self.chunk.code[offset + 2] as usize))
-            << 8)
-            + self.chunk.code[offset + 3] as usize;
+        let constant_offset = self.chunk.long_constant_index(offset).unwrap();
         write!(
             self.file,
-            "{:16} {:12} '",
+            "{:16} {:4} '",
             OpCode::ConstantLong,
             constant_offset
         )?;
         self.print_value(&self.chunk.constants[constant_offset])?;
-        write!(self.file, "'\n")?;
-        return Ok(offset + 4);
+        writeln!(self.file, "'")?;
+        Ok(offset + 4)
+    }
+    fn disassemble_closure(&mut self, offset: usize) -> RU {
+        let constant_offset = self.chunk.code[offset + 1];
+        let value = &self.chunk.constants[constant_offset as usize];
+        writeln!(
+            self.file,
+            "{:16} {:4} {}",
+            OpCode::Closure,
+            constant_offset,
+            value
+        )?;
+        let upvalue_count = match value.as_obj() {
+            Some(Obj::Function(function)) => function.upvalue_count,
+            _ => 0,
+        };
+        let mut offset = offset + 2;
+        for _ in 0..upvalue_count {
+            let is_local = self.chunk.code[offset];
+            let index = self.chunk.code[offset + 1];
+            writeln!(
+                self.file,
+                "{:04}    |                     {} {}",
+                offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            )?;
+            offset += 2;
+        }
+        Ok(offset)
     }
 
     fn print_value(&mut self, value: &Value) -> R {
@@ -72,7 +148,43 @@ impl<'a> ChunkPrinter<'a> {
         OK
     }
 
-    fn new(chunk: &'a Chunk, file: &'a mut File) -> Self {
+    fn new(chunk: &'a Chunk, file: &'a mut dyn Write) -> Self {
         Self { chunk, file }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::tests::long_constants_chunk;
+
+    #[test]
+    fn long_constants() {
+        let chunk = long_constants_chunk();
+        let mut output = vec![];
+        print_chunk(&chunk, &mut output, "long").unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "== long ==\n\
+             0000    1 Constant          254 '254.0'\n\
+             0002    | Constant          255 '255.0'\n\
+             0004    | ConstantLong      256 '256.0'\n\
+             0008    | ConstantLong     65536 '65536.0'\n"
+        );
+    }
+
+    #[test]
+    fn missing_lines() {
+        let mut chunk = Chunk::new_chunk();
+        chunk.write_op_code(OpCode::Nil, 1);
+        chunk.code.push(OpCode::Return.into());
+        let mut output = vec![];
+        print_chunk(&chunk, &mut output, "short").unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "== short ==\n\
+             0000    1 Nil\n\
+             0001    ? Return\n"
+        );
+    }
+}
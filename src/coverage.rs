@@ -0,0 +1,208 @@
+//! The `rlox coverage` recorder: a `Hook` noting which instructions and
+//! which ways of each branch ran, reported in the lcov format.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    ops::ControlFlow,
+    ptr,
+};
+
+use crate::{
+    chunk::{Chunk, OpCode},
+    object::Obj,
+    vm::{Execution, Hook},
+};
+
+#[derive(Default)]
+pub struct Coverage {
+    /// Executions of each instruction, by chunk and offset. The VM runs a
+    /// copy of the script's chunk, so the script is keyed by null instead.
+    counts: HashMap<*const Chunk, Vec<u64>>,
+    /// How often each `JumpIfFalse` fell through and how often it jumped.
+    branches: HashMap<(*const Chunk, usize), [u64; 2]>,
+    calls: HashMap<*const Chunk, u64>,
+    depth: usize,
+}
+
+/// A source file's coverage, gathered from all of its chunks.
+#[derive(Default)]
+struct Report<'a> {
+    /// Declaration line, name and call count of each function.
+    functions: Vec<(usize, &'a str, u64)>,
+    /// Line and counts of each branch, `None` if it never ran.
+    branches: Vec<(usize, Option<[u64; 2]>)>,
+    /// Hits of each line with code: the most any one of its instructions
+    /// ran.
+    lines: BTreeMap<usize, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes an lcov record for `chunk`, compiled from the source at
+    /// `path`, covering the functions declared in it too.
+    pub fn write_lcov(&self, chunk: &Chunk, path: &str, output: &mut dyn Write) -> io::Result<()> {
+        let mut report = Report::default();
+        self.gather(chunk, ptr::null(), &mut report);
+        writeln!(output, "TN:\nSF:{}", path)?;
+        for (line, name, _) in report.functions.iter() {
+            writeln!(output, "FN:{},{}", line, name)?;
+        }
+        for (_, name, calls) in report.functions.iter() {
+            writeln!(output, "FNDA:{},{}", calls, name)?;
+        }
+        let called = report.functions.iter().filter(|(.., calls)| *calls > 0);
+        writeln!(output, "FNF:{}", report.functions.len())?;
+        writeln!(output, "FNH:{}", called.count())?;
+        let mut taken = 0;
+        for (block, (line, counts)) in report.branches.iter().enumerate() {
+            for branch in 0..2 {
+                match counts {
+                    Some(counts) => {
+                        taken += (counts[branch] > 0) as usize;
+                        writeln!(
+                            output,
+                            "BRDA:{},{},{},{}",
+                            line, block, branch, counts[branch]
+                        )?;
+                    }
+                    None => writeln!(output, "BRDA:{},{},{},-", line, block, branch)?,
+                }
+            }
+        }
+        writeln!(output, "BRF:{}", report.branches.len() * 2)?;
+        writeln!(output, "BRH:{}", taken)?;
+        for (line, hits) in report.lines.iter() {
+            writeln!(output, "DA:{},{}", line, hits)?;
+        }
+        let hit = report.lines.values().filter(|hits| **hits > 0);
+        writeln!(output, "LF:{}", report.lines.len())?;
+        writeln!(output, "LH:{}", hit.count())?;
+        writeln!(output, "end_of_record")
+    }
+
+    /// Adds what ran of `chunk` to `report`, then does the same for the
+    /// functions it declares, in the order they are declared.
+    fn gather<'a>(&self, chunk: &'a Chunk, key: *const Chunk, report: &mut Report<'a>) {
+        let counts = self.counts.get(&key);
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let Some(line) = chunk.get_line(offset) else {
+                break;
+            };
+            let count = counts.map_or(0, |counts| counts[offset]);
+            let hits = report.lines.entry(line).or_default();
+            *hits = (*hits).max(count);
+            match OpCode::try_from(chunk.code[offset]) {
+                Ok(OpCode::JumpIfFalse) => {
+                    let branch = self.branches.get(&(key, offset)).copied();
+                    report.branches.push((line, branch));
+                }
+                Ok(OpCode::Closure) => {
+                    let constant = &chunk.constants[chunk.code[offset + 1] as usize];
+                    if let Some(Obj::Function(function)) = constant.as_obj() {
+                        let name = function.name.as_deref().unwrap_or("script");
+                        let calls = self.calls.get(&(&function.chunk as *const Chunk));
+                        report
+                            .functions
+                            .push((line, name, calls.copied().unwrap_or_default()));
+                        self.gather(&function.chunk, &function.chunk, report);
+                    }
+                }
+                _ => {}
+            }
+            offset += chunk.instruction_len(offset).expect("a valid instruction");
+        }
+    }
+}
+
+impl Hook for Coverage {
+    fn instruction(&mut self, execution: &Execution) -> ControlFlow<()> {
+        let chunk = execution.chunk();
+        let offset = execution.offset();
+        let depth = execution.depth();
+        let key: *const Chunk = if depth == 1 { ptr::null() } else { chunk };
+        // Calls start at offset zero one frame deeper; loops only return to
+        // offset zero at the same depth.
+        if depth > self.depth && offset == 0 {
+            *self.calls.entry(key).or_default() += 1;
+        }
+        self.depth = depth;
+        let counts = self
+            .counts
+            .entry(key)
+            .or_insert_with(|| vec![0; chunk.code.len()]);
+        counts[offset] += 1;
+        if execution.op_code() == OpCode::JumpIfFalse {
+            let jumps = execution.stack().last().expect("a condition").is_falsey();
+            self.branches.entry((key, offset)).or_default()[jumps as usize] += 1;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{lowering::Lowered, vm::VM};
+
+    #[test]
+    fn lcov() {
+        let source = "\
+fun sign(n) {
+  if (n < 0) return -1;
+  return 1;
+}
+fun unused() {
+  print \"never\";
+}
+var i = 0;
+while (i < 3) {
+  sign(i);
+  i = i + 1;
+}
+";
+        let chunk = source.lower().unwrap();
+        let mut coverage = Coverage::new();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        vm.interpret_chunk_with(&chunk, &mut coverage);
+        let mut lcov = vec![];
+        coverage.write_lcov(&chunk, "sign.lox", &mut lcov).unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "\
+TN:
+SF:sign.lox
+FN:1,sign
+FN:5,unused
+FNDA:3,sign
+FNDA:0,unused
+FNF:2
+FNH:1
+BRDA:2,0,0,0
+BRDA:2,0,1,3
+BRDA:9,1,0,3
+BRDA:9,1,1,1
+BRF:4
+BRH:3
+DA:1,1
+DA:2,3
+DA:3,3
+DA:5,1
+DA:6,0
+DA:8,1
+DA:9,4
+DA:10,3
+DA:11,3
+LF:9
+LH:8
+end_of_record
+"
+        );
+    }
+}
@@ -0,0 +1,448 @@
+//! The `rlox dap` debug adapter: the Debug Adapter Protocol over a pair of
+//! streams, so editors can debug Lox scripts. Lines are numbered from one
+//! and there is a single thread.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    fs,
+    io::{self, BufRead, LineWriter, Write},
+    ops::ControlFlow,
+    path::PathBuf,
+    rc::Rc,
+};
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    chunk::{Chunk, Value},
+    debugger::{Mode, Stepper, Stop},
+    lowering::Lowered,
+    native::Capabilities,
+    object::Obj,
+    protocol::{read_message, write_message},
+    vm::{Execution, Hook, InterpretResult, VM},
+};
+
+const THREAD_ID: u64 = 1;
+/// Variables reference of the globals scope. Frame `n` has its locals at
+/// `2 * n + 2` and its upvalues at `2 * n + 3`.
+const GLOBALS: u64 = 1;
+
+/// Serves one debugging session, reading requests from `input` and writing
+/// responses and events to `output` until the client disconnects.
+pub fn serve<I: BufRead, O: Write + 'static>(input: I, output: O) -> io::Result<()> {
+    Adapter {
+        input,
+        client: Rc::new(RefCell::new(Client { output, seq: 0 })),
+        stepper: Stepper::new(Mode::Continue),
+        program: None,
+        stopped: false,
+        disconnected: false,
+        error: None,
+    }
+    .serve()
+}
+
+struct Request {
+    seq: u64,
+    command: String,
+    arguments: Json,
+}
+
+/// The editor's end of the connection.
+struct Client<O> {
+    output: O,
+    /// Sequence number of the last message sent.
+    seq: u64,
+}
+
+impl<O: Write> Client<O> {
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        write_message(&mut self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Request, body: Json) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request.seq,
+            "success": true,
+            "command": request.command,
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Request, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request.seq,
+            "success": false,
+            "command": request.command,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+/// Sends what the script writes to the client as `output` events.
+struct Output<O> {
+    client: Rc<RefCell<Client<O>>>,
+    category: &'static str,
+}
+
+impl<O: Write> Write for Output<O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let output = String::from_utf8_lossy(buf);
+        let body = json!({ "category": self.category, "output": output });
+        self.client.borrow_mut().event("output", body)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A launched script, waiting for the client to finish configuring.
+struct Program {
+    path: PathBuf,
+    chunk: Chunk,
+    /// Lines that have code, where breakpoints can stop.
+    lines: BTreeSet<usize>,
+}
+
+struct Adapter<I, O> {
+    input: I,
+    client: Rc<RefCell<Client<O>>>,
+    stepper: Stepper,
+    program: Option<Program>,
+    /// Whether the script has paused yet, to report its first pause as the
+    /// entry.
+    stopped: bool,
+    disconnected: bool,
+    /// An I/O error that stopped the script from inside the hook.
+    error: Option<io::Error>,
+}
+
+impl<I: BufRead, O: Write + 'static> Adapter<I, O> {
+    fn serve(mut self) -> io::Result<()> {
+        while let Some(request) = self.read_request()? {
+            match request.command.as_str() {
+                "initialize" => {
+                    let capabilities = json!({ "supportsConfigurationDoneRequest": true });
+                    self.respond(&request, capabilities)?;
+                    self.client.borrow_mut().event("initialized", json!({}))?;
+                }
+                "launch" => self.launch(&request)?,
+                "configurationDone" => {
+                    self.respond(&request, json!({}))?;
+                    if self.program.is_some() {
+                        self.run()?;
+                    }
+                }
+                "disconnect" => {
+                    self.respond(&request, json!({}))?;
+                    return Ok(());
+                }
+                _ => self.request(&request, None)?,
+            }
+            if self.disconnected {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next request, or `None` once the client has hung up.
+    fn read_request(&mut self) -> io::Result<Option<Request>> {
+        let Some(message) = read_message(&mut self.input)? else {
+            return Ok(None);
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed request");
+        let seq = message["seq"].as_u64().ok_or_else(invalid)?;
+        let command = message["command"].as_str().ok_or_else(invalid)?;
+        Ok(Some(Request {
+            seq,
+            command: command.to_string(),
+            arguments: message["arguments"].clone(),
+        }))
+    }
+
+    fn respond(&self, request: &Request, body: Json) -> io::Result<()> {
+        self.client.borrow_mut().respond(request, body)
+    }
+
+    fn fail(&self, request: &Request, message: &str) -> io::Result<()> {
+        self.client.borrow_mut().fail(request, message)
+    }
+
+    fn launch(&mut self, request: &Request) -> io::Result<()> {
+        let Some(path) = request.arguments["program"].as_str() else {
+            return self.fail(request, "Launching needs a 'program' to debug.");
+        };
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                return self.fail(request, &format!("Could not read '{}': {}.", path, error))
+            }
+        };
+        let chunk = match source.as_str().lower() {
+            Ok(chunk) => chunk,
+            Err(errors) => {
+                let errors: Vec<_> = errors
+                    .iter()
+                    .map(|error| format!("{}:{}", path, error))
+                    .collect();
+                return self.fail(request, &errors.join("\n"));
+            }
+        };
+        if request.arguments["stopOnEntry"].as_bool() == Some(true) {
+            self.stepper.mode = Mode::StepInto;
+        }
+        let mut lines = BTreeSet::new();
+        code_lines(&chunk, &mut lines);
+        self.program = Some(Program {
+            path: path.into(),
+            chunk,
+            lines,
+        });
+        self.respond(request, json!({}))
+    }
+
+    /// Runs the launched script to the end, unless the client disconnects.
+    fn run(&mut self) -> io::Result<()> {
+        let program = self.program.as_ref().expect("a launched program");
+        let chunk = program.chunk.clone();
+        let output = |category| {
+            Box::new(LineWriter::new(Output {
+                client: self.client.clone(),
+                category,
+            }))
+        };
+        // Scripts get the files `rlox run` gives them, but reading stdin
+        // would steal the client's requests.
+        let capabilities = Capabilities {
+            files: true,
+            stdin: false,
+            ..Capabilities::default()
+        };
+        let mut vm =
+            VM::with_output(output("stdout"), output("stderr")).with_capabilities(capabilities);
+        let result = vm.interpret_chunk_with(&chunk, self);
+        // Dropping the VM flushes its output before the exit is reported.
+        drop(vm);
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if self.disconnected {
+            return Ok(());
+        }
+        let exit_code = match result {
+            InterpretResult::Ok => 0,
+            InterpretResult::CompileError => 65,
+            _ => 70,
+        };
+        let mut client = self.client.borrow_mut();
+        client.event("exited", json!({ "exitCode": exit_code }))?;
+        client.event("terminated", json!({}))
+    }
+
+    /// Tells the client why the script paused, then answers requests until
+    /// one resumes it.
+    fn pause(&mut self, execution: &Execution, stop: Stop) -> io::Result<ControlFlow<()>> {
+        let reason = match stop {
+            Stop::Step if !self.stopped => "entry",
+            Stop::Step => "step",
+            Stop::Breakpoint => "breakpoint",
+        };
+        self.stopped = true;
+        let body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.client.borrow_mut().event("stopped", body)?;
+        let depth = execution.depth();
+        while let Some(request) = self.read_request()? {
+            let mode = match request.command.as_str() {
+                "continue" => Mode::Continue,
+                "next" => Mode::StepOver { depth },
+                "stepIn" => Mode::StepInto,
+                "stepOut" => Mode::StepOut { depth },
+                "disconnect" => {
+                    self.respond(&request, json!({}))?;
+                    break;
+                }
+                _ => {
+                    self.request(&request, Some(execution))?;
+                    continue;
+                }
+            };
+            self.stepper.mode = mode;
+            self.respond(&request, json!({ "allThreadsContinued": true }))?;
+            return Ok(ControlFlow::Continue(()));
+        }
+        self.disconnected = true;
+        Ok(ControlFlow::Break(()))
+    }
+
+    /// Answers a request that doesn't start, stop or resume the script.
+    fn request(&mut self, request: &Request, execution: Option<&Execution>) -> io::Result<()> {
+        let arguments = &request.arguments;
+        match (request.command.as_str(), execution) {
+            ("setBreakpoints", _) => {
+                let lines: Vec<_> = arguments["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .map(|line| line as usize)
+                    .collect();
+                let lines_with_code = self.program.as_ref().map(|program| &program.lines);
+                let breakpoints: Vec<_> = lines
+                    .iter()
+                    .map(|line| {
+                        let verified = lines_with_code.is_none_or(|lines| lines.contains(line));
+                        json!({ "verified": verified, "line": line })
+                    })
+                    .collect();
+                self.stepper.breakpoints = lines.into_iter().collect();
+                self.respond(request, json!({ "breakpoints": breakpoints }))
+            }
+            ("threads", _) => {
+                let threads = json!([{ "id": THREAD_ID, "name": "main" }]);
+                self.respond(request, json!({ "threads": threads }))
+            }
+            ("stackTrace", Some(execution)) => {
+                let source = self.program.as_ref().map(|program| {
+                    let name = program.path.file_name().unwrap_or_default();
+                    json!({ "name": name.to_string_lossy(), "path": program.path })
+                });
+                let frames = execution.frames();
+                let start = arguments["startFrame"].as_u64().unwrap_or(0) as usize;
+                let levels = match arguments["levels"].as_u64() {
+                    Some(0) | None => frames.len(),
+                    Some(levels) => levels as usize,
+                };
+                let stack_frames: Vec<_> = frames
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                    .take(levels)
+                    .map(|(id, frame)| {
+                        json!({
+                            "id": id,
+                            "name": frame.function_name().unwrap_or("script"),
+                            "line": frame.line(),
+                            "column": 1,
+                            "source": source,
+                        })
+                    })
+                    .collect();
+                let body = json!({ "stackFrames": stack_frames, "totalFrames": frames.len() });
+                self.respond(request, body)
+            }
+            ("scopes", Some(execution)) => {
+                let id = arguments["frameId"].as_u64().unwrap_or(0);
+                let Some(frame) = execution.frames().into_iter().nth(id as usize) else {
+                    return self.fail(request, "No such frame.");
+                };
+                let mut scopes = vec![json!({
+                    "name": "Locals",
+                    "presentationHint": "locals",
+                    "variablesReference": 2 * id + 2,
+                    "expensive": false,
+                })];
+                if !frame.upvalues().is_empty() {
+                    scopes.push(json!({
+                        "name": "Closure",
+                        "variablesReference": 2 * id + 3,
+                        "expensive": false,
+                    }));
+                }
+                scopes.push(json!({
+                    "name": "Globals",
+                    "variablesReference": GLOBALS,
+                    "expensive": false,
+                }));
+                self.respond(request, json!({ "scopes": scopes }))
+            }
+            ("variables", Some(execution)) => {
+                let reference = arguments["variablesReference"].as_u64().unwrap_or(0);
+                let variables = variables(execution, reference);
+                self.respond(request, json!({ "variables": variables }))
+            }
+            ("stackTrace" | "scopes" | "variables", None) => {
+                self.fail(request, "The script is not paused.")
+            }
+            (command, _) => self.fail(request, &format!("Unsupported request '{}'.", command)),
+        }
+    }
+}
+
+impl<I: BufRead, O: Write + 'static> Hook for Adapter<I, O> {
+    fn instruction(&mut self, execution: &Execution) -> ControlFlow<()> {
+        let Some(stop) = self.stepper.stop(execution) else {
+            return ControlFlow::Continue(());
+        };
+        self.pause(execution, stop).unwrap_or_else(|error| {
+            self.error = Some(error);
+            ControlFlow::Break(())
+        })
+    }
+}
+
+/// The variables in the scope `reference` names; see `GLOBALS`.
+fn variables(execution: &Execution, reference: u64) -> Vec<Json> {
+    let variable = |name: &str, value: &Value| {
+        json!({
+            "name": name,
+            "value": value.to_string(),
+            "type": value.type_name(),
+            "variablesReference": 0,
+        })
+    };
+    if reference == GLOBALS {
+        let mut globals: Vec<_> = execution
+            .globals()
+            .filter(|(_, value)| !matches!(value.as_obj(), Some(Obj::Native(_))))
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+        return globals
+            .into_iter()
+            .map(|(name, value)| variable(name, value))
+            .collect();
+    }
+    let frame = reference.checked_sub(2).map(|index| index as usize / 2);
+    let Some(frame) = frame.and_then(|frame| execution.frames().into_iter().nth(frame)) else {
+        return vec![];
+    };
+    if reference.is_multiple_of(2) {
+        frame
+            .locals()
+            .into_iter()
+            .filter_map(|(name, value)| Some(variable(name?, value)))
+            .collect()
+    } else {
+        frame
+            .upvalues()
+            .iter()
+            .map(|(name, value)| variable(name.unwrap_or("?"), value))
+            .collect()
+    }
+}
+
+/// Adds the lines `chunk` and the functions in it have code on to `lines`.
+fn code_lines(chunk: &Chunk, lines: &mut BTreeSet<usize>) {
+    let mut offset = 0;
+    while let Some(length) = chunk.instruction_len(offset) {
+        lines.extend(chunk.get_line(offset));
+        offset += length;
+    }
+    for constant in chunk.constants.iter() {
+        if let Some(Obj::Function(function)) = constant.as_obj() {
+            code_lines(&function.chunk, lines);
+        }
+    }
+}
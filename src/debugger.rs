@@ -0,0 +1,314 @@
+//! The `rlox debug` step debugger: a `Hook` that stops at breakpoints and
+//! after steps, and reads commands until told to go on.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+    ops::ControlFlow,
+};
+
+use crate::{
+    chunk_printer::print_instruction,
+    object::Obj,
+    vm::{Execution, Hook},
+};
+
+const HELP: &str = "\
+break LINE (b)     stop whenever LINE is reached
+delete LINE (d)    remove the breakpoint on LINE
+continue (c)       run until a breakpoint
+step (s)           run to the next line, entering calls
+next (n)           run to the next line in this function or its callers
+out (o)            run until this function returns
+locals (l)         print the running function's local variables
+globals (g)        print the global variables
+stack              print the value stack
+backtrace (bt)     print the calls in progress
+quit (q)           stop the script
+An empty line repeats the last command.";
+
+/// Where to stop next, besides at breakpoints.
+#[derive(Clone, Copy)]
+pub(crate) enum Mode {
+    Continue,
+    /// The start of the next line, in any function.
+    StepInto,
+    /// The start of the next line at most `depth` calls deep.
+    StepOver {
+        depth: usize,
+    },
+    /// The first instruction back in a caller of the call `depth` deep.
+    StepOut {
+        depth: usize,
+    },
+}
+
+/// Why a `Stepper` stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stop {
+    Step,
+    Breakpoint,
+}
+
+/// Decides where to pause a script: at breakpoints, which are line
+/// numbers, and after steps.
+pub(crate) struct Stepper {
+    pub(crate) breakpoints: BTreeSet<usize>,
+    pub(crate) mode: Mode,
+}
+
+impl Stepper {
+    pub(crate) fn new(mode: Mode) -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            mode,
+        }
+    }
+
+    /// Why to pause before the instruction about to run, if at all.
+    pub(crate) fn stop(&self, execution: &Execution) -> Option<Stop> {
+        let offset = execution.offset();
+        let chunk = execution.chunk();
+        // Lines are stepped by where their code starts, so a line that
+        // spans several instructions is only stopped at once.
+        let starts_line = offset == 0 || chunk.get_line(offset - 1) != Some(execution.line());
+        let depth = execution.depth();
+        let stepped = match self.mode {
+            Mode::Continue => false,
+            Mode::StepInto => starts_line,
+            Mode::StepOver { depth: over } => starts_line && depth <= over,
+            Mode::StepOut { depth: out } => depth < out,
+        };
+        if stepped {
+            Some(Stop::Step)
+        } else if starts_line && self.breakpoints.contains(&execution.line()) {
+            Some(Stop::Breakpoint)
+        } else {
+            None
+        }
+    }
+}
+
+/// What to do after a command.
+enum After {
+    Prompt,
+    Resume(Mode),
+    Quit,
+}
+
+pub struct Debugger<I, O> {
+    input: I,
+    output: O,
+    stepper: Stepper,
+    last_command: String,
+}
+
+impl<I: BufRead, O: Write> Debugger<I, O> {
+    /// A debugger that reads commands from `input` and writes to `output`.
+    /// It stops before the first line, so breakpoints can be set.
+    pub fn new(input: I, output: O) -> Self {
+        Self {
+            input,
+            output,
+            stepper: Stepper::new(Mode::StepInto),
+            last_command: String::new(),
+        }
+    }
+
+    /// Reads and runs commands until one resumes the script.
+    fn prompt(&mut self, execution: &Execution) -> io::Result<ControlFlow<()>> {
+        let function = execution.function_name().unwrap_or("script");
+        writeln!(
+            self.output,
+            "Stopped at line {} in {}",
+            execution.line(),
+            function
+        )?;
+        print_instruction(execution.chunk(), execution.offset(), &mut self.output)?;
+        loop {
+            write!(self.output, "(rlox) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(ControlFlow::Break(()));
+            }
+            let line = line.trim();
+            let command = if line.is_empty() {
+                self.last_command.clone()
+            } else {
+                line.to_string()
+            };
+            self.last_command = command.clone();
+            match self.command(&command, execution)? {
+                After::Prompt => {}
+                After::Resume(mode) => {
+                    self.stepper.mode = mode;
+                    return Ok(ControlFlow::Continue(()));
+                }
+                After::Quit => return Ok(ControlFlow::Break(())),
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, execution: &Execution) -> io::Result<After> {
+        let mut words = command.split_whitespace();
+        let depth = execution.depth();
+        match (words.next().unwrap_or(""), words.next()) {
+            ("continue" | "c", None) => return Ok(After::Resume(Mode::Continue)),
+            ("step" | "s", None) => return Ok(After::Resume(Mode::StepInto)),
+            ("next" | "n", None) => return Ok(After::Resume(Mode::StepOver { depth })),
+            ("out" | "o", None) => return Ok(After::Resume(Mode::StepOut { depth })),
+            ("quit" | "q", None) => return Ok(After::Quit),
+            ("break" | "b" | "delete" | "d", Some(line)) => match line.parse::<usize>() {
+                Ok(line) if command.starts_with('b') => {
+                    self.stepper.breakpoints.insert(line);
+                    writeln!(self.output, "Breakpoint at line {}.", line)?;
+                }
+                Ok(line) if self.stepper.breakpoints.remove(&line) => {
+                    writeln!(self.output, "Deleted the breakpoint at line {}.", line)?;
+                }
+                Ok(line) => writeln!(self.output, "No breakpoint at line {}.", line)?,
+                Err(_) => writeln!(self.output, "Expected a line number, not '{}'.", line)?,
+            },
+            ("locals" | "l", None) => {
+                let mut any = false;
+                for (name, value) in execution.locals() {
+                    if let Some(name) = name {
+                        writeln!(self.output, "{} = {}", name, value)?;
+                        any = true;
+                    }
+                }
+                if !any {
+                    writeln!(self.output, "No locals.")?;
+                }
+            }
+            ("globals" | "g", None) => {
+                let mut globals: Vec<_> = execution
+                    .globals()
+                    .filter(|(_, value)| !matches!(value.as_obj(), Some(Obj::Native(_))))
+                    .collect();
+                globals.sort_by_key(|(name, _)| *name);
+                if globals.is_empty() {
+                    writeln!(self.output, "No globals.")?;
+                }
+                for (name, value) in globals {
+                    writeln!(self.output, "{} = {}", name, value)?;
+                }
+            }
+            ("stack", None) => {
+                for value in execution.stack() {
+                    write!(self.output, "[ {} ]", value)?;
+                }
+                writeln!(self.output)?;
+            }
+            ("backtrace" | "bt", None) => {
+                for frame in execution.trace() {
+                    match frame.function {
+                        Some(name) => writeln!(self.output, "[line {}] in {}()", frame.line, name)?,
+                        None => writeln!(self.output, "[line {}] in script", frame.line)?,
+                    }
+                }
+            }
+            ("help" | "h", None) => writeln!(self.output, "{}", HELP)?,
+            _ => writeln!(
+                self.output,
+                "Unknown command '{}'. Type 'help' for a list.",
+                command
+            )?,
+        }
+        Ok(After::Prompt)
+    }
+}
+
+impl<I: BufRead, O: Write> Hook for Debugger<I, O> {
+    fn instruction(&mut self, execution: &Execution) -> ControlFlow<()> {
+        if self.stepper.stop(execution).is_none() {
+            return ControlFlow::Continue(());
+        }
+        // With nowhere to talk to the user there is no point going on.
+        self.prompt(execution).unwrap_or(ControlFlow::Break(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lowering::Lowered,
+        vm::{InterpretResult, VM},
+    };
+
+    const SOURCE: &str = "\
+fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var x = add(1, 2);
+print x;
+";
+
+    /// Debugs `SOURCE` with the given commands, returning the result and
+    /// the debugger's transcript.
+    fn session(commands: &str) -> (InterpretResult, String) {
+        let chunk = SOURCE.lower().unwrap();
+        let mut debugger = Debugger::new(commands.as_bytes(), vec![]);
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        let result = vm.interpret_chunk_with(&chunk, &mut debugger);
+        (result, String::from_utf8(debugger.output).unwrap())
+    }
+
+    #[test]
+    fn breakpoints_and_locals() {
+        let (result, transcript) = session("b 3\nc\nl\nbt\nc\n");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            transcript,
+            "Stopped at line 1 in script\n\
+             0000    1 Closure             1 <fn add>\n\
+             (rlox) Breakpoint at line 3.\n\
+             (rlox) Stopped at line 3 in add\n\
+             0005    3 GetLocal            3\n\
+             (rlox) a = 1\nb = 2\nsum = 3\n\
+             (rlox) [line 3] in add()\n[line 5] in script\n\
+             (rlox) "
+        );
+    }
+
+    #[test]
+    fn stepping() {
+        let (_, transcript) = session("s\ns\nn\nn\ng\nstack\n\nq\n");
+        let stops: Vec<_> = transcript
+            .lines()
+            .filter_map(|line| line.strip_prefix("(rlox) Stopped at "))
+            .collect();
+        assert_eq!(
+            stops,
+            [
+                "line 5 in script",
+                "line 2 in add",
+                "line 3 in add",
+                "line 6 in script"
+            ]
+        );
+        assert!(transcript.contains("(rlox) add = <fn add>\nx = 3\n"));
+        // An empty line repeats `stack`.
+        assert!(transcript.contains("(rlox) [ <script> ]\n(rlox) [ <script> ]\n"));
+    }
+
+    #[test]
+    fn step_out_and_quit() {
+        let (result, transcript) = session("b 2\nc\no\nbt\nq\n");
+        assert_eq!(result, InterpretResult::Interrupted);
+        assert!(transcript.contains("(rlox) Stopped at line 5 in script\n"));
+        assert!(transcript.ends_with("(rlox) [line 5] in script\n(rlox) "));
+    }
+
+    #[test]
+    fn bad_commands() {
+        let (_, transcript) = session("frobnicate\nb x\nd 9\n");
+        assert!(transcript.contains("Unknown command 'frobnicate'. Type 'help' for a list.\n"));
+        assert!(transcript.contains("Expected a line number, not 'x'.\n"));
+        assert!(transcript.contains("No breakpoint at line 9.\n"));
+    }
+}
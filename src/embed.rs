@@ -0,0 +1,307 @@
+//! A high-level interface for running Lox from a Rust program.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use crate::{
+    compiler::CompilerError,
+    lowering::Lowered,
+    native::Capabilities,
+    value::Value,
+    verifier::verify,
+    vm::{NoHook, RuntimeError, VM},
+};
+
+/// An interpreter that keeps its globals between calls, so a host can load
+/// a script once and then call into it.
+///
+/// ```
+/// use rlox::{Lox, Value};
+///
+/// let mut lox = Lox::new();
+/// lox.define_function("twice", 1, |args| {
+///     let n = f64::try_from(args[0].clone()).map_err(|error| error.to_string())?;
+///     Ok(Value::from(n * 2.0))
+/// });
+/// lox.interpret("fun add(a, b) { return twice(a) + b; }").unwrap();
+/// let sum = lox.call_global("add", &[1.into(), 2.into()]).unwrap();
+/// assert_eq!(f64::try_from(sum), Ok(4.0));
+/// ```
+pub struct Lox {
+    vm: VM,
+}
+
+/// Why a call into Lox failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Compile(Vec<CompilerError>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile(errors) => {
+                let lines: Vec<_> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Error::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lox {
+    /// An interpreter whose `print` writes to stdout.
+    pub fn new() -> Self {
+        Self::with_output(io::stdout())
+    }
+
+    /// An interpreter whose `print` writes to `output`. Errors are returned
+    /// rather than written anywhere.
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        Self {
+            vm: VM::with_output(Box::new(output), Box::new(io::sink())),
+        }
+    }
+
+    /// Sets what scripts can do; see `Capabilities`. Without this they
+    /// can't touch files.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.vm = self.vm.with_capabilities(capabilities);
+        self
+    }
+
+    /// Stops each `interpret` or call after `instructions` instructions,
+    /// with a runtime error whose `limit` is `Limit::Fuel`.
+    ///
+    /// ```
+    /// use rlox::{Error, Limit, Lox};
+    ///
+    /// let mut lox = Lox::new().with_fuel(10_000);
+    /// let Err(Error::Runtime(error)) = lox.interpret("while (true) {}") else {
+    ///     panic!("expected the loop to run out of fuel");
+    /// };
+    /// assert_eq!(error.limit, Some(Limit::Fuel));
+    /// ```
+    pub fn with_fuel(mut self, instructions: u64) -> Self {
+        self.vm = self.vm.with_fuel(instructions);
+        self
+    }
+
+    /// Stops each `interpret` or call once it has taken `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.vm = self.vm.with_timeout(timeout);
+        self
+    }
+
+    /// Refuses to allocate once the interpreter's objects would take more
+    /// than `bytes`, counting its globals and standard library.
+    pub fn with_max_heap(mut self, bytes: usize) -> Self {
+        self.vm = self.vm.with_max_heap(bytes);
+        self
+    }
+
+    /// Limits the value stack to `slots` values.
+    pub fn with_max_stack(mut self, slots: usize) -> Self {
+        self.vm = self.vm.with_max_stack(slots);
+        self
+    }
+
+    /// Stops the running script soon after another thread sets `flag`,
+    /// which is cleared again once it has stopped.
+    pub fn with_interrupt(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.vm = self.vm.with_interrupt(flag);
+        self
+    }
+
+    /// Compiles and runs `source`. Globals it defines stay defined.
+    pub fn interpret(&mut self, source: &str) -> Result<(), Error> {
+        let chunk = source.lower().map_err(Error::Compile)?;
+        // Only a bug in the compiler gets this wrong, but a host shouldn't
+        // go down with it.
+        verify(&chunk).map_err(|error| {
+            Error::Compile(vec![CompilerError::from(error.to_string().as_str())])
+        })?;
+        self.vm.execute(&chunk, &mut NoHook).map_err(Error::Runtime)
+    }
+
+    /// Calls the global function or class `name`.
+    pub fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value, Error> {
+        let callee = self.vm.global(name).ok_or_else(|| {
+            Error::Runtime(RuntimeError {
+                message: format!("Undefined variable '{}'.", name),
+                trace: vec![],
+                limit: None,
+            })
+        })?;
+        self.call(callee, args)
+    }
+
+    /// Calls a function or class value, such as one a script returned.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, Error> {
+        self.vm.call(callee, args).map_err(Error::Runtime)
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.vm.global(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.vm.set_global(name, value.into());
+    }
+
+    /// Makes a Rust function callable from Lox as the global `name`. It is
+    /// only called with exactly `arity` arguments, and an `Err` it returns
+    /// is raised in the script as a runtime error.
+    pub fn define_function(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        self.vm.define_native(name, arity, function);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn globals_persist() {
+        let mut lox = Lox::with_output(io::sink());
+        lox.set_global("limit", 3);
+        lox.interpret("var total = 0; fun bump(n) { total = total + n; return total < limit; }")
+            .unwrap();
+        assert_eq!(lox.call_global("bump", &[2.into()]), Ok(true.into()));
+        assert_eq!(lox.call_global("bump", &[2.into()]), Ok(false.into()));
+        assert_eq!(lox.global("total"), Some(4.into()));
+        assert_eq!(lox.global("missing"), None);
+    }
+
+    #[test]
+    fn calls() {
+        let mut lox = Lox::with_output(io::sink());
+        lox.interpret(
+            "class Point { init(x) { this.x = x; } }\n\
+             fun adder(n) { fun add(m) { return n + m; } return add; }",
+        )
+        .unwrap();
+        let point = lox.call_global("Point", &[1.into()]).unwrap();
+        assert_eq!(point.to_string(), "Point instance");
+        let add = lox.call_global("adder", &[10.into()]).unwrap();
+        assert_eq!(lox.call(add, &[5.into()]), Ok(15.into()));
+        assert_eq!(lox.call_global("str", &[true.into()]), Ok("true".into()));
+    }
+
+    #[test]
+    fn errors() {
+        let mut lox = Lox::with_output(io::sink());
+        let Err(Error::Compile(errors)) = lox.interpret("print ;") else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(errors.len(), 1);
+        lox.interpret("fun fail(x) { return -x; }").unwrap();
+        let error = lox.call_global("fail", &["s".into()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Operand must be a number.\n[line 1] in fail()"
+        );
+        let message = |result: Result<Value, Error>| result.unwrap_err().to_string();
+        assert_eq!(
+            message(lox.call_global("fail", &[])),
+            "Expected 1 arguments but got 0."
+        );
+        assert_eq!(
+            message(lox.call_global("nope", &[])),
+            "Undefined variable 'nope'."
+        );
+        assert_eq!(
+            message(lox.call(1.into(), &[])),
+            "Can only call functions and classes."
+        );
+        // The VM is usable again after an error.
+        assert_eq!(lox.call_global("fail", &[1.into()]), Ok((-1).into()));
+    }
+
+    #[test]
+    fn host_objects() {
+        struct Counter(RefCell<u32>);
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut lox = Lox::with_output(SharedOutput(output.clone()));
+        lox.define_function("tick", 1, |args| {
+            let counter = args[0]
+                .downcast_ref::<Counter>()
+                .ok_or("tick() expects a counter.")?;
+            *counter.0.borrow_mut() += 1;
+            Ok(Value::from(*counter.0.borrow() as f64))
+        });
+        let counter = Value::opaque(Counter(RefCell::new(0)));
+        lox.set_global("counter", counter.clone());
+        lox.interpret("tick(counter); print tick(counter); print type(counter);")
+            .unwrap();
+        assert_eq!(*counter.downcast_ref::<Counter>().unwrap().0.borrow(), 2);
+        assert_eq!(
+            String::from_utf8(output.borrow().clone()).unwrap(),
+            "2\nopaque\n"
+        );
+        let error = lox.interpret("tick(1);").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tick() expects a counter.\n[line 1] in script"
+        );
+    }
+
+    #[test]
+    fn limits() {
+        use std::sync::atomic::Ordering;
+
+        use crate::vm::Limit;
+
+        let limit = |lox: &mut Lox, source: &str| match lox.interpret(source) {
+            Err(Error::Runtime(error)) => error.limit,
+            result => panic!("expected a runtime error, got {:?}", result),
+        };
+        let mut lox = Lox::with_output(io::sink()).with_fuel(1_000);
+        assert_eq!(limit(&mut lox, "while (true) {}"), Some(Limit::Fuel));
+        // The budget is per run, so the interpreter is usable again.
+        lox.interpret("var n = 1;").unwrap();
+        let mut lox = Lox::with_output(io::sink()).with_timeout(Duration::from_millis(10));
+        assert_eq!(limit(&mut lox, "while (true) {}"), Some(Limit::Time));
+        let mut lox = Lox::with_output(io::sink()).with_max_heap(64 * 1024);
+        let grow = "var s = \"x\"; while (true) s = s + s;";
+        assert_eq!(limit(&mut lox, grow), Some(Limit::Heap));
+        let flag = Arc::new(AtomicBool::new(true));
+        let mut lox = Lox::with_output(io::sink()).with_interrupt(flag.clone());
+        assert_eq!(limit(&mut lox, "while (true) {}"), Some(Limit::Interrupt));
+        assert!(!flag.load(Ordering::Relaxed));
+        let mut lox = Lox::with_output(io::sink()).with_max_stack(16);
+        let error = lox.interpret("fun f() { f(); } f();").unwrap_err();
+        assert_eq!(error.to_string().lines().next(), Some("Stack overflow."));
+    }
+
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
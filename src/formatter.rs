@@ -0,0 +1,362 @@
+use crate::{
+    compiler::CompilerError,
+    scanner::{scan_error, Scannable, Scanner},
+    token::Token,
+    token_type::TokenType::{self, *},
+};
+
+const INDENT: &str = "    ";
+
+/// A braceless `if`/`while`/`for`/`else` body that is still open.
+struct Body {
+    depth: usize,
+    /// Written on its own line, so indented one extra level.
+    hanging: bool,
+    /// The then-branch of an `if`, which an `else` may still follow.
+    is_if: bool,
+}
+
+/// What a `{` opened, so the matching `}` knows whether an `else` may follow.
+#[derive(PartialEq)]
+enum Block {
+    IfBody,
+    Other,
+}
+
+/// Pretty-prints a token stream. Works on tokens rather than the AST so that
+/// comments survive and programs that don't parse can still be tidied.
+struct Formatter<'a> {
+    output: String,
+    line: String,
+    line_indent: usize,
+    paren_depth: usize,
+    blocks: Vec<Block>,
+    bodies: Vec<Body>,
+    /// Paren depths at which a control-flow header's condition closes, and
+    /// whether the header is an `if`.
+    headers: Vec<(usize, bool)>,
+    body_pending: Option<bool>,
+    else_pending: bool,
+    newline_pending: bool,
+    previous: Option<Token<'a>>,
+    previous_unary: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn new() -> Self {
+        Self {
+            output: String::new(),
+            line: String::new(),
+            line_indent: 0,
+            paren_depth: 0,
+            blocks: vec![],
+            bodies: vec![],
+            headers: vec![],
+            body_pending: None,
+            else_pending: false,
+            newline_pending: false,
+            previous: None,
+            previous_unary: false,
+        }
+    }
+
+    fn format(mut self, scanner: Scanner<'a>) -> Result<String, CompilerError> {
+        for token in scanner.with_comments() {
+            match token.token_type {
+                EndOfFile => break,
+                ErrorToken => return Err(CompilerError::at(token.span(), scan_error(&token))),
+                _ => self.token(token),
+            }
+        }
+        self.flush();
+        Ok(self.output)
+    }
+
+    fn token(&mut self, token: Token<'a>) {
+        let token_type = token.token_type;
+        let mut closed = None;
+        if token_type == RightBrace {
+            closed = self.blocks.pop();
+            let depth = self.blocks.len();
+            self.bodies.retain(|body| body.depth <= depth);
+            self.else_pending = false;
+        }
+        if self.else_pending && token_type != Comment {
+            self.else_pending = false;
+            if token_type != Else {
+                self.end_statement();
+            }
+        }
+        let breaks = self.breaks_before(&token);
+        if token_type != Comment {
+            if let Some(is_if) = self.body_pending.take() {
+                if token_type == LeftBrace {
+                    self.body_pending = Some(is_if);
+                } else {
+                    self.bodies.push(Body {
+                        depth: self.blocks.len(),
+                        hanging: breaks,
+                        is_if,
+                    });
+                }
+            }
+        }
+        if breaks {
+            let blank = self.previous.is_some_and(|previous| {
+                token.line > previous.line + 1
+                    && previous.token_type != LeftBrace
+                    && token_type != RightBrace
+            });
+            self.flush();
+            if blank {
+                self.output.push('\n');
+            }
+            self.line_indent =
+                self.blocks.len() + self.bodies.iter().filter(|body| body.hanging).count();
+        } else if self.needs_space(&token) {
+            self.line.push(' ');
+        }
+        self.newline_pending = false;
+        self.write(&token);
+        self.after(&token, closed);
+    }
+
+    /// A statement just finished at the current depth: close the braceless
+    /// bodies it completes, stopping at an `if` that an `else` may extend.
+    fn end_statement(&mut self) {
+        let depth = self.blocks.len();
+        while self.bodies.last().is_some_and(|body| body.depth == depth) {
+            if self.bodies.pop().unwrap().is_if {
+                self.else_pending = true;
+                return;
+            }
+        }
+    }
+
+    fn breaks_before(&self, token: &Token) -> bool {
+        let previous = match self.previous {
+            Some(previous) => previous,
+            None => return false,
+        };
+        let token_type = token.token_type;
+        if token_type == Comment {
+            return token.line > previous.line;
+        }
+        if self.newline_pending {
+            return match (previous.token_type, token_type) {
+                (Comment, _) => true,
+                (LeftBrace, RightBrace) => false,
+                (RightBrace, Else | Semicolon | RightParen | Comma) => false,
+                _ => true,
+            };
+        }
+        match (previous.token_type, token_type) {
+            (RightParen | TokenType::Identifier | Else, LeftBrace) => false,
+            (_, RightBrace) => true,
+            _ => {
+                self.paren_depth == 0
+                    && token.line > previous.line
+                    && !continues(previous.token_type)
+                    && !continuation(token_type)
+            }
+        }
+    }
+
+    fn needs_space(&self, token: &Token) -> bool {
+        let previous = match self.previous {
+            Some(previous) => previous.token_type,
+            None => return false,
+        };
+        match (previous, token.token_type) {
+            (_, Semicolon | Comma | RightParen | Dot) => false,
+            (LeftParen | Dot, _) => false,
+            (TokenType::Identifier | RightParen | This, LeftParen) => false,
+            (LeftBrace, RightBrace) => false,
+            (Minus | Bang, _) if self.previous_unary => false,
+            _ => true,
+        }
+    }
+
+    fn write(&mut self, token: &Token) {
+        if token.token_type == StringLiteral {
+            self.line.push('"');
+            self.line.push_str(token.content);
+            self.line.push('"');
+        } else {
+            self.line.push_str(token.content);
+        }
+    }
+
+    fn after(&mut self, token: &Token<'a>, closed: Option<Block>) {
+        self.previous_unary = matches!(token.token_type, Minus | Bang)
+            && !self
+                .previous
+                .is_some_and(|previous| ends_value(previous.token_type));
+        match token.token_type {
+            LeftBrace => {
+                self.blocks.push(match self.body_pending.take() {
+                    Some(true) => Block::IfBody,
+                    _ => Block::Other,
+                });
+                self.newline_pending = true;
+            }
+            RightBrace => {
+                self.newline_pending = true;
+                // Only an `if` body can be followed by `else`; anything else
+                // completes its statement here.
+                if closed == Some(Block::IfBody) {
+                    self.else_pending = true;
+                } else {
+                    self.end_statement();
+                }
+            }
+            Semicolon if self.paren_depth == 0 => {
+                self.newline_pending = true;
+                self.end_statement();
+            }
+            Comment => self.newline_pending = true,
+            LeftParen => self.paren_depth += 1,
+            RightParen => {
+                self.paren_depth = self.paren_depth.saturating_sub(1);
+                if self.headers.last().map(|(depth, _)| *depth) == Some(self.paren_depth) {
+                    let (_, is_if) = self.headers.pop().unwrap();
+                    self.body_pending = Some(is_if);
+                }
+            }
+            If => self.headers.push((self.paren_depth, true)),
+            While | For => self.headers.push((self.paren_depth, false)),
+            Else => self.body_pending = Some(false),
+            _ => {}
+        }
+        self.previous = Some(*token);
+    }
+
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        for _ in 0..self.line_indent {
+            self.output.push_str(INDENT);
+        }
+        self.output.push_str(&self.line);
+        self.output.push('\n');
+        self.line.clear();
+    }
+}
+
+fn ends_value(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Identifier | Number | StringLiteral | RightParen | True | False | Nil | This
+    )
+}
+
+/// Tokens after which a source line break is just wrapping an unfinished
+/// expression or declaration.
+fn continues(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        Plus | Minus
+            | Star
+            | Slash
+            | EqualEqual
+            | BangEqual
+            | Less
+            | LessEqual
+            | Greater
+            | GreaterEqual
+            | Equal
+            | Bang
+            | And
+            | Or
+            | Comma
+            | Dot
+            | LeftParen
+            | Var
+            | Fun
+            | Class
+    )
+}
+
+/// Tokens that can't begin a statement, so a line starting with one
+/// continues the previous line.
+fn continuation(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        Plus | Star
+            | Slash
+            | EqualEqual
+            | BangEqual
+            | Less
+            | LessEqual
+            | Greater
+            | GreaterEqual
+            | Equal
+            | And
+            | Or
+            | Comma
+            | Dot
+            | RightParen
+            | Semicolon
+    )
+}
+
+pub trait Formatted {
+    fn format(&self) -> Result<String, CompilerError>;
+}
+
+impl Formatted for &str {
+    fn format(&self) -> Result<String, CompilerError> {
+        Formatter::new().format(self.scanner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_dir, read_to_string};
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let source = "fun add(a,b){return a+b;}\nif(x){print -add( 1 ,2);}\nelse{print !true;}";
+        assert_eq!(
+            source.format().unwrap(),
+            "fun add(a, b) {\n    return a + b;\n}\nif (x) {\n    print -add(1, 2);\n} else {\n    print !true;\n}\n"
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let source =
+            "// header\nvar a = 1; // trailing\n\n\n\nclass A {}\n{\n  // inside\n  print a;\n}";
+        assert_eq!(
+            source.format().unwrap(),
+            "// header\nvar a = 1; // trailing\n\nclass A {}\n{\n    // inside\n    print a;\n}\n"
+        );
+    }
+
+    #[test]
+    fn hanging_bodies() {
+        let source = "while (a)\nif (b)\nprint c;\nelse\nprint d;\nprint e;";
+        assert_eq!(
+            source.format().unwrap(),
+            "while (a)\n    if (b)\n        print c;\n    else\n        print d;\nprint e;\n"
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        for file in read_dir("tests/programs").unwrap() {
+            let source = read_to_string(file.unwrap().path()).unwrap();
+            let once = source.as_str().format().unwrap();
+            assert_eq!(once.as_str().format().unwrap(), once);
+        }
+    }
+
+    #[test]
+    fn unexpected_character() {
+        let error = "var a = 1;\nvar b = @;".format().unwrap_err();
+        assert_eq!(error.span.unwrap().line, 2);
+    }
+}
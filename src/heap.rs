@@ -0,0 +1,21 @@
+//! Accounting of the memory held by Lox objects and tables, which the VM's
+//! heap limit is checked against.
+
+use std::cell::Cell;
+
+thread_local! {
+    static LIVE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Approximate bytes held by live objects and tables on this thread.
+pub fn live() -> usize {
+    LIVE.with(Cell::get)
+}
+
+pub(crate) fn allocated(bytes: usize) {
+    LIVE.with(|live| live.set(live.get() + bytes));
+}
+
+pub(crate) fn freed(bytes: usize) {
+    LIVE.with(|live| live.set(live.get() - bytes));
+}
@@ -1,7 +1,82 @@
-pub mod chunk;
-pub mod scanner;
-pub mod token;
-pub mod token_type;
-pub mod vm;
-pub mod compiler;
-pub mod chunk_printer;
+//! A bytecode virtual machine for Lox.
+//!
+//! Hosts embedding scripts use [`Lox`]. Tools work on the stages it runs:
+//! [`Parsed::parse_program`] builds an [`ast::Program`], [`Lowered::lower`]
+//! turns it (or source) into a [`Chunk`], [`verify`] checks a chunk before
+//! it runs, and [`Chunk::serialize`] and [`Chunk::deserialize`] save and
+//! load `.loxc` files. [`Formatted::format`] pretty-prints source.
+//!
+//! ```
+//! use rlox::{verify, Chunk, Lowered, Parsed};
+//!
+//! let program = "print 1 + 2;".parse_program().unwrap();
+//! let chunk = program.lower().unwrap();
+//! let bytes = chunk.serialize(None).unwrap();
+//! let loaded = Chunk::deserialize(&bytes).unwrap();
+//! assert!(verify(&loaded).is_ok());
+//! ```
+
+pub mod ast;
+mod chunk;
+mod chunk_printer;
+mod compiler;
+mod coverage;
+mod dap;
+mod debugger;
+mod embed;
+mod formatter;
+mod heap;
+mod lowering;
+mod lsp;
+mod native;
+mod object;
+mod optimizer;
+mod parser;
+mod profiler;
+mod protocol;
+mod resolver;
+mod scanner;
+mod serialize;
+mod stack;
+mod table;
+mod token;
+mod token_type;
+mod value;
+mod verifier;
+mod vm;
+
+pub use chunk::{Chunk, OpCode};
+pub use compiler::CompilerError;
+pub use embed::{Error, Lox};
+pub use formatter::Formatted;
+pub use lowering::Lowered;
+pub use native::Capabilities;
+pub use parser::Parsed;
+pub use serialize::{check_source, source_hash, LoadError, SaveError};
+pub use token::Span;
+pub use value::{ConversionError, Value};
+pub use verifier::{verify, VerifyError, VerifyErrorKind};
+pub use vm::{Limit, RuntimeError, TraceLine};
+
+/// What the `rlox` binary, the benchmarks, the fuzz targets and the
+/// integration tests build on. None of it is part of the embedding API, and
+/// it can change in any release.
+#[doc(hidden)]
+pub mod internals {
+    pub use crate::{
+        chunk_printer::print_chunk,
+        coverage::Coverage,
+        dap::serve as serve_dap,
+        debugger::Debugger,
+        lsp::serve as serve_lsp,
+        object::{intern, Obj},
+        optimizer::optimize,
+        profiler::Profiler,
+        resolver::Checked,
+        scanner::Scanned,
+        serialize::MAGIC,
+        stack::Stack,
+        table::Table,
+        vm::{InterpretResult, STACK_MAX, VM},
+    };
+}
@@ -0,0 +1,929 @@
+use crate::{
+    ast::*,
+    chunk::{Chunk, LocalName, OpCode, Value},
+    compiler::CompilerError,
+    object::Function as FunctionObject,
+    parser::Parsed,
+    token::Span,
+};
+
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+#[derive(PartialEq, Clone, Copy)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: String,
+    /// `None` while the variable's initializer is being compiled.
+    depth: Option<usize>,
+    is_captured: bool,
+    /// Index of its entry in the chunk's `locals` once it is in scope.
+    debug_name: Option<usize>,
+}
+
+#[derive(PartialEq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+struct FunctionState {
+    function: FunctionObject,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(name: Option<&str>, kind: FunctionKind) -> Self {
+        // Slot zero holds the callee, or the receiver inside methods.
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            _ => "",
+        };
+        let mut function = FunctionObject::new(name);
+        if !slot_zero.is_empty() {
+            function.chunk.locals.push(LocalName {
+                name: slot_zero.into(),
+                slot: 0,
+                live: 0..usize::MAX,
+            });
+        }
+        Self {
+            function,
+            kind,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: Some(0),
+                is_captured: false,
+                debug_name: None,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<(u8, bool)> {
+        self.locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(slot, local)| (slot as u8, local.depth.is_some()))
+    }
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Walks a `Program` and emits bytecode for it, one `FunctionState` per
+/// function being compiled.
+struct Lowering {
+    functions: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    errors: Vec<CompilerError>,
+}
+
+enum Access {
+    Get,
+    Set,
+}
+
+impl Lowering {
+    fn new() -> Self {
+        Self {
+            functions: vec![FunctionState::new(None, FunctionKind::Script)],
+            classes: vec![],
+            errors: vec![],
+        }
+    }
+
+    fn lower(mut self, program: &Program) -> Result<Chunk, Vec<CompilerError>> {
+        for statement in program.statements.iter() {
+            self.statement(statement);
+        }
+        let end = program
+            .statements
+            .last()
+            .map_or(Span::default(), |s| s.span);
+        self.emit_return(end);
+        if self.errors.is_empty() {
+            Ok(self.end_function().function.chunk)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Pops the finished function, ending the debug ranges of the locals
+    /// still in scope at its end.
+    fn end_function(&mut self) -> FunctionState {
+        let mut state = self.functions.pop().unwrap();
+        let chunk = &mut state.function.chunk;
+        let end = chunk.code.len();
+        for local in chunk.locals.iter_mut() {
+            local.live.end = local.live.end.min(end);
+        }
+        state
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.functions.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn error(&mut self, span: Span, message: &str) {
+        self.errors.push(CompilerError::at(span, message));
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        let span = statement.span;
+        match &statement.kind {
+            StmtKind::Class(class) => self.class(class, span),
+            StmtKind::Fun(function) => {
+                let global = self.declare_variable(&function.name);
+                self.mark_initialized();
+                self.function(function, FunctionKind::Function);
+                self.define_variable(global, span);
+            }
+            StmtKind::Var { name, initializer } => {
+                let global = self.declare_variable(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit_op(OpCode::Nil, span),
+                }
+                self.define_variable(global, span);
+            }
+            StmtKind::Expression(expression) => {
+                self.expression(expression);
+                self.emit_op(OpCode::Pop, span);
+            }
+            StmtKind::Print(expression) => {
+                self.expression(expression);
+                self.emit_op(OpCode::Print, span);
+            }
+            StmtKind::Return(value) => {
+                match self.state().kind {
+                    FunctionKind::Script => self.error(span, "Can't return from top-level code."),
+                    FunctionKind::Initializer if value.is_some() => {
+                        self.error(span, "Can't return a value from an initializer.")
+                    }
+                    _ => {}
+                }
+                match value {
+                    Some(value) => {
+                        self.expression(value);
+                        self.emit_op(OpCode::Return, span);
+                    }
+                    None => self.emit_return(span),
+                }
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                self.emit_op(OpCode::Pop, span);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump, span);
+                self.patch_jump(then_jump, span);
+                self.emit_op(OpCode::Pop, span);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump, span);
+            }
+            StmtKind::While { condition, body } => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                self.emit_op(OpCode::Pop, span);
+                self.statement(body);
+                self.emit_loop(loop_start, span);
+                self.patch_jump(exit_jump, span);
+                self.emit_op(OpCode::Pop, span);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                let mut loop_start = self.chunk().code.len();
+                let exit_jump = condition.as_ref().map(|condition| {
+                    self.expression(condition);
+                    let exit_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                    self.emit_op(OpCode::Pop, span);
+                    exit_jump
+                });
+                if let Some(increment) = increment {
+                    let body_jump = self.emit_jump(OpCode::Jump, span);
+                    let increment_start = self.chunk().code.len();
+                    self.expression(increment);
+                    self.emit_op(OpCode::Pop, span);
+                    self.emit_loop(loop_start, span);
+                    loop_start = increment_start;
+                    self.patch_jump(body_jump, span);
+                }
+                self.statement(body);
+                self.emit_loop(loop_start, span);
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump, span);
+                    self.emit_op(OpCode::Pop, span);
+                }
+                self.end_scope(span);
+            }
+            StmtKind::Block(statements) => {
+                self.begin_scope();
+                for statement in statements.iter() {
+                    self.statement(statement);
+                }
+                self.end_scope(span);
+            }
+        }
+    }
+
+    fn class(&mut self, class: &Class, span: Span) {
+        let global = self.declare_variable(&class.name);
+        // At top level the global's name is also the class's name.
+        let name_constant = match global {
+            Some(constant) => constant,
+            None => self.identifier_constant(&class.name),
+        };
+        self.emit_op(OpCode::Class, span);
+        self.emit_byte(name_constant, span);
+        self.define_variable(global, span);
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        if let Some(superclass) = &class.superclass {
+            if superclass.name == class.name.name {
+                self.error(superclass.span, "A class can't inherit from itself.");
+            }
+            self.named_variable(superclass, Access::Get);
+            self.begin_scope();
+            self.add_local("super", superclass.span);
+            self.mark_initialized();
+            self.named_variable(&class.name, Access::Get);
+            self.emit_op(OpCode::Inherit, span);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+        self.named_variable(&class.name, Access::Get);
+        for method in class.methods.iter() {
+            let constant = self.identifier_constant(&method.name);
+            let kind = if method.name.name == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+            self.emit_op(OpCode::Method, method.span);
+            self.emit_byte(constant, method.span);
+        }
+        self.emit_op(OpCode::Pop, span);
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope(span);
+        }
+    }
+
+    fn function(&mut self, function: &Function, kind: FunctionKind) {
+        self.functions
+            .push(FunctionState::new(Some(&function.name.name), kind));
+        self.begin_scope();
+        for param in function.params.iter() {
+            let arity = &mut self.state_mut().function.arity;
+            match arity.checked_add(1) {
+                Some(more) => *arity = more,
+                None => self.error(param.span, "Can't have more than 255 parameters."),
+            }
+            let global = self.declare_variable(param);
+            self.define_variable(global, param.span);
+        }
+        for statement in function.body.iter() {
+            self.statement(statement);
+        }
+        self.emit_return(function.span);
+        let state = self.end_function();
+        let mut compiled = state.function;
+        compiled.upvalue_count = state.upvalues.len();
+        let constant = self.make_constant(Value::function(compiled), function.span);
+        self.emit_op(OpCode::Closure, function.span);
+        self.emit_byte(constant, function.span);
+        for upvalue in state.upvalues.iter() {
+            self.emit_byte(upvalue.is_local as u8, function.span);
+            self.emit_byte(upvalue.index, function.span);
+        }
+    }
+
+    fn expression(&mut self, expression: &Expr) {
+        let (innermost, links) = expression.chain();
+        self.unchained(innermost);
+        for link in links {
+            self.link(link);
+        }
+    }
+
+    /// Lowers a link of a chain whose chained operand has been lowered.
+    fn link(&mut self, expression: &Expr) {
+        let span = expression.span;
+        match &expression.kind {
+            ExprKind::Binary {
+                operator, right, ..
+            } => {
+                self.expression(right);
+                let (op_code, negate) = match operator {
+                    BinaryOperator::Add => (OpCode::Add, false),
+                    BinaryOperator::Subtract => (OpCode::Subtract, false),
+                    BinaryOperator::Multiply => (OpCode::Multiply, false),
+                    BinaryOperator::Divide => (OpCode::Divide, false),
+                    BinaryOperator::Equal => (OpCode::Equal, false),
+                    BinaryOperator::NotEqual => (OpCode::Equal, true),
+                    BinaryOperator::Less => (OpCode::Less, false),
+                    BinaryOperator::LessEqual => (OpCode::Greater, true),
+                    BinaryOperator::Greater => (OpCode::Greater, false),
+                    BinaryOperator::GreaterEqual => (OpCode::Less, true),
+                };
+                self.emit_op(op_code, span);
+                if negate {
+                    self.emit_op(OpCode::Not, span);
+                }
+            }
+            ExprKind::Logical {
+                operator, right, ..
+            } => match operator {
+                LogicalOperator::And => {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                    self.emit_op(OpCode::Pop, span);
+                    self.expression(right);
+                    self.patch_jump(end_jump, span);
+                }
+                LogicalOperator::Or => {
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                    let end_jump = self.emit_jump(OpCode::Jump, span);
+                    self.patch_jump(else_jump, span);
+                    self.emit_op(OpCode::Pop, span);
+                    self.expression(right);
+                    self.patch_jump(end_jump, span);
+                }
+            },
+            ExprKind::Call { arguments, .. } => {
+                for argument in arguments.iter() {
+                    self.expression(argument);
+                }
+                // The parser reports this too, but lowering can be given a
+                // tree from elsewhere.
+                let argc = u8::try_from(arguments.len()).unwrap_or_else(|_| {
+                    self.error(span, "Can't have more than 255 arguments.");
+                    u8::MAX
+                });
+                self.emit_op(OpCode::Call, span);
+                self.emit_byte(argc, span);
+            }
+            ExprKind::Get { name, .. } => {
+                let constant = self.identifier_constant(name);
+                self.emit_op(OpCode::GetProperty, span);
+                self.emit_byte(constant, span);
+            }
+            _ => unreachable!("only chains have links"),
+        }
+    }
+
+    /// Lowers an expression that isn't a link of a chain.
+    fn unchained(&mut self, expression: &Expr) {
+        let span = expression.span;
+        match &expression.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Nil => self.emit_op(OpCode::Nil, span),
+                Literal::Bool(true) => self.emit_op(OpCode::True, span),
+                Literal::Bool(false) => self.emit_op(OpCode::False, span),
+                Literal::Number(n) => self.emit_constant(Value::number(*n), span),
+                Literal::String(s) => self.emit_constant(Value::string(s), span),
+            },
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Unary { operator, operand } => {
+                self.expression(operand);
+                self.emit_op(
+                    match operator {
+                        UnaryOperator::Negate => OpCode::Negate,
+                        UnaryOperator::Not => OpCode::Not,
+                    },
+                    span,
+                );
+            }
+            ExprKind::Binary { .. }
+            | ExprKind::Logical { .. }
+            | ExprKind::Call { .. }
+            | ExprKind::Get { .. } => unreachable!("chains are lowered a link at a time"),
+            ExprKind::Variable(name) => self.named_variable(name, Access::Get),
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                self.named_variable(name, Access::Set);
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                let constant = self.identifier_constant(name);
+                self.emit_op(OpCode::SetProperty, span);
+                self.emit_byte(constant, span);
+            }
+            ExprKind::This => {
+                if self.classes.is_empty() {
+                    self.error(span, "Can't use 'this' outside of a class.");
+                    return;
+                }
+                self.named_variable(&synthetic("this", span), Access::Get);
+            }
+            ExprKind::Super { method } => {
+                match self.classes.last() {
+                    None => self.error(span, "Can't use 'super' outside of a class."),
+                    Some(ClassState {
+                        has_superclass: false,
+                    }) => self.error(span, "Can't use 'super' in a class with no superclass."),
+                    _ => {}
+                }
+                let constant = self.identifier_constant(method);
+                self.named_variable(&synthetic("this", span), Access::Get);
+                self.named_variable(&synthetic("super", span), Access::Get);
+                self.emit_op(OpCode::GetSuper, span);
+                self.emit_byte(constant, span);
+            }
+        }
+    }
+
+    fn named_variable(&mut self, name: &Identifier, access: Access) {
+        let (get, set, operand) =
+            if let Some(slot) = self.resolve_local(self.functions.len() - 1, name) {
+                (OpCode::GetLocal, OpCode::SetLocal, slot)
+            } else if let Some(index) = self.resolve_upvalue(self.functions.len() - 1, name) {
+                (OpCode::GetUpvalue, OpCode::SetUpvalue, index)
+            } else {
+                let constant = self.identifier_constant(name);
+                (OpCode::GetGlobal, OpCode::SetGlobal, constant)
+            };
+        let op_code = match access {
+            Access::Get => get,
+            Access::Set => set,
+        };
+        self.emit_op(op_code, name.span);
+        self.emit_byte(operand, name.span);
+    }
+
+    fn resolve_local(&mut self, function: usize, name: &Identifier) -> Option<u8> {
+        let (slot, initialized) = self.functions[function].resolve_local(&name.name)?;
+        if !initialized {
+            self.error(
+                name.span,
+                "Can't read local variable in its own initializer.",
+            );
+        }
+        Some(slot)
+    }
+
+    fn resolve_upvalue(&mut self, function: usize, name: &Identifier) -> Option<u8> {
+        if function == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            self.functions[function - 1].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(function, slot, true, name));
+        }
+        let index = self.resolve_upvalue(function - 1, name)?;
+        Some(self.add_upvalue(function, index, false, name))
+    }
+
+    fn add_upvalue(&mut self, function: usize, index: u8, is_local: bool, name: &Identifier) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let state = &mut self.functions[function];
+        if let Some(existing) = state.upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+        if state.upvalues.len() == MAX_UPVALUES {
+            self.error(name.span, "Too many closure variables in function.");
+            return 0;
+        }
+        state
+            .function
+            .chunk
+            .upvalue_names
+            .push(name.name.as_str().into());
+        state.upvalues.push(upvalue);
+        (state.upvalues.len() - 1) as u8
+    }
+
+    /// Declares `name` in the current scope, returning its name constant when
+    /// it is a global.
+    fn declare_variable(&mut self, name: &Identifier) -> Option<u8> {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return Some(self.identifier_constant(name));
+        }
+        let duplicate = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+            .any(|local| local.name == name.name);
+        if duplicate {
+            self.error(
+                name.span,
+                "Already a variable with this name in this scope.",
+            );
+        }
+        self.add_local(&name.name, name.span);
+        None
+    }
+
+    fn add_local(&mut self, name: &str, span: Span) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error(span, "Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name: name.to_string(),
+            depth: None,
+            is_captured: false,
+            debug_name: None,
+        });
+    }
+
+    fn define_variable(&mut self, global: Option<u8>, span: Span) {
+        match global {
+            Some(constant) => {
+                self.emit_op(OpCode::DefineGlobal, span);
+                self.emit_byte(constant, span);
+            }
+            None => self.mark_initialized(),
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        let slot = state.locals.len() - 1;
+        let chunk = &mut state.function.chunk;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(depth);
+            local.debug_name = Some(chunk.locals.len());
+            chunk.locals.push(LocalName {
+                name: local.name.as_str().into(),
+                slot: slot as u8,
+                live: chunk.code.len()..usize::MAX,
+            });
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, span: Span) {
+        self.state_mut().scope_depth -= 1;
+        let depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_some_and(|d| d <= depth) {
+                break;
+            }
+            let op_code = if local.is_captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            self.emit_op(op_code, span);
+            let local = self.state_mut().locals.pop().unwrap();
+            if let Some(index) = local.debug_name {
+                let chunk = self.chunk();
+                chunk.locals[index].live.end = chunk.code.len();
+            }
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Identifier) -> u8 {
+        self.make_constant(Value::string(&name.name), name.span)
+    }
+
+    fn make_constant(&mut self, value: Value, span: Span) -> u8 {
+        match self.chunk().put_constant(value).map(u8::try_from) {
+            Ok(Ok(constant)) => constant,
+            _ => {
+                self.error(span, "Too many constants in one chunk.");
+                0
+            }
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value, span: Span) {
+        if let Err(error) = self.chunk().write_constant(value, span.line as usize) {
+            self.error(span, &error.to_string());
+        }
+    }
+
+    fn emit_op(&mut self, op_code: OpCode, span: Span) {
+        self.chunk().write_op_code(op_code, span.line as usize);
+    }
+
+    fn emit_byte(&mut self, byte: u8, span: Span) {
+        self.chunk().write_operand(byte, span.line as usize);
+    }
+
+    fn emit_return(&mut self, span: Span) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_op(OpCode::GetLocal, span);
+            self.emit_byte(0, span);
+        } else {
+            self.emit_op(OpCode::Nil, span);
+        }
+        self.emit_op(OpCode::Return, span);
+    }
+
+    fn emit_jump(&mut self, op_code: OpCode, span: Span) -> usize {
+        self.emit_op(op_code, span);
+        self.emit_byte(0xff, span);
+        self.emit_byte(0xff, span);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize, span: Span) {
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error(span, "Too much code to jump over.");
+        }
+        let code = &mut self.chunk().code;
+        code[offset] = (jump >> 8) as u8;
+        code[offset + 1] = jump as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, span: Span) {
+        self.emit_op(OpCode::Loop, span);
+        let jump = self.chunk().code.len() - loop_start + 2;
+        if jump > u16::MAX as usize {
+            self.error(span, "Loop body too large.");
+        }
+        self.emit_byte((jump >> 8) as u8, span);
+        self.emit_byte(jump as u8, span);
+    }
+}
+
+fn synthetic(name: &str, span: Span) -> Identifier {
+    Identifier {
+        name: name.to_string(),
+        span,
+    }
+}
+
+pub trait Lowered {
+    fn lower(&self) -> Result<Chunk, Vec<CompilerError>>;
+}
+
+impl Lowered for Program {
+    fn lower(&self) -> Result<Chunk, Vec<CompilerError>> {
+        Lowering::new().lower(self)
+    }
+}
+
+/// Parses and lowers a whole Lox program in one go.
+impl Lowered for &str {
+    fn lower(&self) -> Result<Chunk, Vec<CompilerError>> {
+        self.parse_program()?.lower()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_to_string;
+
+    use super::*;
+    use crate::object::Obj;
+
+    fn op_codes(chunk: &Chunk) -> Vec<OpCode> {
+        let mut offset = 0;
+        let mut op_codes = vec![];
+        while offset < chunk.code.len() {
+            op_codes.push(OpCode::try_from(chunk.code[offset]).unwrap());
+            offset += chunk.instruction_len(offset).unwrap();
+        }
+        op_codes
+    }
+
+    #[test]
+    fn arithmetic() {
+        let chunk = "print -(1 + 2) * 3;".lower().unwrap();
+        assert_eq!(
+            op_codes(&chunk),
+            vec![
+                OpCode::Constant,
+                OpCode::Constant,
+                OpCode::Add,
+                OpCode::Negate,
+                OpCode::Constant,
+                OpCode::Multiply,
+                OpCode::Print,
+                OpCode::Nil,
+                OpCode::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn locals_and_upvalues() {
+        let chunk = "fun outer() { var x = 1; fun inner() { return x; } return inner; }"
+            .lower()
+            .unwrap();
+        let Some(Obj::Function(outer)) = chunk.constants[1].as_obj() else {
+            panic!("expected function constant");
+        };
+        assert_eq!(outer.upvalue_count, 0);
+        assert!(op_codes(&outer.chunk).contains(&OpCode::Closure));
+        let inner = outer
+            .chunk
+            .constants
+            .iter()
+            .find_map(|c| match c.as_obj() {
+                Some(Obj::Function(f)) => Some(f),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(inner.upvalue_count, 1);
+        assert_eq!(
+            op_codes(&inner.chunk),
+            vec![
+                OpCode::GetUpvalue,
+                OpCode::Return,
+                OpCode::Nil,
+                OpCode::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn local_names() {
+        // 0: Constant, 2: GetLocal 1, 4: GetLocal 2, 6: Print, 7: Pop,
+        // 8: GetLocal 1, 10: Print, 11: Pop
+        let chunk = "{ var a = 1; { var b = a; print b; } print a; }"
+            .lower()
+            .unwrap();
+        assert_eq!(chunk.local_name(1, 0), None);
+        assert_eq!(chunk.local_name(1, 2), Some("a"));
+        assert_eq!(chunk.local_name(2, 2), None);
+        assert_eq!(chunk.local_name(2, 4), Some("b"));
+        assert_eq!(chunk.local_name(2, 8), None);
+        assert_eq!(chunk.local_name(1, 11), Some("a"));
+        assert_eq!(chunk.local_name(1, 12), None);
+        let chunk = "class A { m(x) { return this; } }".lower().unwrap();
+        let method = chunk
+            .constants
+            .iter()
+            .find_map(|c| match c.as_obj() {
+                Some(Obj::Function(f)) => Some(f),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(method.chunk.local_name(0, 0), Some("this"));
+        assert_eq!(method.chunk.local_name(1, 0), Some("x"));
+    }
+
+    #[test]
+    fn local_classes() {
+        let chunk = "{ class A {} }".lower().unwrap();
+        assert_eq!(
+            op_codes(&chunk),
+            vec![
+                OpCode::Class,
+                OpCode::GetLocal,
+                OpCode::Pop,
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Return
+            ]
+        );
+        assert!("fun f() { class B {} return B; }".lower().is_ok());
+    }
+
+    #[test]
+    fn lowers_sample_programs() {
+        let source = read_to_string("tests/programs/fib.lox").unwrap();
+        assert!(source.as_str().lower().is_ok());
+    }
+
+    #[test]
+    fn semantic_errors() {
+        let messages = |source: &str| -> Vec<String> {
+            source
+                .lower()
+                .unwrap_err()
+                .into_iter()
+                .map(|e| e.message)
+                .collect()
+        };
+        assert_eq!(
+            messages("return 1;"),
+            vec!["Can't return from top-level code."]
+        );
+        assert_eq!(
+            messages("{ var a = 1; var a = 2; }"),
+            vec!["Already a variable with this name in this scope."]
+        );
+        assert_eq!(
+            messages("{ var a = a; }"),
+            vec!["Can't read local variable in its own initializer."]
+        );
+        assert_eq!(
+            messages("print this;"),
+            vec!["Can't use 'this' outside of a class."]
+        );
+        assert_eq!(
+            messages("class A { go() { super.go(); } }"),
+            vec!["Can't use 'super' in a class with no superclass."]
+        );
+        assert_eq!(
+            messages("class A < A {}"),
+            vec!["A class can't inherit from itself."]
+        );
+        assert_eq!(
+            messages("class A { init() { return 1; } }"),
+            vec!["Can't return a value from an initializer."]
+        );
+    }
+
+    #[test]
+    fn constant_pool_stays_bounded() {
+        let body = "total = total + 0 * count; count = count + 1;\n".repeat(300);
+        let source = format!(
+            "var total = 0; var count = 0;\nwhile (count < 10) {{\n{}}}",
+            body
+        );
+        let chunk = source.as_str().lower().unwrap();
+        assert_eq!(chunk.constants.len(), 5);
+        assert!(!op_codes(&chunk).contains(&OpCode::ConstantLong));
+    }
+
+    #[test]
+    fn long_chains_and_deep_nesting() {
+        use crate::resolver::Checked;
+
+        let chain = format!("print 0{};", " + 1".repeat(50_000));
+        let chunk = chain.as_str().lower().unwrap();
+        let adds = op_codes(&chunk)
+            .into_iter()
+            .filter(|op_code| *op_code == OpCode::Add)
+            .count();
+        assert_eq!(adds, 50_000);
+        let calls = format!("fun f() {{ return f; }} f(){}.x;", "()".repeat(50_000));
+        assert!(calls.as_str().check().errors.is_empty());
+        let deepest = format!(
+            "{}print {}1{};{}",
+            "{".repeat(63),
+            "(".repeat(62),
+            ")".repeat(62),
+            "}".repeat(63)
+        );
+        assert!(deepest.as_str().check().errors.is_empty());
+        assert!(deepest.as_str().lower().is_ok());
+    }
+
+    #[test]
+    fn argument_count_from_another_tree() {
+        use crate::{
+            ast::{ExprKind, StmtKind},
+            parser::Parsed,
+        };
+
+        let source = format!("f({});", vec!["1"; 255].join(", "));
+        let mut program = source.as_str().parse_program().unwrap();
+        let StmtKind::Expression(call) = &mut program.statements[0].kind else {
+            panic!("expected expression statement");
+        };
+        let ExprKind::Call { arguments, .. } = &mut call.kind else {
+            panic!("expected call");
+        };
+        arguments.push(arguments[0].clone());
+        let errors = program.lower().unwrap_err();
+        assert_eq!(errors[0].message, "Can't have more than 255 arguments.");
+    }
+}
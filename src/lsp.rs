@@ -0,0 +1,504 @@
+//! The `rlox lsp` language server: diagnostics, semantic tokens, document
+//! symbols, go-to-definition and hover over the Language Server Protocol.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    ast::{Function, Program, Stmt, StmtKind},
+    parser::Parsed,
+    protocol::{read_message, write_message},
+    resolver::{Checked, Resolved},
+    scanner::Scannable,
+    token::Span,
+    token_type::TokenType,
+};
+
+/// The semantic token types the server reports, indexed by `token_kind`.
+const TOKEN_TYPES: [&str; 6] = [
+    "keyword", "variable", "string", "number", "operator", "comment",
+];
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+
+/// Serves one client, reading from `input` and writing to `output` until it
+/// sends `exit`. Returns whether it asked the server to shut down first, as
+/// a clean exit requires.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<bool> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let Some(method) = message["method"].as_str() else {
+            // A response to a request the server never makes.
+            continue;
+        };
+        if method == "exit" {
+            return Ok(server.shut_down);
+        }
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => server.request(id, method, params)?,
+            None => server.notification(method, params)?,
+        }
+    }
+    Ok(false)
+}
+
+struct Server<O> {
+    output: O,
+    /// The text of each open document, by URI.
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+impl<O: Write> Server<O> {
+    fn request(&mut self, id: &Json, method: &str, params: &Json) -> io::Result<()> {
+        let result = if self.shut_down {
+            Err((INVALID_REQUEST, "The server is shut down.".to_string()))
+        } else {
+            self.answer(method, params)
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        write_message(&mut self.output, &response)
+    }
+
+    fn answer(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        if method == "initialize" {
+            return Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 1, "save": true },
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                    "documentSymbolProvider": true,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                },
+                "serverInfo": { "name": "rlox" },
+            }));
+        }
+        if method == "shutdown" {
+            self.shut_down = true;
+            return Ok(Json::Null);
+        }
+        if !matches!(
+            method,
+            "textDocument/semanticTokens/full"
+                | "textDocument/documentSymbol"
+                | "textDocument/definition"
+                | "textDocument/hover"
+        ) {
+            return Err((METHOD_NOT_FOUND, format!("Unknown method '{}'.", method)));
+        }
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(text) = self.documents.get(uri) else {
+            return Ok(Json::Null);
+        };
+        let lines = Lines::new(text);
+        let offset = lines.offset(&params["position"]);
+        Ok(match method {
+            "textDocument/semanticTokens/full" => json!({ "data": semantic_tokens(&lines) }),
+            "textDocument/documentSymbol" => match text.as_str().parse_program() {
+                Ok(program) => json!(symbols(&program.statements, &lines)),
+                Err(_) => Json::Null,
+            },
+            "textDocument/definition" => match definition(text, offset) {
+                Some(declaration) => json!({ "uri": uri, "range": lines.range(declaration) }),
+                None => Json::Null,
+            },
+            "textDocument/hover" => match hover(text, offset) {
+                Some((span, contents)) => json!({
+                    "contents": { "kind": "markdown", "value": contents },
+                    "range": lines.range(span),
+                }),
+                None => Json::Null,
+            },
+            _ => unreachable!("checked above"),
+        })
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let document = &params["textDocument"];
+        let Some(uri) = document["uri"].as_str() else {
+            return Ok(());
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = document["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+                self.publish_diagnostics(uri)
+            }
+            "textDocument/didChange" => {
+                // Changes are always whole documents, as `initialize` asks.
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|changes| changes.last()?["text"].as_str()) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                Ok(())
+            }
+            "textDocument/didSave" => self.publish_diagnostics(uri),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.publish_diagnostics(uri)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports the compile errors and warnings in the document at `uri`,
+    /// or clears them once it is closed.
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = self
+            .documents
+            .get(uri)
+            .map_or(vec![], |text| diagnostics(text));
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        write_message(&mut self.output, &notification)
+    }
+}
+
+/// Converts between byte offsets and LSP positions, which count lines from
+/// zero and characters in UTF-16 code units.
+struct Lines<'a> {
+    text: &'a str,
+    /// The offset each line starts at.
+    starts: Vec<usize>,
+}
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str) -> Self {
+        let newlines = text.match_indices('\n').map(|(offset, _)| offset + 1);
+        Self {
+            text,
+            starts: std::iter::once(0).chain(newlines).collect(),
+        }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let character = utf16_len(&self.text[self.starts[line]..offset]);
+        json!({ "line": line, "character": character })
+    }
+
+    fn range(&self, span: Span) -> Json {
+        json!({ "start": self.position(span.start), "end": self.position(span.end) })
+    }
+
+    /// The offset of an LSP position, clamped to the end of its line.
+    fn offset(&self, position: &Json) -> usize {
+        let line = position["line"].as_u64().unwrap_or_default() as usize;
+        let Some(&start) = self.starts.get(line) else {
+            return self.text.len();
+        };
+        let mut character = position["character"].as_u64().unwrap_or_default() as usize;
+        for (index, char) in self.text[start..].char_indices() {
+            if char == '\n' || character < char.len_utf16() {
+                return start + index;
+            }
+            character -= char.len_utf16();
+        }
+        self.text.len()
+    }
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// The index of a token's type in `TOKEN_TYPES`, or `None` for punctuation.
+fn token_kind(token_type: TokenType) -> Option<u32> {
+    use TokenType::*;
+    Some(match token_type {
+        And | Class | Else | If | Nil | Or | Print | Return | Super | False | For | Fun | True
+        | This | Var | While => 0,
+        Identifier => 1,
+        StringLiteral => 2,
+        Number => 3,
+        LessEqual | GreaterEqual | Less | Greater | EqualEqual | Equal | BangEqual | Bang
+        | Minus | Plus | Slash | Star => 4,
+        Comment => 5,
+        _ => return None,
+    })
+}
+
+/// Semantic tokens in the protocol's relative encoding. Strings spanning
+/// several lines become one token per line.
+fn semantic_tokens(lines: &Lines) -> Vec<u32> {
+    let mut data = vec![];
+    let (mut previous_line, mut previous_start) = (0, 0);
+    for token in lines.text.scanner().with_comments() {
+        let Some(kind) = token_kind(token.token_type) else {
+            continue;
+        };
+        let span = token.span();
+        let mut start = span.start;
+        for piece in lines.text[span.start..span.end].split('\n') {
+            let position = lines.position(start);
+            let line = position["line"].as_u64().unwrap() as u32;
+            let character = position["character"].as_u64().unwrap() as u32;
+            let length = utf16_len(piece) as u32;
+            start += piece.len() + 1;
+            if length == 0 {
+                continue;
+            }
+            let delta_start = if line == previous_line {
+                character - previous_start
+            } else {
+                character
+            };
+            data.extend([line - previous_line, delta_start, length, kind, 0]);
+            (previous_line, previous_start) = (line, character);
+        }
+    }
+    data
+}
+
+const CLASS: u32 = 5;
+const METHOD: u32 = 6;
+const FUNCTION: u32 = 12;
+
+/// The classes, functions and methods declared in `statements`, nested as
+/// they are in the source.
+fn symbols(statements: &[Stmt], lines: &Lines) -> Vec<Json> {
+    let symbol = |function: &Function, span: Span, kind| {
+        let params: Vec<_> = function
+            .params
+            .iter()
+            .map(|param| param.name.as_str())
+            .collect();
+        json!({
+            "name": function.name.name,
+            "detail": format!("({})", params.join(", ")),
+            "kind": kind,
+            "range": lines.range(span.to(function.name.span)),
+            "selectionRange": lines.range(function.name.span),
+            "children": symbols(&function.body, lines),
+        })
+    };
+    let mut symbols = vec![];
+    for statement in statements.iter() {
+        match &statement.kind {
+            StmtKind::Class(class) => {
+                let methods: Vec<_> = class
+                    .methods
+                    .iter()
+                    .map(|method| symbol(method, method.span, METHOD))
+                    .collect();
+                let detail = match &class.superclass {
+                    Some(superclass) => format!("< {}", superclass.name),
+                    None => String::new(),
+                };
+                symbols.push(json!({
+                    "name": class.name.name,
+                    "detail": detail,
+                    "kind": CLASS,
+                    "range": lines.range(statement.span),
+                    "selectionRange": lines.range(class.name.span),
+                    "children": methods,
+                }));
+            }
+            StmtKind::Fun(function) => symbols.push(symbol(function, statement.span, FUNCTION)),
+            StmtKind::Block(body) => symbols.extend(self::symbols(body, lines)),
+            StmtKind::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                symbols.extend(self::symbols(std::slice::from_ref(then_branch), lines));
+                if let Some(else_branch) = else_branch {
+                    symbols.extend(self::symbols(std::slice::from_ref(else_branch), lines));
+                }
+            }
+            StmtKind::While { body, .. } | StmtKind::For { body, .. } => {
+                symbols.extend(self::symbols(std::slice::from_ref(body), lines));
+            }
+            _ => {}
+        }
+    }
+    symbols
+}
+
+/// The declaration of the variable used at `offset`.
+fn definition(text: &str, offset: usize) -> Option<Span> {
+    let program = text.parse_program().ok()?;
+    let reference = program
+        .references()
+        .into_iter()
+        .find(|reference| contains(reference.usage, offset))?;
+    Some(reference.declaration)
+}
+
+/// The signature and arity of the function, method or class named at
+/// `offset`, either where it is declared or where it is used.
+fn hover(text: &str, offset: usize) -> Option<(Span, String)> {
+    let program = text.parse_program().ok()?;
+    let callables = callables(&program);
+    let (span, declaration) = match program
+        .references()
+        .into_iter()
+        .find(|reference| contains(reference.usage, offset))
+    {
+        Some(reference) => (reference.usage, reference.declaration),
+        None => {
+            let (declaration, ..) = callables
+                .iter()
+                .find(|(span, ..)| contains(*span, offset))?;
+            (*declaration, *declaration)
+        }
+    };
+    let (_, signature, arity) = callables
+        .into_iter()
+        .find(|(span, ..)| *span == declaration)?;
+    let arguments = match arity {
+        0 => "no arguments".to_string(),
+        1 => "1 argument".to_string(),
+        arity => format!("{} arguments", arity),
+    };
+    Some((
+        span,
+        format!("```lox\n{}\n```\nTakes {}.", signature, arguments),
+    ))
+}
+
+/// Every function, method and class with the span of its name, its
+/// signature and how many arguments calling it takes.
+fn callables(program: &Program) -> Vec<(Span, String, usize)> {
+    fn signature(function: &Function) -> String {
+        let params: Vec<_> = function
+            .params
+            .iter()
+            .map(|param| param.name.as_str())
+            .collect();
+        format!("{}({})", function.name.name, params.join(", "))
+    }
+    fn walk(statement: &Stmt, callables: &mut Vec<(Span, String, usize)>) {
+        match &statement.kind {
+            StmtKind::Class(class) => {
+                let init = class
+                    .methods
+                    .iter()
+                    .find(|method| method.name.name == "init");
+                let superclass = match &class.superclass {
+                    Some(superclass) => format!(" < {}", superclass.name),
+                    None => String::new(),
+                };
+                callables.push((
+                    class.name.span,
+                    format!("class {}{}", class.name.name, superclass),
+                    init.map_or(0, |init| init.params.len()),
+                ));
+                for method in class.methods.iter() {
+                    let signature = format!("{}.{}", class.name.name, signature(method));
+                    callables.push((method.name.span, signature, method.params.len()));
+                    method
+                        .body
+                        .iter()
+                        .for_each(|statement| walk(statement, callables));
+                }
+            }
+            StmtKind::Fun(function) => {
+                let signature = format!("fun {}", signature(function));
+                callables.push((function.name.span, signature, function.params.len()));
+                function
+                    .body
+                    .iter()
+                    .for_each(|statement| walk(statement, callables));
+            }
+            StmtKind::Block(body) => body.iter().for_each(|statement| walk(statement, callables)),
+            StmtKind::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                walk(then_branch, callables);
+                if let Some(else_branch) = else_branch {
+                    walk(else_branch, callables);
+                }
+            }
+            StmtKind::While { body, .. } | StmtKind::For { body, .. } => walk(body, callables),
+            _ => {}
+        }
+    }
+    let mut callables = vec![];
+    program
+        .statements
+        .iter()
+        .for_each(|statement| walk(statement, &mut callables));
+    callables
+}
+
+/// Whether `offset` is in `span` or just after it, where an editor's cursor
+/// sits after typing a name.
+fn contains(span: Span, offset: usize) -> bool {
+    span.start <= offset && offset <= span.end
+}
+
+fn diagnostics(text: &str) -> Vec<Json> {
+    let lines = Lines::new(text);
+    let diagnostics = text.check();
+    let errors = diagnostics.errors.iter().map(|error| {
+        json!({
+            "range": lines.range(error.span.unwrap_or_default()),
+            "severity": 1,
+            "source": "rlox",
+            "message": error.message,
+        })
+    });
+    let warnings = diagnostics.warnings.iter().map(|warning| {
+        json!({
+            "range": lines.range(warning.span),
+            "severity": 2,
+            "code": warning.kind.to_string(),
+            "source": "rlox",
+            "message": warning.message,
+        })
+    });
+    errors.chain(warnings).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let lines = Lines::new("var a;\nvar é = \"𝄞\";\n");
+        let position = |line: u64, character: u64| json!({ "line": line, "character": character });
+        assert_eq!(lines.position(0), position(0, 0));
+        assert_eq!(lines.position(7), position(1, 0));
+        // `é` is two bytes but one UTF-16 unit, `𝄞` four bytes and two units.
+        assert_eq!(lines.position(13), position(1, 5));
+        assert_eq!(lines.position(17), position(1, 9));
+        assert_eq!(lines.position(21), position(1, 11));
+        // Offsets inside a character round down to its start.
+        assert_eq!(lines.position(12), position(1, 4));
+        assert_eq!(lines.offset(&position(1, 5)), 13);
+        assert_eq!(lines.offset(&position(1, 11)), 21);
+        assert_eq!(lines.offset(&position(0, 40)), 6);
+        assert_eq!(lines.offset(&position(9, 0)), lines.text.len());
+    }
+}
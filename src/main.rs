@@ -0,0 +1,290 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use rlox::{
+    check_source,
+    internals::{
+        optimize, print_chunk, serve_dap, serve_lsp, Checked, Coverage, Debugger, InterpretResult,
+        Profiler, MAGIC, VM,
+    },
+    source_hash, Capabilities, Chunk, Formatted, Lowered,
+};
+
+#[derive(Parser)]
+#[command(name = "rlox", about = "A bytecode virtual machine for Lox")]
+struct Cli {
+    /// Fold constants and apply peephole optimizations to compiled bytecode
+    #[arg(short = 'O', global = true)]
+    optimize: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Reformat Lox source files in place, or stdin to stdout when no files are given
+    Fmt {
+        /// Exit with an error listing files that aren't formatted instead of rewriting them
+        #[arg(long)]
+        check: bool,
+        paths: Vec<PathBuf>,
+    },
+    /// Report compile errors and lint warnings without running anything
+    Check { paths: Vec<PathBuf> },
+    /// Compile a source file and print its bytecode
+    Disassemble { path: PathBuf },
+    /// Compile a source file to a `.loxc` bytecode file
+    Compile {
+        path: PathBuf,
+        /// Where to write the bytecode; defaults to the source path with a `.loxc` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run a Lox source file or a compiled `.loxc` file
+    Run { path: PathBuf },
+    /// Run a Lox source file, then report where its time went on stderr
+    Profile {
+        path: PathBuf,
+        /// Where to write folded stacks for flame graph tools; defaults to the source path with a `.folded` extension
+        #[arg(long)]
+        folded: Option<PathBuf>,
+    },
+    /// Run Lox source files, then write the lines and branches they covered as lcov
+    Coverage {
+        paths: Vec<PathBuf>,
+        /// Where to write the lcov tracefile
+        #[arg(short, long, default_value = "lcov.info")]
+        output: PathBuf,
+    },
+    /// Step through a Lox source file, stopping at breakpoints
+    Debug { path: PathBuf },
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for debugging from an editor
+    Dap,
+    /// Serve the Language Server Protocol on stdin and stdout, for editing in an editor
+    Lsp,
+}
+
+/// Exit code for malformed input, following the sysexits convention clox uses.
+const EXIT_DATA_ERROR: u8 = 65;
+/// Exit code for a runtime error, as in clox.
+const EXIT_SOFTWARE: u8 = 70;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Fmt { check, paths } => fmt(check, paths),
+        Command::Check { paths } => check(paths),
+        Command::Disassemble { path } => disassemble(path, cli.optimize),
+        Command::Compile { path, output } => compile(path, output, cli.optimize),
+        Command::Run { path } => run(path, cli.optimize),
+        Command::Profile { path, folded } => profile(path, folded, cli.optimize),
+        Command::Coverage { paths, output } => coverage(paths, output),
+        Command::Debug { path } => debug(path),
+        Command::Dap => serve_dap(io::stdin().lock(), io::stdout()).map(|()| ExitCode::SUCCESS),
+        Command::Lsp => serve_lsp(io::stdin().lock(), io::stdout()).map(|clean| match clean {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        }),
+    };
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn fmt(check: bool, paths: Vec<PathBuf>) -> io::Result<ExitCode> {
+    let mut code = ExitCode::SUCCESS;
+    if paths.is_empty() {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        match source.as_str().format() {
+            Ok(formatted) if check && formatted != source => {
+                eprintln!("<stdin> is not formatted");
+                code = ExitCode::FAILURE;
+            }
+            Ok(formatted) if !check => print!("{}", formatted),
+            Ok(_) => {}
+            Err(error) => {
+                eprintln!("<stdin>:{}", error);
+                code = ExitCode::from(EXIT_DATA_ERROR);
+            }
+        }
+        return Ok(code);
+    }
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        match source.as_str().format() {
+            Ok(formatted) if formatted == source => {}
+            Ok(_) if check => {
+                println!("{}", path.display());
+                code = ExitCode::FAILURE;
+            }
+            Ok(formatted) => fs::write(&path, formatted)?,
+            Err(error) => {
+                eprintln!("{}:{}", path.display(), error);
+                code = ExitCode::from(EXIT_DATA_ERROR);
+            }
+        }
+    }
+    Ok(code)
+}
+
+fn check(paths: Vec<PathBuf>) -> io::Result<ExitCode> {
+    let mut code = ExitCode::SUCCESS;
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        let diagnostics = source.as_str().check();
+        for error in diagnostics.errors.iter() {
+            eprintln!("{}:{}", path.display(), error);
+        }
+        for warning in diagnostics.warnings.iter() {
+            eprintln!("{}:{}", path.display(), warning);
+        }
+        if !diagnostics.errors.is_empty() {
+            code = ExitCode::from(EXIT_DATA_ERROR);
+        }
+    }
+    Ok(code)
+}
+
+/// Lowers the source at `path`, reporting any compile errors.
+fn lower(path: &Path, source: &str, optimized: bool) -> Option<Chunk> {
+    match source.lower() {
+        Ok(chunk) if optimized => Some(optimize(&chunk)),
+        Ok(chunk) => Some(chunk),
+        Err(errors) => {
+            for error in errors.iter() {
+                eprintln!("{}:{}", path.display(), error);
+            }
+            None
+        }
+    }
+}
+
+fn disassemble(path: PathBuf, optimized: bool) -> io::Result<ExitCode> {
+    let source = fs::read_to_string(&path)?;
+    let Some(chunk) = lower(&path, &source, optimized) else {
+        return Ok(ExitCode::from(EXIT_DATA_ERROR));
+    };
+    print_chunk(&chunk, &mut io::stdout(), &path.display().to_string())?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compile(path: PathBuf, output: Option<PathBuf>, optimized: bool) -> io::Result<ExitCode> {
+    let source = fs::read_to_string(&path)?;
+    let Some(chunk) = lower(&path, &source, optimized) else {
+        return Ok(ExitCode::from(EXIT_DATA_ERROR));
+    };
+    let bytes = match chunk.serialize(Some(source_hash(&source))) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("{}:{}", path.display(), error);
+            return Ok(ExitCode::from(EXIT_DATA_ERROR));
+        }
+    };
+    let output = output.unwrap_or_else(|| path.with_extension("loxc"));
+    fs::write(output, bytes)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run(path: PathBuf, optimized: bool) -> io::Result<ExitCode> {
+    let bytes = fs::read(&path)?;
+    let chunk = if bytes.starts_with(MAGIC) {
+        // A `.loxc` next to its source shouldn't silently run old code.
+        let sibling = path.with_extension("lox");
+        let fresh = match fs::read_to_string(&sibling) {
+            Ok(source) if sibling != path => check_source(&bytes, &source),
+            _ => Ok(()),
+        };
+        match fresh.and_then(|()| Chunk::deserialize(&bytes)) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                eprintln!("{}:{}", path.display(), error);
+                return Ok(ExitCode::from(EXIT_DATA_ERROR));
+            }
+        }
+    } else {
+        let source = String::from_utf8(bytes)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        match lower(&path, &source, optimized) {
+            Some(chunk) => chunk,
+            None => return Ok(ExitCode::from(EXIT_DATA_ERROR)),
+        }
+    };
+    Ok(exit_code(vm().interpret_chunk(&chunk)))
+}
+
+fn profile(path: PathBuf, folded: Option<PathBuf>, optimized: bool) -> io::Result<ExitCode> {
+    let source = fs::read_to_string(&path)?;
+    let Some(chunk) = lower(&path, &source, optimized) else {
+        return Ok(ExitCode::from(EXIT_DATA_ERROR));
+    };
+    let mut profiler = Profiler::new();
+    let result = vm().interpret_chunk_with(&chunk, &mut profiler);
+    profiler.finish();
+    profiler.write_summary(&mut io::stderr())?;
+    let folded = folded.unwrap_or_else(|| path.with_extension("folded"));
+    profiler.write_folded(&mut fs::File::create(folded)?)?;
+    Ok(exit_code(result))
+}
+
+/// Runs each source file unoptimized, so that no line's code is folded
+/// away, and writes one lcov record per file. Exits with the last failure.
+fn coverage(paths: Vec<PathBuf>, output: PathBuf) -> io::Result<ExitCode> {
+    let mut code = ExitCode::SUCCESS;
+    let mut lcov = vec![];
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        let Some(chunk) = lower(&path, &source, false) else {
+            code = ExitCode::from(EXIT_DATA_ERROR);
+            continue;
+        };
+        let mut coverage = Coverage::new();
+        let result = vm().interpret_chunk_with(&chunk, &mut coverage);
+        if result != InterpretResult::Ok {
+            code = exit_code(result);
+        }
+        coverage.write_lcov(&chunk, &path.display().to_string(), &mut lcov)?;
+    }
+    fs::write(output, lcov)?;
+    Ok(code)
+}
+
+/// Runs a source file under the debugger, unoptimized so that every line
+/// still has its own code.
+fn debug(path: PathBuf) -> io::Result<ExitCode> {
+    let source = fs::read_to_string(&path)?;
+    let Some(chunk) = lower(&path, &source, false) else {
+        return Ok(ExitCode::from(EXIT_DATA_ERROR));
+    };
+    let mut debugger = Debugger::new(io::stdin().lock(), io::stdout());
+    Ok(exit_code(vm().interpret_chunk_with(&chunk, &mut debugger)))
+}
+
+/// A VM for running a script the user asked to run, which may use files.
+fn vm() -> VM {
+    VM::new().with_capabilities(Capabilities {
+        files: true,
+        ..Capabilities::default()
+    })
+}
+
+fn exit_code(result: InterpretResult) -> ExitCode {
+    match result {
+        InterpretResult::Ok => ExitCode::SUCCESS,
+        InterpretResult::CompileError => ExitCode::from(EXIT_DATA_ERROR),
+        InterpretResult::RuntimeError
+        | InterpretResult::OutOfFuel
+        | InterpretResult::Timeout
+        | InterpretResult::OutOfMemory
+        | InterpretResult::Interrupted => ExitCode::from(EXIT_SOFTWARE),
+    }
+}
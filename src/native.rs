@@ -0,0 +1,288 @@
+//! The standard library: native functions every VM starts with as globals.
+
+use std::{fs, io, time::Instant};
+
+use crate::{chunk::Value, object::Obj, value::ValueKind, vm::VM};
+
+/// What scripts may do beyond computing and printing. The default allows
+/// the clock and stdin but not files, which a host has to opt into;
+/// `sandboxed` allows nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `readFile` and `writeFile`.
+    pub files: bool,
+    /// `clock`.
+    pub clock: bool,
+    /// `input`.
+    pub stdin: bool,
+    /// Makes a run depend only on its source and affect only its output:
+    /// `clock()` always returns 0, and `input`, `readFile` and `writeFile`
+    /// are denied whatever `stdin` and `files` allow.
+    pub deterministic: bool,
+}
+
+impl Capabilities {
+    pub fn sandboxed() -> Self {
+        Self {
+            files: false,
+            clock: false,
+            stdin: false,
+            deterministic: true,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            files: false,
+            clock: true,
+            stdin: true,
+            deterministic: false,
+        }
+    }
+}
+
+/// Defines the standard library, or redefines it for new capabilities.
+/// Globals the host has defined under the same names are left alone.
+pub fn define_stdlib(vm: &mut VM, capabilities: Capabilities) {
+    let stdin = capabilities.stdin && !capabilities.deterministic;
+    let files = capabilities.files && !capabilities.deterministic;
+    if !capabilities.clock {
+        deny(vm, "clock", 0);
+    } else if capabilities.deterministic {
+        vm.define_library_native("clock", 0, |_| Ok(Value::number(0.0)));
+    } else {
+        let start = Instant::now();
+        vm.define_library_native("clock", 0, move |_| {
+            Ok(Value::number(start.elapsed().as_secs_f64()))
+        });
+    }
+    if stdin {
+        vm.define_library_native("input", 0, input);
+    } else {
+        deny(vm, "input", 0);
+    }
+    if files {
+        vm.define_library_native("readFile", 1, read_file);
+    } else {
+        deny(vm, "readFile", 1);
+    }
+    if files {
+        vm.define_library_native("writeFile", 2, write_file);
+    } else {
+        deny(vm, "writeFile", 2);
+    }
+    vm.define_library_native("str", 1, |args| Ok(Value::string(&args[0].to_string())));
+    vm.define_library_native("num", 1, num);
+    vm.define_library_native("len", 1, len);
+    vm.define_library_native("type", 1, |args| Ok(Value::string(args[0].type_name())));
+    vm.define_library_native("sqrt", 1, |args| math("sqrt", args, f64::sqrt));
+    vm.define_library_native("floor", 1, |args| math("floor", args, f64::floor));
+    vm.define_library_native("abs", 1, |args| math("abs", args, f64::abs));
+}
+
+/// Defines `name` as a native that always fails, so scripts get a clear
+/// error rather than an undefined variable.
+fn deny(vm: &mut VM, name: &'static str, arity: u8) {
+    vm.define_library_native(name, arity, move |_| {
+        Err(format!("{}() is not allowed in this sandbox.", name))
+    });
+}
+
+fn expected(function: &str, expected: &str, got: &Value) -> String {
+    format!(
+        "{}() expects a {} but got {}.",
+        function,
+        expected,
+        got.type_name()
+    )
+}
+
+/// Parses a string as a number, giving `nil` if it isn't one so scripts can
+/// check user input. Numbers pass through unchanged.
+fn num(args: &[Value]) -> Result<Value, String> {
+    match args[0].kind() {
+        ValueKind::Number(_) => Ok(args[0].clone()),
+        ValueKind::Obj(Obj::String(string)) => {
+            Ok(string.trim().parse().map_or(Value::nil(), Value::number))
+        }
+        _ => Err(expected("num", "string or number", &args[0])),
+    }
+}
+
+fn len(args: &[Value]) -> Result<Value, String> {
+    match args[0].as_str() {
+        Some(string) => Ok(Value::number(string.chars().count() as f64)),
+        None => Err(expected("len", "string", &args[0])),
+    }
+}
+
+/// Reads a line from stdin without its line ending, or `nil` at the end of
+/// input.
+fn input(_: &[Value]) -> Result<Value, String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(Value::nil()),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            Ok(Value::string(line.strip_suffix('\r').unwrap_or(line)))
+        }
+        Err(error) => Err(format!("Could not read input: {}.", error)),
+    }
+}
+
+fn read_file(args: &[Value]) -> Result<Value, String> {
+    let path = args[0]
+        .as_str()
+        .ok_or_else(|| expected("readFile", "string", &args[0]))?;
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Value::string(&contents)),
+        Err(error) => Err(format!("Could not read '{}': {}.", path, error)),
+    }
+}
+
+fn write_file(args: &[Value]) -> Result<Value, String> {
+    let (Some(path), Some(contents)) = (args[0].as_str(), args[1].as_str()) else {
+        return Err("writeFile() expects a path and contents as strings.".to_string());
+    };
+    match fs::write(path, contents) {
+        Ok(()) => Ok(Value::nil()),
+        Err(error) => Err(format!("Could not write '{}': {}.", path, error)),
+    }
+}
+
+fn math(function: &str, args: &[Value], op: fn(f64) -> f64) -> Result<Value, String> {
+    match args[0].as_number() {
+        Some(n) => Ok(Value::number(op(n))),
+        None => Err(expected(function, "number", &args[0])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+    use crate::Lox;
+
+    /// A path in the temp directory unique to this test process.
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rlox-{}-{}", process::id(), name))
+    }
+
+    fn error(lox: &mut Lox, source: &str) -> String {
+        let error = lox.interpret(source).unwrap_err().to_string();
+        error.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn files() {
+        let path = temp_path("files.txt");
+        let mut lox = Lox::with_output(io::sink());
+        let write = format!("writeFile(\"{}\", \"saved\");", path.display());
+        assert_eq!(
+            error(&mut lox, &write),
+            "writeFile() is not allowed in this sandbox."
+        );
+        assert!(!path.exists());
+        let capabilities = Capabilities {
+            files: true,
+            ..Capabilities::default()
+        };
+        let mut lox = Lox::with_output(io::sink()).with_capabilities(capabilities);
+        let source = format!(
+            "writeFile(\"{0}\", \"saved\"); var s = readFile(\"{0}\");",
+            path.display()
+        );
+        lox.interpret(&source).unwrap();
+        assert_eq!(lox.global("s"), Some("saved".into()));
+        fs::remove_file(&path).unwrap();
+        let read = format!("readFile(\"{}\");", path.display());
+        assert!(error(&mut lox, &read).starts_with("Could not read"));
+    }
+
+    #[test]
+    fn sandboxed_vm_cannot_reach_the_filesystem() {
+        let existing = temp_path("existing.txt");
+        fs::write(&existing, "secret").unwrap();
+        let created = temp_path("created.txt");
+        let mut lox = Lox::with_output(io::sink()).with_capabilities(Capabilities::sandboxed());
+        assert_eq!(
+            error(
+                &mut lox,
+                &format!("print readFile(\"{}\");", existing.display())
+            ),
+            "readFile() is not allowed in this sandbox."
+        );
+        assert_eq!(
+            error(
+                &mut lox,
+                &format!("writeFile(\"{}\", \"x\");", created.display())
+            ),
+            "writeFile() is not allowed in this sandbox."
+        );
+        assert!(!created.exists());
+        assert_eq!(
+            error(&mut lox, "input();"),
+            "input() is not allowed in this sandbox."
+        );
+        assert_eq!(
+            error(&mut lox, "clock();"),
+            "clock() is not allowed in this sandbox."
+        );
+        // Arity is still checked first, as for any other function.
+        assert_eq!(
+            error(&mut lox, "clock(1);"),
+            "Expected 0 arguments but got 1."
+        );
+        // Everything else is still there.
+        lox.interpret("var n = len(str(num(\"12\")));").unwrap();
+        assert_eq!(lox.global("n"), Some(2.into()));
+        fs::remove_file(&existing).unwrap();
+    }
+
+    #[test]
+    fn deterministic() {
+        let capabilities = Capabilities {
+            deterministic: true,
+            ..Capabilities::default()
+        };
+        let mut lox = Lox::with_output(io::sink()).with_capabilities(capabilities);
+        lox.interpret("var now = clock();").unwrap();
+        assert_eq!(lox.global("now"), Some(0.into()));
+        let capabilities = Capabilities {
+            files: true,
+            ..capabilities
+        };
+        let mut lox = Lox::with_output(io::sink()).with_capabilities(capabilities);
+        assert_eq!(
+            error(&mut lox, "input();"),
+            "input() is not allowed in this sandbox."
+        );
+        assert_eq!(
+            error(&mut lox, "readFile(\"x\");"),
+            "readFile() is not allowed in this sandbox."
+        );
+        let path = temp_path("deterministic.txt");
+        let write = format!("writeFile(\"{}\", \"saved\");", path.display());
+        assert_eq!(
+            error(&mut lox, &write),
+            "writeFile() is not allowed in this sandbox."
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn redefining_keeps_host_natives() {
+        let mut lox = Lox::with_output(io::sink());
+        lox.define_function("clock", 0, |_| Ok(Value::number(42.0)));
+        let mut lox = lox.with_capabilities(Capabilities::sandboxed());
+        lox.interpret("var now = clock();").unwrap();
+        assert_eq!(lox.global("now"), Some(42.into()));
+        assert_eq!(
+            error(&mut lox, "input();"),
+            "input() is not allowed in this sandbox."
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    any::Any,
+    cell::{OnceCell, RefCell},
+    fmt,
+    mem::size_of,
+    ops::Deref,
+    rc::Rc,
+};
+
+use crate::{
+    chunk::{Chunk, Value},
+    heap,
+    table::Table,
+};
+
+/// Heap-allocated Lox values, shared through `Value::obj`.
+pub enum Obj {
+    String(LoxString),
+    Function(Function),
+    Closure(Closure),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(NativeFn),
+    Opaque(Opaque),
+}
+
+impl Obj {
+    /// Moves the object to the heap, counting it towards `heap::live`.
+    /// Every object is created this way.
+    pub fn alloc(self) -> Rc<Obj> {
+        heap::allocated(self.size());
+        Rc::new(self)
+    }
+
+    /// Bytes the object accounts for: its allocation, including the `Rc`
+    /// counts, and what it owns outside tables, which count themselves.
+    fn size(&self) -> usize {
+        Obj::footprint(self.owned())
+    }
+
+    /// What an object owning `owned` bytes accounts for; see `size`. The VM
+    /// checks this against its heap limit before allocating.
+    pub(crate) const fn footprint(owned: usize) -> usize {
+        2 * size_of::<usize>() + size_of::<Obj>() + owned
+    }
+
+    fn owned(&self) -> usize {
+        match self {
+            Obj::String(string) => string.len(),
+            Obj::Function(function) => {
+                function.chunk.code.len() + function.chunk.constants.len() * size_of::<Value>()
+            }
+            Obj::Closure(closure) => Closure::owned(closure.upvalues.len()),
+            _ => 0,
+        }
+    }
+}
+
+impl Drop for Obj {
+    fn drop(&mut self) {
+        heap::freed(self.size());
+    }
+}
+
+/// A string, with its hash computed at most once. Strings from `intern` are
+/// the only live ones with their contents; those from `uninterned` skip the
+/// pool, so only the contents tell them apart.
+pub struct LoxString {
+    chars: Box<str>,
+    hash: OnceCell<u32>,
+    interned: bool,
+}
+
+impl LoxString {
+    pub fn as_str(&self) -> &str {
+        &self.chars
+    }
+
+    pub fn hash(&self) -> u32 {
+        *self.hash.get_or_init(|| hash_string(&self.chars))
+    }
+
+    pub fn is_interned(&self) -> bool {
+        self.interned
+    }
+}
+
+impl Deref for LoxString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+/// FxHash over eight bytes at a time: every new string is hashed when it is
+/// interned, so this needs to be fast on long strings.
+pub fn hash_string(chars: &str) -> u32 {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+    let step = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    let mut words = chars.as_bytes().chunks_exact(8);
+    let mut hash = (&mut words).fold(chars.len() as u64, |hash, word| {
+        step(hash, u64::from_le_bytes(word.try_into().unwrap()))
+    });
+    let mut tail = [0; 8];
+    tail[..words.remainder().len()].copy_from_slice(words.remainder());
+    hash = step(hash, u64::from_le_bytes(tail));
+    (hash ^ (hash >> 32)) as u32
+}
+
+thread_local! {
+    /// Every live string, as keys with `nil` values.
+    static STRINGS: RefCell<Table> = RefCell::new(Table::new());
+}
+
+/// The one `Obj::String` with these contents, creating it if needed.
+pub fn intern(chars: &str) -> Rc<Obj> {
+    let hash = hash_string(chars);
+    STRINGS.with(|strings| {
+        let mut strings = strings.borrow_mut();
+        if let Some(string) = strings.find_string(chars, hash) {
+            return string.clone();
+        }
+        if strings.is_full() {
+            // Strings only the pool still refers to are garbage.
+            strings.retain(|string, _| Rc::strong_count(string) > 1);
+        }
+        let string = Obj::String(LoxString {
+            chars: chars.into(),
+            hash: OnceCell::from(hash),
+            interned: true,
+        })
+        .alloc();
+        strings.set(string.clone(), Value::nil());
+        string
+    })
+}
+
+/// A string that skips the intern pool. Hashing and looking up every
+/// intermediate result of a loop of concatenations costs more than the
+/// concatenation itself, and most are dropped straight away. Tables can't
+/// use these as keys.
+pub fn uninterned(chars: String) -> Rc<Obj> {
+    Obj::String(LoxString {
+        chars: chars.into_boxed_str(),
+        hash: OnceCell::new(),
+        interned: false,
+    })
+    .alloc()
+}
+
+/// A compiled function. Only the compiler and `serialize::deserialize`, which
+/// verifies what it reads, build these: the VM decodes their code without
+/// bounds checks, so code from anywhere else could read out of bounds.
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub(crate) name: Option<Box<str>>,
+    pub(crate) arity: u8,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Chunk,
+}
+
+impl Function {
+    pub(crate) fn new(name: Option<&str>) -> Self {
+        Self {
+            name: name.map(Into::into),
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new_chunk(),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// A variable captured by a closure: still on the stack while its scope is
+/// live, then moved into the upvalue itself.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    /// Always an `Obj::Function`.
+    pub(crate) function: Rc<Obj>,
+    pub(crate) upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    /// Bytes a closure with `upvalues` upvalues owns outside itself.
+    pub(crate) const fn owned(upvalues: usize) -> usize {
+        upvalues * size_of::<Rc<RefCell<Upvalue>>>()
+    }
+
+    pub fn function(&self) -> &Function {
+        match self.function.as_ref() {
+            Obj::Function(function) => function,
+            _ => unreachable!("closure over a non-function"),
+        }
+    }
+}
+
+pub type Fields = RefCell<Table>;
+
+pub struct Class {
+    pub name: Box<str>,
+    /// Method closures by name.
+    pub methods: Fields,
+}
+
+pub struct Instance {
+    /// Always an `Obj::Class`.
+    pub class: Rc<Obj>,
+    pub fields: Fields,
+}
+
+pub struct BoundMethod {
+    pub receiver: Value,
+    /// Always an `Obj::Closure`.
+    pub method: Rc<Obj>,
+}
+
+/// A function implemented in Rust. It gets its arguments as a slice and
+/// returns the call's result, or a message to raise as a runtime error.
+pub type NativeFunction = dyn Fn(&[Value]) -> Result<Value, String>;
+
+pub struct NativeFn {
+    pub name: Box<str>,
+    pub arity: u8,
+    pub function: Box<NativeFunction>,
+}
+
+/// A Rust value the host hands to scripts. Lox code can only pass it
+/// around; native functions get it back with `Value::downcast_ref`.
+pub struct Opaque {
+    pub type_name: &'static str,
+    pub value: Box<dyn Any>,
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obj::String(s) => write!(f, "{}", s.as_str()),
+            Obj::Function(function) => write!(f, "{}", function),
+            Obj::Closure(closure) => write!(f, "{}", closure.function()),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(instance) => write!(f, "{} instance", instance.class),
+            Obj::BoundMethod(bound) => write!(f, "{}", bound.method),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::Opaque(opaque) => write!(f, "<opaque {}>", opaque.type_name),
+        }
+    }
+}
+
+impl fmt::Debug for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_pool_drops_unused_strings() {
+        let kept = intern("kept");
+        for n in 0..10_000 {
+            intern(&n.to_string());
+        }
+        let (len, has_kept) = STRINGS.with(|strings| {
+            let strings = strings.borrow();
+            let has_kept = strings
+                .find_string("kept", hash_string("kept"))
+                .is_some_and(|string| Rc::ptr_eq(string, &kept));
+            (strings.len(), has_kept)
+        });
+        assert!(len < 1_000, "{} strings still interned", len);
+        assert!(has_kept);
+    }
+}
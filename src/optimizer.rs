@@ -0,0 +1,605 @@
+use crate::{
+    chunk::{Chunk, OpCode, Value},
+    object::{Function, Obj},
+    value::ValueKind,
+};
+
+#[derive(Clone)]
+enum Operand {
+    None,
+    Byte(u8),
+    /// Index into the constant pool.
+    Constant(usize),
+    /// Index of the target instruction.
+    Jump(usize),
+    Closure(usize, Vec<u8>),
+}
+
+#[derive(Clone)]
+struct Instruction {
+    op_code: OpCode,
+    operand: Operand,
+    line: usize,
+    /// Offset in the original code of the first instruction this one came
+    /// from, for moving debug info to the new code.
+    origin: usize,
+}
+
+impl Instruction {
+    fn new(op_code: OpCode, operand: Operand, line: usize) -> Self {
+        Self {
+            op_code,
+            operand,
+            line,
+            origin: 0,
+        }
+    }
+}
+
+/// Returns an equivalent chunk with constant expressions folded, no-op jumps
+/// dropped, runs of `Pop` merged and the constant pool deduplicated. Nested
+/// functions are optimized too. Chunks that can't be decoded are returned
+/// unchanged.
+pub fn optimize(chunk: &Chunk) -> Chunk {
+    let mut constants: Vec<Value> = chunk.constants.iter().map(optimize_constant).collect();
+    let mut instructions = match decode(chunk) {
+        Some(instructions) => instructions,
+        None => return chunk.clone(),
+    };
+    loop {
+        let mut changed = false;
+        for pass in [fold_constants, remove_jumps_to_next, merge_pops] {
+            let (rewritten, pass_changed) = pass(&instructions, &mut constants);
+            instructions = rewritten;
+            changed |= pass_changed;
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut pool = compact_constants(&mut instructions, constants);
+    pool.locals = chunk.locals.clone();
+    pool.upvalue_names = chunk.upvalue_names.clone();
+    encode(&instructions, pool).unwrap_or_else(|| chunk.clone())
+}
+
+fn optimize_constant(value: &Value) -> Value {
+    match value.as_obj() {
+        Some(Obj::Function(function)) => Value::function(Function {
+            chunk: optimize(&function.chunk),
+            ..function.clone()
+        }),
+        _ => value.clone(),
+    }
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut instructions = vec![];
+    let mut indices = vec![None; chunk.code.len() + 1];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op_code = OpCode::try_from(chunk.code[offset]).ok()?;
+        let len = chunk.instruction_len(offset)?;
+        let operands = chunk.code.get(offset + 1..offset + len)?;
+        let operand = match op_code {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper => Operand::Constant(operands[0] as usize),
+            OpCode::ConstantLong => Operand::Constant(chunk.long_constant_index(offset)?),
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = ((operands[0] as usize) << 8) + operands[1] as usize;
+                // Targets are resolved to instruction indices below.
+                Operand::Jump(if op_code == OpCode::Loop {
+                    (offset + len).checked_sub(jump)?
+                } else {
+                    offset + len + jump
+                })
+            }
+            OpCode::Closure => Operand::Closure(operands[0] as usize, operands[1..].to_vec()),
+            _ if len == 2 => Operand::Byte(operands[0]),
+            _ => Operand::None,
+        };
+        indices[offset] = Some(instructions.len());
+        instructions.push(Instruction {
+            origin: offset,
+            ..Instruction::new(op_code, operand, chunk.get_line(offset).unwrap_or_default())
+        });
+        offset += len;
+    }
+    indices[offset] = Some(instructions.len());
+    for instruction in instructions.iter_mut() {
+        if let Operand::Jump(target) = instruction.operand {
+            instruction.operand = Operand::Jump((*indices.get(target)?)?);
+        }
+    }
+    Some(instructions)
+}
+
+/// Writes `instructions` into `chunk`, whose debug info still refers to the
+/// original code, and moves that debug info to the new offsets.
+fn encode(instructions: &[Instruction], mut chunk: Chunk) -> Option<Chunk> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut jumps = vec![];
+    for instruction in instructions.iter() {
+        let line = instruction.line;
+        offsets.push(chunk.code.len());
+        if let (OpCode::Constant | OpCode::ConstantLong, Operand::Constant(index)) =
+            (instruction.op_code, &instruction.operand)
+        {
+            chunk.write_constant_index(*index, line).ok()?;
+            continue;
+        }
+        chunk.write_op_code(instruction.op_code, line);
+        match &instruction.operand {
+            Operand::None => {}
+            Operand::Byte(byte) => chunk.write_operand(*byte, line),
+            Operand::Constant(index) => chunk.write_operand(u8::try_from(*index).ok()?, line),
+            Operand::Jump(target) => {
+                jumps.push((chunk.code.len(), *target));
+                chunk.write_operand(0xff, line);
+                chunk.write_operand(0xff, line);
+            }
+            Operand::Closure(index, upvalues) => {
+                chunk.write_operand(u8::try_from(*index).ok()?, line);
+                for byte in upvalues.iter() {
+                    chunk.write_operand(*byte, line);
+                }
+            }
+        }
+    }
+    offsets.push(chunk.code.len());
+    for (operand, target) in jumps {
+        let next = operand + 2;
+        let target = offsets[target];
+        let jump = if chunk.code[operand - 1] == OpCode::Loop as u8 {
+            next.checked_sub(target)?
+        } else {
+            target.checked_sub(next)?
+        };
+        let jump = u16::try_from(jump).ok()?;
+        chunk.code[operand] = (jump >> 8) as u8;
+        chunk.code[operand + 1] = jump as u8;
+    }
+    // Passes keep instructions in order, so origins only grow. An old
+    // offset moves to the first instruction that came from at or after it.
+    let moved = |offset: usize| {
+        offsets[instructions.partition_point(|instruction| instruction.origin < offset)]
+    };
+    for local in chunk.locals.iter_mut() {
+        local.live = moved(local.live.start)..moved(local.live.end);
+    }
+    Some(chunk)
+}
+
+/// Marks instructions some jump lands on; patterns spanning one can't be
+/// rewritten because control may enter partway through.
+fn jump_targets(instructions: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; instructions.len() + 1];
+    for instruction in instructions.iter() {
+        if let Operand::Jump(target) = instruction.operand {
+            targets[target] = true;
+        }
+    }
+    targets
+}
+
+/// Builds a new instruction list while tracking where each old instruction
+/// went, then retargets jumps accordingly.
+struct Rewriter<'a> {
+    old: &'a [Instruction],
+    instructions: Vec<Instruction>,
+    indices: Vec<usize>,
+    changed: bool,
+}
+
+impl<'a> Rewriter<'a> {
+    fn new(old: &'a [Instruction]) -> Self {
+        Self {
+            old,
+            instructions: Vec::with_capacity(old.len()),
+            indices: vec![0; old.len() + 1],
+            changed: false,
+        }
+    }
+
+    fn keep(&mut self, index: usize, instruction: &Instruction) {
+        self.indices[index] = self.instructions.len();
+        self.instructions.push(instruction.clone());
+    }
+
+    fn replace(&mut self, indices: std::ops::Range<usize>, replacement: Option<Instruction>) {
+        let origin = self.old[indices.start].origin;
+        for index in indices {
+            self.indices[index] = self.instructions.len();
+        }
+        self.instructions
+            .extend(replacement.map(|instruction| Instruction {
+                origin,
+                ..instruction
+            }));
+        self.changed = true;
+    }
+
+    fn finish(mut self) -> (Vec<Instruction>, bool) {
+        self.indices[self.old.len()] = self.instructions.len();
+        for instruction in self.instructions.iter_mut() {
+            if let Operand::Jump(target) = instruction.operand {
+                instruction.operand = Operand::Jump(self.indices[target]);
+            }
+        }
+        (self.instructions, self.changed)
+    }
+}
+
+fn literal(instruction: &Instruction, constants: &[Value]) -> Option<Value> {
+    match (instruction.op_code, &instruction.operand) {
+        (OpCode::Nil, _) => Some(Value::nil()),
+        (OpCode::True, _) => Some(Value::bool(true)),
+        (OpCode::False, _) => Some(Value::bool(false)),
+        (OpCode::Constant | OpCode::ConstantLong, Operand::Constant(index)) => {
+            let value = &constants[*index];
+            match value.kind() {
+                ValueKind::Number(_) => Some(value.clone()),
+                _ if value.as_str().is_some() => Some(value.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn load(value: Value, line: usize, constants: &mut Vec<Value>) -> Instruction {
+    match value.kind() {
+        ValueKind::Nil => Instruction::new(OpCode::Nil, Operand::None, line),
+        ValueKind::Bool(true) => Instruction::new(OpCode::True, Operand::None, line),
+        ValueKind::Bool(false) => Instruction::new(OpCode::False, Operand::None, line),
+        _ => {
+            constants.push(value);
+            Instruction::new(
+                OpCode::Constant,
+                Operand::Constant(constants.len() - 1),
+                line,
+            )
+        }
+    }
+}
+
+/// Evaluates an operator on literal operands, or `None` when the result
+/// would be a runtime error that must be left for the VM to report.
+fn fold(op_code: OpCode, operands: &[Value]) -> Option<Value> {
+    use ValueKind::Number;
+    let kinds: Vec<_> = operands.iter().map(Value::kind).collect();
+    Some(match (op_code, kinds.as_slice()) {
+        (OpCode::Negate, [Number(a)]) => Value::number(-a),
+        (OpCode::Not, [_]) => Value::bool(operands[0].is_falsey()),
+        (OpCode::Equal, [_, _]) => Value::bool(operands[0] == operands[1]),
+        (OpCode::Add, [Number(a), Number(b)]) => Value::number(a + b),
+        (OpCode::Subtract, [Number(a), Number(b)]) => Value::number(a - b),
+        (OpCode::Multiply, [Number(a), Number(b)]) => Value::number(a * b),
+        (OpCode::Divide, [Number(a), Number(b)]) => Value::number(a / b),
+        (OpCode::Greater, [Number(a), Number(b)]) => Value::bool(a > b),
+        (OpCode::Less, [Number(a), Number(b)]) => Value::bool(a < b),
+        (OpCode::Add, [_, _]) => {
+            let (a, b) = (operands[0].as_str()?, operands[1].as_str()?);
+            Value::string(&format!("{}{}", a, b))
+        }
+        _ => return None,
+    })
+}
+
+fn fold_constants(
+    instructions: &[Instruction],
+    constants: &mut Vec<Value>,
+) -> (Vec<Instruction>, bool) {
+    let targets = jump_targets(instructions);
+    let mut rewriter = Rewriter::new(instructions);
+    let mut i = 0;
+    'outer: while i < instructions.len() {
+        for arity in [2, 1] {
+            let end = i + arity;
+            if end >= instructions.len() || targets[i + 1..=end].contains(&true) {
+                continue;
+            }
+            let operands: Option<Vec<Value>> = instructions[i..end]
+                .iter()
+                .map(|instruction| literal(instruction, constants))
+                .collect();
+            let folded = operands.and_then(|operands| fold(instructions[end].op_code, &operands));
+            if let Some(value) = folded {
+                let instruction = load(value, instructions[end].line, constants);
+                rewriter.replace(i..end + 1, Some(instruction));
+                i = end + 1;
+                continue 'outer;
+            }
+        }
+        rewriter.keep(i, &instructions[i]);
+        i += 1;
+    }
+    rewriter.finish()
+}
+
+fn remove_jumps_to_next(
+    instructions: &[Instruction],
+    _: &mut Vec<Value>,
+) -> (Vec<Instruction>, bool) {
+    let mut rewriter = Rewriter::new(instructions);
+    for (i, instruction) in instructions.iter().enumerate() {
+        match (instruction.op_code, &instruction.operand) {
+            // `JumpIfFalse` leaves the condition on the stack either way, so
+            // jumping to the next instruction does nothing at all.
+            (OpCode::Jump | OpCode::JumpIfFalse, Operand::Jump(target)) if *target == i + 1 => {
+                rewriter.replace(i..i + 1, None)
+            }
+            _ => rewriter.keep(i, instruction),
+        }
+    }
+    rewriter.finish()
+}
+
+fn pop_count(instruction: &Instruction) -> Option<usize> {
+    match (instruction.op_code, &instruction.operand) {
+        (OpCode::Pop, _) => Some(1),
+        (OpCode::PopN, Operand::Byte(count)) => Some(*count as usize),
+        _ => None,
+    }
+}
+
+fn merge_pops(instructions: &[Instruction], _: &mut Vec<Value>) -> (Vec<Instruction>, bool) {
+    let targets = jump_targets(instructions);
+    let mut rewriter = Rewriter::new(instructions);
+    let mut i = 0;
+    while i < instructions.len() {
+        let mut count = match pop_count(&instructions[i]) {
+            Some(count) => count,
+            None => {
+                rewriter.keep(i, &instructions[i]);
+                i += 1;
+                continue;
+            }
+        };
+        let mut end = i + 1;
+        while end < instructions.len() && !targets[end] {
+            match pop_count(&instructions[end]) {
+                Some(more) if count + more <= u8::MAX as usize => count += more,
+                _ => break,
+            }
+            end += 1;
+        }
+        if end == i + 1 {
+            rewriter.keep(i, &instructions[i]);
+        } else {
+            let line = instructions[i].line;
+            let pop_n = Instruction::new(OpCode::PopN, Operand::Byte(count as u8), line);
+            rewriter.replace(i..end, Some(pop_n));
+        }
+        i = end;
+    }
+    rewriter.finish()
+}
+
+/// Drops constants nothing refers to any more and merges duplicates,
+/// renumbering operands to match. Returns an empty chunk holding the pool.
+fn compact_constants(instructions: &mut [Instruction], constants: Vec<Value>) -> Chunk {
+    let mut used = vec![false; constants.len()];
+    for instruction in instructions.iter() {
+        if let Operand::Constant(index) | Operand::Closure(index, _) = instruction.operand {
+            used[index] = true;
+        }
+    }
+    let mut chunk = Chunk::new_chunk();
+    let mut indices = vec![0; constants.len()];
+    for (index, constant) in constants.into_iter().enumerate() {
+        if used[index] {
+            // The pool only shrinks, so it can't overflow.
+            indices[index] = chunk.put_constant(constant).unwrap();
+        }
+    }
+    for instruction in instructions.iter_mut() {
+        if let Operand::Constant(index) | Operand::Closure(index, _) = &mut instruction.operand {
+            *index = indices[*index];
+        }
+    }
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunk_printer::print_chunk, lowering::Lowered};
+
+    fn disassemble(chunk: &Chunk) -> String {
+        let mut output = vec![];
+        print_chunk(chunk, &mut output, "test").unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn optimized(source: &str) -> String {
+        disassemble(&optimize(&source.lower().unwrap()))
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        assert_eq!(
+            optimized("print 1 - 2 * 3;"),
+            "== test ==\n\
+             0000    1 Constant            0 '-5.0'\n\
+             0002    | Print\n\
+             0003    | Nil\n\
+             0004    | Return\n"
+        );
+    }
+
+    #[test]
+    fn folds_comparisons_and_not() {
+        assert_eq!(
+            optimized("print 1 <= 2; print \"a\" == \"b\"; print !nil;"),
+            "== test ==\n\
+             0000    1 True\n\
+             0001    | Print\n\
+             0002    | False\n\
+             0003    | Print\n\
+             0004    | True\n\
+             0005    | Print\n\
+             0006    | Nil\n\
+             0007    | Return\n"
+        );
+    }
+
+    #[test]
+    fn folds_negated_constants() {
+        assert_eq!(
+            optimized("print -(-3);"),
+            "== test ==\n\
+             0000    1 Constant            0 '3.0'\n\
+             0002    | Print\n\
+             0003    | Nil\n\
+             0004    | Return\n"
+        );
+    }
+
+    #[test]
+    fn leaves_runtime_errors() {
+        assert_eq!(
+            optimized("print -\"a\" + 1;"),
+            "== test ==\n\
+             0000    1 Constant            0 'a'\n\
+             0002    | Negate\n\
+             0003    | Constant            1 '1.0'\n\
+             0005    | Add\n\
+             0006    | Print\n\
+             0007    | Nil\n\
+             0008    | Return\n"
+        );
+    }
+
+    #[test]
+    fn does_not_fold_across_jump_targets() {
+        assert_eq!(
+            optimized("print (x and 1) - 2;"),
+            "== test ==\n\
+             0000    1 GetGlobal           0 'x'\n\
+             0002    | JumpIfFalse         2 -> 8\n\
+             0005    | Pop\n\
+             0006    | Constant            1 '1.0'\n\
+             0008    | Constant            2 '2.0'\n\
+             0010    | Subtract\n\
+             0011    | Print\n\
+             0012    | Nil\n\
+             0013    | Return\n"
+        );
+    }
+
+    #[test]
+    fn removes_jumps_to_next() {
+        let mut chunk = Chunk::new_chunk();
+        chunk.write_op_code(OpCode::True, 1);
+        chunk.write_op_code(OpCode::JumpIfFalse, 1);
+        chunk.write_operand(0, 1);
+        chunk.write_operand(0, 1);
+        chunk.write_op_code(OpCode::Jump, 1);
+        chunk.write_operand(0, 1);
+        chunk.write_operand(0, 1);
+        chunk.write_op_code(OpCode::Return, 1);
+        assert_eq!(
+            disassemble(&optimize(&chunk)),
+            "== test ==\n\
+             0000    1 True\n\
+             0001    | Return\n"
+        );
+    }
+
+    #[test]
+    fn merges_pops() {
+        assert_eq!(
+            optimized("{ var a; var b; var c; }"),
+            "== test ==\n\
+             0000    1 Nil\n\
+             0001    | Nil\n\
+             0002    | Nil\n\
+             0003    | PopN                3\n\
+             0005    | Nil\n\
+             0006    | Return\n"
+        );
+    }
+
+    #[test]
+    fn retargets_jumps() {
+        assert_eq!(
+            optimized("while (x) { var a = 1 + 1; var b; }"),
+            "== test ==\n\
+             0000    1 GetGlobal           0 'x'\n\
+             0002    | JumpIfFalse         2 -> 14\n\
+             0005    | Pop\n\
+             0006    | Constant            1 '2.0'\n\
+             0008    | Nil\n\
+             0009    | PopN                2\n\
+             0011    | Loop               11 -> 0\n\
+             0014    | Pop\n\
+             0015    | Nil\n\
+             0016    | Return\n"
+        );
+    }
+
+    #[test]
+    fn merges_duplicate_constants() {
+        let chunk = optimize(
+            &"print 1; print 1; print \"x\"; print \"x\"; print 0 * -1; print 0;"
+                .lower()
+                .unwrap(),
+        );
+        let constants: Vec<String> = chunk.constants.iter().map(|c| format!("{:?}", c)).collect();
+        assert_eq!(constants, vec!["1.0", "x", "0.0", "-0.0"]);
+    }
+
+    #[test]
+    fn optimizes_nested_functions() {
+        assert_eq!(
+            optimized("fun add() { return 1 + 2; }"),
+            "== test ==\n\
+             0000    1 Closure             1 <fn add>\n\
+             0002    | DefineGlobal        0 'add'\n\
+             0004    | Nil\n\
+             0005    | Return\n\
+             == <fn add> ==\n\
+             0000    1 Constant            0 '3.0'\n\
+             0002    | Return\n\
+             0003    | Nil\n\
+             0004    | Return\n"
+        );
+    }
+
+    #[test]
+    fn keeps_debug_info() {
+        let chunk = "fun f(n) { var a = 1 + 2; { var b; var c; } fun g() { return n; } return a; }"
+            .lower()
+            .unwrap();
+        let optimized = optimize(&chunk);
+        let function = |chunk: &Chunk, index: usize| match chunk.constants[index].as_obj() {
+            Some(Obj::Function(function)) => function.clone(),
+            _ => panic!("expected function constant"),
+        };
+        let f = function(&optimized, 1);
+        // Folding `1 + 2` and merging the pops moves every later offset.
+        let locals: Vec<_> = f.chunk.locals[..4]
+            .iter()
+            .map(|local| (&*local.name, local.slot, local.live.clone()))
+            .collect();
+        assert_eq!(
+            locals,
+            vec![
+                ("n", 1, 0..15),
+                ("a", 2, 2..15),
+                ("b", 3, 3..6),
+                ("c", 4, 4..6)
+            ]
+        );
+        let g = function(&f.chunk, 0);
+        assert_eq!(g.chunk.upvalue_names, vec!["n".into()]);
+    }
+}
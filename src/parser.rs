@@ -0,0 +1,750 @@
+use crate::{
+    ast::{
+        self, BinaryOperator, Expr, ExprKind, Function, Literal, LogicalOperator, Program, Stmt,
+        StmtKind, UnaryOperator,
+    },
+    compiler::CompilerError,
+    scanner::{scan_error, Scannable, Scanner},
+    token::{Span, Token},
+    token_type::TokenType::{self, *},
+};
+
+const MAX_ARGUMENTS: usize = 255;
+/// How deeply blocks, functions and control flow bodies may nest, and
+/// separately how deeply groupings, unary operators, assignments and call
+/// arguments may nest within a statement. Together they keep the parser and
+/// the passes that walk its tree within a 2 MB thread stack even in a debug
+/// build. Chains of binary operators, calls and property accesses don't
+/// count: they are parsed in a loop, and walked along their left side in
+/// one.
+const MAX_STATEMENT_NESTING: usize = 64;
+const MAX_EXPRESSION_NESTING: usize = 64;
+
+/// What `nested` counts a level of.
+#[derive(Clone, Copy)]
+enum Nesting {
+    Statement,
+    Expression,
+}
+
+impl Nesting {
+    fn limit(self) -> usize {
+        match self {
+            Nesting::Statement => MAX_STATEMENT_NESTING,
+            Nesting::Expression => MAX_EXPRESSION_NESTING,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Nesting::Statement => "Statement nests too deeply.",
+            Nesting::Expression => "Expression nests too deeply.",
+        }
+    }
+}
+
+type R<T> = Result<T, CompilerError>;
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    previous: Token<'a>,
+    current: Token<'a>,
+    errors: Vec<CompilerError>,
+    /// Whether the scanner reported an error during the current
+    /// declaration, whose parse error would only be a consequence of it.
+    scan_failed: bool,
+    /// How deeply nested the statement being parsed is.
+    statement_depth: usize,
+    /// How deeply nested the expression being parsed is within its
+    /// statement.
+    expression_depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, scanner: Scanner<'a>) -> Self {
+        let end = Token {
+            line: 1,
+            offset: source.len(),
+            content: "",
+            token_type: EndOfFile,
+        };
+        Self {
+            scanner,
+            previous: end,
+            current: end,
+            errors: vec![],
+            scan_failed: false,
+            statement_depth: 0,
+            expression_depth: 0,
+        }
+    }
+
+    fn parse(mut self) -> Result<Program, Vec<CompilerError>> {
+        self.advance();
+        let mut statements = vec![];
+        while !self.check(EndOfFile) {
+            self.scan_failed = false;
+            self.statement_depth = 0;
+            self.expression_depth = 0;
+            match self.declaration() {
+                Ok(statement) => statements.push(statement),
+                Err(error) => {
+                    if !self.scan_failed {
+                        self.errors.push(error);
+                    }
+                    self.synchronize();
+                }
+            }
+        }
+        if self.errors.is_empty() {
+            Ok(Program { statements })
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn declaration(&mut self) -> R<Stmt> {
+        if self.matches(Class) {
+            self.class_declaration()
+        } else if self.matches(Fun) {
+            let start = self.previous.span();
+            let function = self.function("function")?;
+            Ok(self.statement_from(start, StmtKind::Fun(function)))
+        } else if self.matches(Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn class_declaration(&mut self) -> R<Stmt> {
+        let start = self.previous.span();
+        let name = self.identifier("Expect class name.")?;
+        let superclass = if self.matches(Less) {
+            Some(self.identifier("Expect superclass name.")?)
+        } else {
+            None
+        };
+        self.consume(LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = vec![];
+        while !self.check(RightBrace) && !self.check(EndOfFile) {
+            methods.push(self.function("method")?);
+        }
+        self.consume(RightBrace, "Expect '}' after class body.")?;
+        Ok(self.statement_from(
+            start,
+            StmtKind::Class(ast::Class {
+                name,
+                superclass,
+                methods,
+            }),
+        ))
+    }
+
+    fn function(&mut self, kind: &str) -> R<Function> {
+        self.nested(Nesting::Statement, |parser| parser.function_body(kind))
+    }
+
+    fn function_body(&mut self, kind: &str) -> R<Function> {
+        let name = self.identifier(&format!("Expect {} name.", kind))?;
+        self.consume(LeftParen, &format!("Expect '(' after {} name.", kind))?;
+        let mut params = vec![];
+        if !self.check(RightParen) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                params.push(self.identifier("Expect parameter name.")?);
+                if !self.matches(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after parameters.")?;
+        self.consume(LeftBrace, &format!("Expect '{{' before {} body.", kind))?;
+        let body = self.block()?;
+        let span = name.span.to(self.previous.span());
+        Ok(Function {
+            name,
+            params,
+            body,
+            span,
+        })
+    }
+
+    fn var_declaration(&mut self) -> R<Stmt> {
+        let start = self.previous.span();
+        let name = self.identifier("Expect variable name.")?;
+        let initializer = if self.matches(Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(Semicolon, "Expect ';' after variable declaration.")?;
+        Ok(self.statement_from(start, StmtKind::Var { name, initializer }))
+    }
+
+    fn statement(&mut self) -> R<Stmt> {
+        self.nested(Nesting::Statement, Self::nested_statement)
+    }
+
+    fn nested_statement(&mut self) -> R<Stmt> {
+        let start = self.current.span();
+        let kind = if self.matches(Print) {
+            let value = self.expression()?;
+            self.consume(Semicolon, "Expect ';' after value.")?;
+            StmtKind::Print(value)
+        } else if self.matches(Return) {
+            let value = if self.check(Semicolon) {
+                None
+            } else {
+                Some(self.expression()?)
+            };
+            self.consume(Semicolon, "Expect ';' after return value.")?;
+            StmtKind::Return(value)
+        } else if self.matches(If) {
+            self.consume(LeftParen, "Expect '(' after 'if'.")?;
+            let condition = self.expression()?;
+            self.consume(RightParen, "Expect ')' after condition.")?;
+            let then_branch = Box::new(self.statement()?);
+            let else_branch = if self.matches(Else) {
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            }
+        } else if self.matches(While) {
+            self.consume(LeftParen, "Expect '(' after 'while'.")?;
+            let condition = self.expression()?;
+            self.consume(RightParen, "Expect ')' after condition.")?;
+            let body = Box::new(self.statement()?);
+            StmtKind::While { condition, body }
+        } else if self.matches(For) {
+            self.for_statement()?
+        } else if self.matches(LeftBrace) {
+            StmtKind::Block(self.block()?)
+        } else {
+            let expression = self.expression()?;
+            self.consume(Semicolon, "Expect ';' after expression.")?;
+            StmtKind::Expression(expression)
+        };
+        Ok(self.statement_from(start, kind))
+    }
+
+    fn for_statement(&mut self) -> R<StmtKind> {
+        self.consume(LeftParen, "Expect '(' after 'for'.")?;
+        let initializer = if self.matches(Semicolon) {
+            None
+        } else if self.matches(Var) {
+            Some(Box::new(self.var_declaration()?))
+        } else {
+            let start = self.current.span();
+            let expression = self.expression()?;
+            self.consume(Semicolon, "Expect ';' after expression.")?;
+            Some(Box::new(
+                self.statement_from(start, StmtKind::Expression(expression)),
+            ))
+        };
+        let condition = if self.check(Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(Semicolon, "Expect ';' after loop condition.")?;
+        let increment = if self.check(RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(RightParen, "Expect ')' after for clauses.")?;
+        let body = Box::new(self.statement()?);
+        Ok(StmtKind::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        })
+    }
+
+    fn block(&mut self) -> R<Vec<Stmt>> {
+        let mut statements = vec![];
+        while !self.check(RightBrace) && !self.check(EndOfFile) {
+            statements.push(self.declaration()?);
+        }
+        self.consume(RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> R<Expr> {
+        self.nested(Nesting::Expression, Self::assignment)
+    }
+
+    fn assignment(&mut self) -> R<Expr> {
+        let mut target = self.or()?;
+        if !self.matches(Equal) {
+            return Ok(target);
+        }
+        let equals = self.previous;
+        let value = Box::new(self.nested(Nesting::Expression, Self::assignment)?);
+        let span = target.span.to(value.span);
+        let kind = std::mem::replace(&mut target.kind, ExprKind::Literal(Literal::Nil));
+        Ok(match kind {
+            ExprKind::Variable(name) => Expr {
+                kind: ExprKind::Assign { name, value },
+                span,
+            },
+            ExprKind::Get { object, name } => Expr {
+                kind: ExprKind::Set {
+                    object,
+                    name,
+                    value,
+                },
+                span,
+            },
+            kind => {
+                self.error_at(equals, "Invalid assignment target.");
+                Expr {
+                    kind,
+                    span: target.span,
+                }
+            }
+        })
+    }
+
+    fn or(&mut self) -> R<Expr> {
+        let mut expr = self.and()?;
+        while self.matches(Or) {
+            let right = self.and()?;
+            expr = logical(LogicalOperator::Or, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> R<Expr> {
+        let mut expr = self.equality()?;
+        while self.matches(And) {
+            let right = self.equality()?;
+            expr = logical(LogicalOperator::And, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> R<Expr> {
+        let mut expr = self.comparison()?;
+        loop {
+            let operator = match self.current.token_type {
+                EqualEqual => BinaryOperator::Equal,
+                BangEqual => BinaryOperator::NotEqual,
+                _ => break,
+            };
+            self.advance();
+            let right = self.comparison()?;
+            expr = binary(operator, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> R<Expr> {
+        let mut expr = self.term()?;
+        loop {
+            let operator = match self.current.token_type {
+                Less => BinaryOperator::Less,
+                LessEqual => BinaryOperator::LessEqual,
+                Greater => BinaryOperator::Greater,
+                GreaterEqual => BinaryOperator::GreaterEqual,
+                _ => break,
+            };
+            self.advance();
+            let right = self.term()?;
+            expr = binary(operator, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> R<Expr> {
+        let mut expr = self.factor()?;
+        loop {
+            let operator = match self.current.token_type {
+                Plus => BinaryOperator::Add,
+                Minus => BinaryOperator::Subtract,
+                _ => break,
+            };
+            self.advance();
+            let right = self.factor()?;
+            expr = binary(operator, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn factor(&mut self) -> R<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let operator = match self.current.token_type {
+                Star => BinaryOperator::Multiply,
+                Slash => BinaryOperator::Divide,
+                _ => break,
+            };
+            self.advance();
+            let right = self.unary()?;
+            expr = binary(operator, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> R<Expr> {
+        let operator = match self.current.token_type {
+            Minus => UnaryOperator::Negate,
+            Bang => UnaryOperator::Not,
+            _ => return self.call(),
+        };
+        self.advance();
+        let start = self.previous.span();
+        let operand = Box::new(self.nested(Nesting::Expression, Self::unary)?);
+        let span = start.to(operand.span);
+        Ok(Expr {
+            kind: ExprKind::Unary { operator, operand },
+            span,
+        })
+    }
+
+    fn call(&mut self) -> R<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.matches(LeftParen) {
+                let mut arguments = vec![];
+                if !self.check(RightParen) {
+                    loop {
+                        if arguments.len() == MAX_ARGUMENTS {
+                            self.error_at_current("Can't have more than 255 arguments.");
+                        }
+                        arguments.push(self.expression()?);
+                        if !self.matches(Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(RightParen, "Expect ')' after arguments.")?;
+                let span = expr.span.to(self.previous.span());
+                expr = Expr {
+                    kind: ExprKind::Call {
+                        callee: Box::new(expr),
+                        arguments,
+                    },
+                    span,
+                };
+            } else if self.matches(Dot) {
+                let name = self.identifier("Expect property name after '.'.")?;
+                let span = expr.span.to(name.span);
+                expr = Expr {
+                    kind: ExprKind::Get {
+                        object: Box::new(expr),
+                        name,
+                    },
+                    span,
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> R<Expr> {
+        let token = self.current;
+        let kind = match token.token_type {
+            False => ExprKind::Literal(Literal::Bool(false)),
+            True => ExprKind::Literal(Literal::Bool(true)),
+            Nil => ExprKind::Literal(Literal::Nil),
+            This => ExprKind::This,
+            Number => ExprKind::Literal(Literal::Number(token.content.parse().unwrap())),
+            StringLiteral => ExprKind::Literal(Literal::String(token.content.to_string())),
+            Identifier => ExprKind::Variable(identifier(token)),
+            LeftParen => {
+                self.advance();
+                let expr = self.expression()?;
+                self.consume(RightParen, "Expect ')' after expression.")?;
+                return Ok(Expr {
+                    kind: ExprKind::Grouping(Box::new(expr)),
+                    span: token.span().to(self.previous.span()),
+                });
+            }
+            Super => {
+                self.advance();
+                self.consume(Dot, "Expect '.' after 'super'.")?;
+                let method = self.identifier("Expect superclass method name.")?;
+                return Ok(Expr {
+                    span: token.span().to(method.span),
+                    kind: ExprKind::Super { method },
+                });
+            }
+            _ => return Err(CompilerError::at(token.span(), "Expect expression.")),
+        };
+        self.advance();
+        Ok(Expr {
+            kind,
+            span: token.span(),
+        })
+    }
+
+    /// Parses with `parse` one level deeper, failing past the limit for
+    /// `nesting`.
+    fn nested<T>(&mut self, nesting: Nesting, parse: impl FnOnce(&mut Self) -> R<T>) -> R<T> {
+        if *self.depth(nesting) == nesting.limit() {
+            return Err(CompilerError::at(self.current.span(), nesting.message()));
+        }
+        *self.depth(nesting) += 1;
+        let result = parse(self);
+        *self.depth(nesting) -= 1;
+        result
+    }
+
+    fn depth(&mut self, nesting: Nesting) -> &mut usize {
+        match nesting {
+            Nesting::Statement => &mut self.statement_depth,
+            Nesting::Expression => &mut self.expression_depth,
+        }
+    }
+
+    fn statement_from(&self, start: Span, kind: StmtKind) -> Stmt {
+        Stmt {
+            kind,
+            span: start.to(self.previous.span()),
+        }
+    }
+
+    fn identifier(&mut self, message: &str) -> R<ast::Identifier> {
+        self.consume(TokenType::Identifier, message)?;
+        Ok(identifier(self.previous))
+    }
+
+    fn consume(&mut self, expected: TokenType, message: &str) -> R<()> {
+        if self.check(expected) {
+            self.advance();
+            Ok(())
+        } else {
+            Err(CompilerError::at(self.current.span(), message))
+        }
+    }
+
+    fn matches(&mut self, expected: TokenType) -> bool {
+        if !self.check(expected) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn check(&self, expected: TokenType) -> bool {
+        self.current.token_type == expected
+    }
+
+    /// Moves to the next token, reporting and skipping any the scanner
+    /// couldn't make sense of.
+    fn advance(&mut self) {
+        self.previous = self.current;
+        loop {
+            match self.scanner.next() {
+                Some(token) if token.token_type == ErrorToken => {
+                    self.error_at(token, scan_error(&token));
+                    self.scan_failed = true;
+                }
+                Some(token) => {
+                    self.current = token;
+                    return;
+                }
+                None => {
+                    self.current.token_type = EndOfFile;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Records an error that doesn't leave the parser confused about where
+    /// it is, so parsing carries on without synchronizing.
+    fn error_at(&mut self, token: Token, message: &str) {
+        self.errors.push(CompilerError::at(token.span(), message));
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(self.current, message);
+    }
+
+    /// Skips tokens until a likely statement boundary so one mistake doesn't
+    /// cascade into a wall of errors.
+    fn synchronize(&mut self) {
+        self.advance();
+        while !self.check(EndOfFile) {
+            if self.previous.token_type == Semicolon {
+                return;
+            }
+            match self.current.token_type {
+                Class | Fun | Var | For | If | While | Print | Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+}
+
+fn identifier(token: Token) -> ast::Identifier {
+    ast::Identifier {
+        name: token.content.to_string(),
+        span: token.span(),
+    }
+}
+
+fn binary(operator: BinaryOperator, left: Expr, right: Expr) -> Expr {
+    let span = left.span.to(right.span);
+    Expr {
+        kind: ExprKind::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        },
+        span,
+    }
+}
+
+fn logical(operator: LogicalOperator, left: Expr, right: Expr) -> Expr {
+    let span = left.span.to(right.span);
+    Expr {
+        kind: ExprKind::Logical {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        },
+        span,
+    }
+}
+
+pub trait Parsed {
+    fn parse_program(&self) -> Result<Program, Vec<CompilerError>>;
+}
+
+impl Parsed for &str {
+    fn parse_program(&self) -> Result<Program, Vec<CompilerError>> {
+        Parser::new(self, self.scanner()).parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_to_string;
+
+    use super::*;
+
+    #[test]
+    fn parses_fib() {
+        let source = read_to_string("tests/programs/fib.lox").unwrap();
+        let program = source.as_str().parse_program().unwrap();
+        assert_eq!(program.statements.len(), 1);
+        match &program.statements[0].kind {
+            StmtKind::Fun(function) => {
+                assert_eq!(function.name.name, "fib");
+                assert_eq!(function.params.len(), 1);
+                assert_eq!(function.body.len(), 2);
+            }
+            kind => panic!("expected function, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let program = "print 1 + 2 * 3;".parse_program().unwrap();
+        let StmtKind::Print(expr) = &program.statements[0].kind else {
+            panic!("expected print");
+        };
+        let ExprKind::Binary {
+            operator, right, ..
+        } = &expr.kind
+        else {
+            panic!("expected binary");
+        };
+        assert_eq!(*operator, BinaryOperator::Add);
+        assert!(matches!(
+            right.kind,
+            ExprKind::Binary {
+                operator: BinaryOperator::Multiply,
+                ..
+            }
+        ));
+        assert_eq!(
+            expr.span,
+            Span {
+                line: 1,
+                start: 6,
+                end: 15
+            }
+        );
+    }
+
+    #[test]
+    fn assignment_targets() {
+        let program = "a.b = c = 1;".parse_program().unwrap();
+        let StmtKind::Expression(expr) = &program.statements[0].kind else {
+            panic!("expected expression statement");
+        };
+        assert!(matches!(expr.kind, ExprKind::Set { .. }));
+        let errors = "1 = 2;".parse_program().unwrap_err();
+        assert_eq!(errors[0].message, "Invalid assignment target.");
+    }
+
+    #[test]
+    fn recovers_after_errors() {
+        let source = read_to_string("tests/programs/lots_of_stuff.lox").unwrap();
+        let errors = source.as_str().parse_program().unwrap_err();
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["Expect ';' after return value.", "Expect ';' after value."]
+        );
+        assert_eq!(errors[0].span.unwrap().line, 7);
+        assert_eq!(errors[1].span.unwrap().line, 25);
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let messages = |source: &str| {
+            let errors = source.parse_program().unwrap_err();
+            errors.into_iter().map(|e| e.message).collect::<Vec<_>>()
+        };
+        let parens = format!("print {}1{};", "(".repeat(1000), ")".repeat(1000));
+        assert_eq!(messages(&parens), vec!["Expression nests too deeply."]);
+        let negations = format!("print {}1;", "-".repeat(50_000));
+        assert_eq!(messages(&negations), vec!["Expression nests too deeply."]);
+        let assignments = format!("{}1;", "a = ".repeat(50_000));
+        assert_eq!(messages(&assignments), vec!["Expression nests too deeply."]);
+        let arguments = format!("{}1{};", "f(".repeat(1000), ")".repeat(1000));
+        assert_eq!(messages(&arguments), vec!["Expression nests too deeply."]);
+        let blocks = format!("{}{}", "{".repeat(50_000), "}".repeat(50_000));
+        assert_eq!(messages(&blocks)[0], "Statement nests too deeply.");
+        let functions = "fun f() {".repeat(1000);
+        assert_eq!(messages(&functions)[0], "Statement nests too deeply.");
+        // The limits are separate, so an expression nested as deeply as it
+        // can be is fine in a statement nested as deeply as it can be.
+        let nested = format!(
+            "{}print {}1{};{}",
+            "{".repeat(63),
+            "(".repeat(62),
+            ")".repeat(62),
+            "}".repeat(63)
+        );
+        assert!(nested.as_str().parse_program().is_ok());
+    }
+
+    #[test]
+    fn chains_are_not_nesting() {
+        for chain in [" + 1", " and 1", "()", ".b"] {
+            let source = format!("print a{};", chain.repeat(50_000));
+            assert!(source.as_str().parse_program().is_ok(), "{}", chain);
+        }
+        let errors = format!("print 1{}", " + 1".repeat(50_000))
+            .as_str()
+            .parse_program()
+            .unwrap_err();
+        assert_eq!(errors[0].message, "Expect ';' after value.");
+    }
+}
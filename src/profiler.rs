@@ -0,0 +1,348 @@
+//! The `rlox profile` profiler: a `Hook` counting what a script executes
+//! and timing its calls.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    ops::ControlFlow,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    chunk::OpCode,
+    object::Obj,
+    vm::{Execution, Hook},
+};
+
+/// What one function did over a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    /// `script` for the top-level code.
+    pub name: String,
+    pub calls: u64,
+    /// Time spent running the function's own code.
+    pub self_time: Duration,
+    /// Time from calling the function until it returned, counting each
+    /// recursive call only once.
+    pub total_time: Duration,
+}
+
+/// A call in progress.
+struct Active {
+    function: usize,
+    entered: Instant,
+    /// Total time of the calls this one has made so far.
+    children: Duration,
+    stack: usize,
+}
+
+/// Counts every instruction, and reads the clock only when a call starts or
+/// returns, so that timing doesn't add to the cost of each instruction.
+pub struct Profiler {
+    /// Executions of each opcode, indexed by its byte.
+    op_counts: Vec<u64>,
+    /// Instructions executed on each line, indexed by line number.
+    line_hits: Vec<u64>,
+    functions: Vec<FunctionProfile>,
+    /// Index into `functions` of each function seen running, by address.
+    function_index: HashMap<*const Obj, usize>,
+    /// The functions in `function_index`, held so that none is freed and
+    /// its address reused for another while profiling.
+    seen: Vec<Rc<Obj>>,
+    active: Vec<Active>,
+    /// Every call stack seen, as its caller's stack and the function called.
+    stacks: Vec<(Option<usize>, usize)>,
+    stack_index: HashMap<(Option<usize>, usize), usize>,
+    /// Instructions executed with each stack in `stacks`.
+    stack_counts: Vec<u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            op_counts: vec![0; 256],
+            line_hits: vec![],
+            functions: vec![],
+            function_index: HashMap::new(),
+            seen: vec![],
+            active: vec![],
+            stacks: vec![],
+            stack_index: HashMap::new(),
+            stack_counts: vec![],
+        }
+    }
+
+    /// Ends the timing of calls the script didn't return from, which
+    /// includes the script itself. Call it once the run is over.
+    pub fn finish(&mut self) {
+        let now = Instant::now();
+        while !self.active.is_empty() {
+            self.leave(now);
+        }
+    }
+
+    pub fn op_count(&self, op_code: OpCode) -> u64 {
+        self.op_counts[op_code as usize]
+    }
+
+    pub fn line_hits(&self, line: usize) -> u64 {
+        self.line_hits.get(line).copied().unwrap_or_default()
+    }
+
+    /// Every function that was called, in the order they were first called.
+    pub fn functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    /// Writes tables of functions by total time, opcodes by count and the
+    /// lines that ran.
+    pub fn write_summary(&self, output: &mut dyn Write) -> io::Result<()> {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by_key(|function| std::cmp::Reverse(function.total_time));
+        writeln!(
+            output,
+            "{:<24} {:>10} {:>12} {:>12}",
+            "Function", "Calls", "Self ms", "Total ms"
+        )?;
+        for function in functions {
+            writeln!(
+                output,
+                "{:<24} {:>10} {:>12.3} {:>12.3}",
+                function.name,
+                function.calls,
+                millis(function.self_time),
+                millis(function.total_time)
+            )?;
+        }
+        let mut op_counts: Vec<_> = (0..=u8::MAX)
+            .filter_map(|byte| Some((OpCode::try_from(byte).ok()?, self.op_counts[byte as usize])))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        op_counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        writeln!(output, "\n{:<24} {:>10}", "Opcode", "Count")?;
+        for (op_code, count) in op_counts {
+            writeln!(output, "{:<24} {:>10}", op_code.to_string(), count)?;
+        }
+        writeln!(output, "\n{:<24} {:>10}", "Line", "Hits")?;
+        for (line, hits) in self.line_hits.iter().enumerate() {
+            if *hits > 0 {
+                writeln!(output, "{:<24} {:>10}", line, hits)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes one line per call stack, its functions from the outermost
+    /// separated by semicolons, followed by how many instructions ran with
+    /// it on top: the folded format flame graph tools read.
+    pub fn write_folded(&self, output: &mut dyn Write) -> io::Result<()> {
+        for (stack, count) in self.stack_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let mut names = vec![];
+            let mut next = Some(stack);
+            while let Some(stack) = next {
+                let (caller, function) = self.stacks[stack];
+                names.push(self.functions[function].name.as_str());
+                next = caller;
+            }
+            names.reverse();
+            writeln!(output, "{} {}", names.join(";"), count)?;
+        }
+        Ok(())
+    }
+
+    /// Starts timing a call, or stops timing the ones that have returned.
+    #[cold]
+    fn called_or_returned(&mut self, execution: &Execution) {
+        let now = Instant::now();
+        let depth = execution.depth();
+        while self.active.len() > depth {
+            self.leave(now);
+        }
+        if self.active.len() < depth {
+            let function = self.function(execution);
+            self.functions[function].calls += 1;
+            let caller = self.active.last().map(|active| active.stack);
+            let stack = *self
+                .stack_index
+                .entry((caller, function))
+                .or_insert_with(|| {
+                    self.stacks.push((caller, function));
+                    self.stack_counts.push(0);
+                    self.stacks.len() - 1
+                });
+            self.active.push(Active {
+                function,
+                entered: now,
+                children: Duration::ZERO,
+                stack,
+            });
+        }
+    }
+
+    fn leave(&mut self, now: Instant) {
+        let active = self.active.pop().expect("a call to leave");
+        let total = now - active.entered;
+        let function = &mut self.functions[active.function];
+        function.self_time += total.saturating_sub(active.children);
+        if !self
+            .active
+            .iter()
+            .any(|caller| caller.function == active.function)
+        {
+            function.total_time += total;
+        }
+        if let Some(caller) = self.active.last_mut() {
+            caller.children += total;
+        }
+    }
+
+    fn function(&mut self, execution: &Execution) -> usize {
+        let function = execution.function();
+        *self
+            .function_index
+            .entry(Rc::as_ptr(&function))
+            .or_insert_with(|| {
+                self.seen.push(function);
+                self.functions.push(FunctionProfile {
+                    name: execution.function_name().unwrap_or("script").to_string(),
+                    calls: 0,
+                    self_time: Duration::ZERO,
+                    total_time: Duration::ZERO,
+                });
+                self.functions.len() - 1
+            })
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hook for Profiler {
+    fn instruction(&mut self, execution: &Execution) -> ControlFlow<()> {
+        if execution.depth() != self.active.len() {
+            self.called_or_returned(execution);
+        }
+        self.op_counts[execution.op_code() as usize] += 1;
+        let line = execution.line();
+        if line >= self.line_hits.len() {
+            self.line_hits.resize(line + 1, 0);
+        }
+        self.line_hits[line] += 1;
+        let stack = self.active.last().expect("a call in progress").stack;
+        self.stack_counts[stack] += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        lowering::Lowered,
+        vm::{InterpretResult, VM},
+    };
+
+    fn profile(source: &str) -> Profiler {
+        let chunk = source.lower().unwrap();
+        let mut profiler = Profiler::new();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        assert_eq!(
+            vm.interpret_chunk_with(&chunk, &mut profiler),
+            InterpretResult::Ok
+        );
+        profiler.finish();
+        profiler
+    }
+
+    const SOURCE: &str = "\
+fun add(a, b) {
+  return a + b;
+}
+fun count(n) {
+  if (n > 0) count(n - 1);
+}
+var i = 0;
+while (i < 3) {
+  add(i, 1);
+  i = i + 1;
+}
+count(2);
+";
+
+    #[test]
+    fn counts() {
+        let profiler = profile(SOURCE);
+        let calls: Vec<_> = profiler
+            .functions()
+            .iter()
+            .map(|function| (function.name.as_str(), function.calls))
+            .collect();
+        assert_eq!(calls, [("script", 1), ("add", 3), ("count", 3)]);
+        // Three in `add` and three incrementing `i`.
+        assert_eq!(profiler.op_count(OpCode::Add), 6);
+        assert_eq!(profiler.op_count(OpCode::Less), 4);
+        // GetLocal a, GetLocal b, Add, Return.
+        assert_eq!(profiler.line_hits(2), 12);
+        assert_eq!(profiler.line_hits(13), 0);
+        for function in profiler.functions() {
+            assert!(function.self_time <= function.total_time);
+        }
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let profiler = profile(SOURCE);
+        let mut folded = vec![];
+        profiler.write_folded(&mut folded).unwrap();
+        let stacks: Vec<_> = String::from_utf8(folded)
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_string())
+            .collect();
+        assert_eq!(
+            stacks,
+            [
+                "script",
+                "script;add",
+                "script;count",
+                "script;count;count",
+                "script;count;count;count"
+            ]
+        );
+        let mut summary = vec![];
+        profiler.write_summary(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert!(summary.starts_with("Function "));
+        assert!(summary.contains("\nOpcode "));
+        assert!(summary.contains(&format!("\n{:<24} {:>10}\n", "Add", 6)));
+    }
+
+    #[test]
+    fn functions_freed_between_runs_stay_apart() {
+        let mut profiler = Profiler::new();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        for source in ["fun a() {} a();", "fun b() {} b();"] {
+            // Each chunk is dropped before the next is lowered, so without
+            // holding on to its functions their addresses could be reused.
+            let chunk = source.lower().unwrap();
+            vm.interpret_chunk_with(&chunk, &mut profiler);
+            profiler.finish();
+        }
+        let names: Vec<_> = profiler
+            .functions()
+            .iter()
+            .map(|function| function.name.as_str())
+            .collect();
+        assert_eq!(names, ["script", "a", "script", "b"]);
+    }
+}
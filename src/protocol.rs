@@ -0,0 +1,59 @@
+//! The framing the Debug Adapter and Language Server Protocols share: JSON
+//! messages, each preceded by a `Content-Length` header.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value as Json;
+
+/// Reads one message, or `None` at the end of the input. Headers other than
+/// `Content-Length` are ignored.
+pub(crate) fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(io::Error::from)
+}
+
+pub(crate) fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let mut input = "Content-Length: 10\r\nContent-Type: application/json\r\n\r\n\
+             {\"seq\": 1}Content-Length: 2\r\n\r\n{}"
+            .as_bytes();
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        let mut output = vec![];
+        write_message(&mut output, &json!({ "id": 1, "result": null })).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Content-Length: 22\r\n\r\n{\"id\":1,\"result\":null}"
+        );
+    }
+}
@@ -0,0 +1,587 @@
+use std::{collections::HashMap, fmt};
+
+use strum_macros::{Display, EnumString};
+
+use crate::{
+    ast::*,
+    compiler::CompilerError,
+    lowering::Lowered,
+    parser::Parsed,
+    scanner::Scannable,
+    token::{LineNo, Span},
+    token_type::TokenType,
+};
+
+/// Comment marker that silences warnings on its line, optionally limited to
+/// some kinds: `// rlox-ignore: unused-variable, shadowing`.
+const IGNORE_MARKER: &str = "rlox-ignore";
+
+#[derive(Debug, Display, EnumString, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum WarningKind {
+    UnusedVariable,
+    UnusedParameter,
+    Shadowing,
+    UnreachableCode,
+    UndeclaredGlobal,
+    /// An ignore marker names a kind that doesn't exist.
+    UnknownIgnore,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: warning[{}]: {}",
+            self.span.line, self.kind, self.message
+        )
+    }
+}
+
+/// A variable's use, or assignment, and the declaration it refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub usage: Span,
+    pub declaration: Span,
+}
+
+struct Variable {
+    name: String,
+    span: Span,
+    used: bool,
+    is_parameter: bool,
+}
+
+/// Walks the AST tracking lexical scopes the same way the lowering pass
+/// does, reporting code that compiles but is probably a mistake and
+/// noting which declaration each variable refers to.
+struct Resolver {
+    scopes: Vec<Vec<Variable>>,
+    /// Where each top-level name is first declared.
+    globals: HashMap<String, Span>,
+    warnings: Vec<Warning>,
+    references: Vec<Reference>,
+}
+
+impl Resolver {
+    fn new(program: &Program) -> Self {
+        let mut globals = HashMap::new();
+        for statement in program.statements.iter() {
+            let name = match &statement.kind {
+                StmtKind::Var { name, .. } => name,
+                StmtKind::Fun(function) => &function.name,
+                StmtKind::Class(class) => &class.name,
+                _ => continue,
+            };
+            globals.entry(name.name.clone()).or_insert(name.span);
+        }
+        Self {
+            scopes: vec![],
+            globals,
+            warnings: vec![],
+            references: vec![],
+        }
+    }
+
+    fn warn(&mut self, kind: WarningKind, span: Span, message: String) {
+        self.warnings.push(Warning {
+            kind,
+            message,
+            span,
+        });
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        let mut returned = false;
+        for statement in statements.iter() {
+            if returned {
+                self.warn(
+                    WarningKind::UnreachableCode,
+                    statement.span,
+                    "Unreachable code after 'return'.".to_string(),
+                );
+                returned = false;
+            }
+            self.statement(statement);
+            if always_returns(statement) {
+                returned = true;
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match &statement.kind {
+            StmtKind::Class(class) => {
+                self.declare(&class.name, false);
+                if let Some(superclass) = &class.superclass {
+                    self.read(superclass);
+                }
+                for method in class.methods.iter() {
+                    self.function(method);
+                }
+            }
+            StmtKind::Fun(function) => {
+                self.declare(&function.name, false);
+                self.function(function);
+            }
+            StmtKind::Var { name, initializer } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.declare(name, false);
+            }
+            StmtKind::Expression(expression) | StmtKind::Print(expression) => {
+                self.expression(expression)
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            StmtKind::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body);
+                self.end_scope();
+            }
+            StmtKind::Block(statements) => {
+                self.begin_scope();
+                self.statements(statements);
+                self.end_scope();
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.begin_scope();
+        for param in function.params.iter() {
+            self.declare(param, true);
+        }
+        self.statements(&function.body);
+        self.end_scope();
+    }
+
+    fn expression(&mut self, expression: &Expr) {
+        let (innermost, links) = expression.chain();
+        self.unchained(innermost);
+        for link in links {
+            match &link.kind {
+                ExprKind::Binary { right, .. } | ExprKind::Logical { right, .. } => {
+                    self.expression(right)
+                }
+                ExprKind::Call { arguments, .. } => {
+                    for argument in arguments.iter() {
+                        self.expression(argument);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Resolves an expression that isn't a link of a chain.
+    fn unchained(&mut self, expression: &Expr) {
+        match &expression.kind {
+            ExprKind::Literal(_) => {}
+            ExprKind::Binary { .. }
+            | ExprKind::Logical { .. }
+            | ExprKind::Call { .. }
+            | ExprKind::Get { .. } => unreachable!("chains are resolved a link at a time"),
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Unary { operand, .. } => self.expression(operand),
+            ExprKind::Variable(name) => self.read(name),
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                let declaration = self
+                    .local(name)
+                    .map(|variable| variable.span)
+                    .or_else(|| self.globals.get(&name.name).copied());
+                if let Some(declaration) = declaration {
+                    self.references.push(Reference {
+                        usage: name.span,
+                        declaration,
+                    });
+                } else {
+                    self.warn(
+                        WarningKind::UndeclaredGlobal,
+                        name.span,
+                        format!("Assignment to undeclared global '{}'.", name.name),
+                    );
+                }
+            }
+            ExprKind::Set { object, value, .. } => {
+                self.expression(object);
+                self.expression(value);
+            }
+            // Outside a class these are lowering errors, not warnings.
+            ExprKind::This | ExprKind::Super { .. } => {}
+        }
+    }
+
+    /// The innermost local variable called `name`.
+    fn local(&mut self, name: &Identifier) -> Option<&mut Variable> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|variable| variable.name == name.name)
+    }
+
+    fn read(&mut self, name: &Identifier) {
+        let declaration = match self.local(name) {
+            Some(variable) => {
+                variable.used = true;
+                Some(variable.span)
+            }
+            None => self.globals.get(&name.name).copied(),
+        };
+        if let Some(declaration) = declaration {
+            self.references.push(Reference {
+                usage: name.span,
+                declaration,
+            });
+        }
+    }
+
+    fn declare(&mut self, name: &Identifier, is_parameter: bool) {
+        let Some((scope, enclosing)) = self.scopes.split_last_mut() else {
+            return;
+        };
+        if enclosing
+            .iter()
+            .any(|scope| scope.iter().any(|variable| variable.name == name.name))
+        {
+            self.warnings.push(Warning {
+                kind: WarningKind::Shadowing,
+                message: format!(
+                    "'{}' shadows a variable from an enclosing scope.",
+                    name.name
+                ),
+                span: name.span,
+            });
+        }
+        scope.push(Variable {
+            name: name.name.clone(),
+            span: name.span,
+            used: false,
+            is_parameter,
+        });
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    fn end_scope(&mut self) {
+        for variable in self.scopes.pop().unwrap() {
+            // A leading underscore marks a variable as deliberately unused.
+            if variable.used || variable.name.starts_with('_') {
+                continue;
+            }
+            let (kind, description) = if variable.is_parameter {
+                (WarningKind::UnusedParameter, "parameter")
+            } else {
+                (WarningKind::UnusedVariable, "local variable")
+            };
+            self.warn(
+                kind,
+                variable.span,
+                format!("Unused {} '{}'.", description, variable.name),
+            );
+        }
+    }
+}
+
+fn always_returns(statement: &Stmt) -> bool {
+    match &statement.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::Block(statements) => statements.iter().any(always_returns),
+        StmtKind::If {
+            then_branch,
+            else_branch: Some(else_branch),
+            ..
+        } => always_returns(then_branch) && always_returns(else_branch),
+        _ => false,
+    }
+}
+
+/// A line carrying an ignore marker.
+struct Suppression {
+    line: LineNo,
+    /// The kinds it is limited to, or `None` for all kinds.
+    kinds: Option<Vec<WarningKind>>,
+}
+
+impl Suppression {
+    fn covers(&self, warning: &Warning) -> bool {
+        self.line == warning.span.line
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&warning.kind))
+    }
+}
+
+/// The ignore markers in `source`, and a warning for each kind they name
+/// that doesn't exist. A marker whose list names no real kind suppresses
+/// nothing, so a typo can't silence everything.
+fn suppressions(source: &str) -> (Vec<Suppression>, Vec<Warning>) {
+    let mut suppressions = vec![];
+    let mut warnings = vec![];
+    let comments = source
+        .scanner()
+        .with_comments()
+        .filter(|token| token.token_type == TokenType::Comment);
+    for token in comments {
+        let text = token.content.trim_start_matches('/').trim();
+        let Some(rest) = text.strip_prefix(IGNORE_MARKER) else {
+            continue;
+        };
+        // `rlox-ignored` and the like aren't markers.
+        let kinds = match rest.strip_prefix(':') {
+            Some(list) => list
+                .split(',')
+                .map(str::trim)
+                .filter(|kind| !kind.is_empty()),
+            None if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+                suppressions.push(Suppression {
+                    line: token.line,
+                    kinds: None,
+                });
+                continue;
+            }
+            None => continue,
+        };
+        let mut known = vec![];
+        for kind in kinds {
+            match kind.parse() {
+                Ok(kind) => known.push(kind),
+                Err(_) => warnings.push(Warning {
+                    kind: WarningKind::UnknownIgnore,
+                    message: format!("Unknown warning kind '{}'.", kind),
+                    span: token.span(),
+                }),
+            }
+        }
+        suppressions.push(Suppression {
+            line: token.line,
+            kinds: Some(known),
+        });
+    }
+    (suppressions, warnings)
+}
+
+pub trait Linted {
+    fn lint(&self) -> Vec<Warning>;
+}
+
+impl Linted for Program {
+    fn lint(&self) -> Vec<Warning> {
+        let mut resolver = Resolver::new(self);
+        resolver.statements(&self.statements);
+        resolver.warnings
+    }
+}
+
+pub trait Resolved {
+    /// The uses of variables whose declarations can be found, in source
+    /// order. Globals refer to their first top-level declaration.
+    fn references(&self) -> Vec<Reference>;
+}
+
+impl Resolved for Program {
+    fn references(&self) -> Vec<Reference> {
+        let mut resolver = Resolver::new(self);
+        resolver.statements(&self.statements);
+        resolver.references
+    }
+}
+
+/// Everything `rlox check` reports about a source file.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<CompilerError>,
+    pub warnings: Vec<Warning>,
+}
+
+pub trait Checked {
+    fn check(&self) -> Diagnostics;
+}
+
+impl Checked for &str {
+    fn check(&self) -> Diagnostics {
+        let program = match self.parse_program() {
+            Ok(program) => program,
+            Err(errors) => {
+                return Diagnostics {
+                    errors,
+                    warnings: vec![],
+                }
+            }
+        };
+        let errors = program.lower().err().unwrap_or_default();
+        let (suppressions, unknown_kinds) = suppressions(self);
+        let mut warnings: Vec<_> = program
+            .lint()
+            .into_iter()
+            .filter(|warning| {
+                !suppressions
+                    .iter()
+                    .any(|suppression| suppression.covers(warning))
+            })
+            .collect();
+        warnings.extend(unknown_kinds);
+        Diagnostics { errors, warnings }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<WarningKind> {
+        source.check().warnings.iter().map(|w| w.kind).collect()
+    }
+
+    #[test]
+    fn unused_locals_and_parameters() {
+        let warnings = "fun go(a, _b) { var c = 1; var d = 2; print d; }"
+            .check()
+            .warnings;
+        let messages: Vec<_> = warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["Unused parameter 'a'.", "Unused local variable 'c'."]
+        );
+        assert_eq!(
+            warnings[0].span,
+            Span {
+                line: 1,
+                start: 7,
+                end: 8
+            }
+        );
+    }
+
+    #[test]
+    fn shadowing() {
+        assert_eq!(
+            kinds("fun go(a) { { var a = a; print a; } }"),
+            vec![WarningKind::Shadowing]
+        );
+        assert!(kinds("var a = 1; { var a = 2; print a; }").is_empty());
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(
+            kinds("fun go(a) { if (a) return 1; else { return 2; } print a; }"),
+            vec![WarningKind::UnreachableCode]
+        );
+        assert!(kinds("fun go(a) { if (a) return 1; print a; }").is_empty());
+    }
+
+    #[test]
+    fn undeclared_globals() {
+        assert_eq!(
+            kinds("fun go() { count = 1; total = 2; }\nvar total;"),
+            vec![WarningKind::UndeclaredGlobal]
+        );
+    }
+
+    #[test]
+    fn this_outside_method() {
+        // Lowering reports it; a warning as well would say it twice.
+        let diagnostics = "fun go() { return this; }".check();
+        assert!(diagnostics.warnings.is_empty());
+        assert_eq!(diagnostics.errors.len(), 1);
+        assert!(kinds("class A { m() { fun g() { return this; } return g; } }").is_empty());
+    }
+
+    #[test]
+    fn references() {
+        let source = "var a = 1;\nfun go(a) { { var a = a; print a; } return a; }\nprint a;";
+        let program = source.parse_program().unwrap();
+        let text = |span: Span| format!("{}@{}", &source[span.start..span.end], span.start);
+        let references: Vec<_> = program
+            .references()
+            .into_iter()
+            .map(|reference| (text(reference.usage), text(reference.declaration)))
+            .collect();
+        let pairs = [
+            ("a@33", "a@18"),
+            ("a@42", "a@29"),
+            ("a@54", "a@18"),
+            ("a@65", "a@4"),
+        ];
+        let expected: Vec<_> = pairs
+            .iter()
+            .map(|(usage, declaration)| (usage.to_string(), declaration.to_string()))
+            .collect();
+        assert_eq!(references, expected);
+    }
+
+    #[test]
+    fn suppression() {
+        let source = "fun go(a) { // rlox-ignore\n  var b; // rlox-ignore: shadowing\n  var c; // rlox-ignore: unused-variable\n}";
+        let warnings = source.check().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "Unused local variable 'b'.");
+    }
+
+    #[test]
+    fn suppression_typos_suppress_nothing() {
+        let source = "fun go() {\n  var b; // rlox-ignore: unused-varaible\n  var c; // rlox-ignored\n  var d; // rlox-ignore: shadowing, nope\n  var e; // rlox-ignore since it's fine\n}";
+        let messages: Vec<_> = source
+            .check()
+            .warnings
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "2: warning[unused-variable]: Unused local variable 'b'.",
+                "3: warning[unused-variable]: Unused local variable 'c'.",
+                "4: warning[unused-variable]: Unused local variable 'd'.",
+                "2: warning[unknown-ignore]: Unknown warning kind 'unused-varaible'.",
+                "4: warning[unknown-ignore]: Unknown warning kind 'nope'.",
+            ]
+        );
+    }
+}
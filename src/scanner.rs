@@ -1,7 +1,4 @@
-use std::{
-    iter::{Enumerate, Peekable},
-    str::Chars,
-};
+use std::{iter::Peekable, str::CharIndices};
 
 use super::{
     token::{LineNo, Token},
@@ -9,33 +6,76 @@ use super::{
 };
 
 pub(crate) trait Scannable {
-    fn scanner(&self) -> Scanner;
+    fn scanner(&self) -> Scanner<'_>;
 }
 
 impl Scannable for &str {
-    fn scanner(&self) -> Scanner {
+    fn scanner(&self) -> Scanner<'_> {
         Scanner {
-            string: &self,
-            iter: self.chars().enumerate().peekable(),
+            string: self,
+            iter: self.char_indices().peekable(),
             line_count: 1,
-            char_count: 0,
             done: false,
+            keep_comments: false,
         }
     }
 }
 
 pub(crate) struct Scanner<'a> {
     string: &'a str,
-    iter: Peekable<Enumerate<Chars<'a>>>,
+    iter: Peekable<CharIndices<'a>>,
     line_count: LineNo,
-    char_count: usize,
     done: bool,
+    keep_comments: bool,
+}
+
+/// What's wrong with the source an `ErrorToken` covers.
+pub(crate) fn scan_error(token: &Token) -> &'static str {
+    if token.content.starts_with('"') {
+        "Unterminated string."
+    } else {
+        "Unexpected character."
+    }
 }
 
 impl<'a> Scanner<'a> {
+    /// Emits `//` comments as `Comment` tokens instead of skipping them, for
+    /// tools that need to reproduce the source.
+    pub(crate) fn with_comments(mut self) -> Self {
+        self.keep_comments = true;
+        self
+    }
+
     fn peek(&mut self) -> char {
         self.iter.peek().map(|(.., b)| *b).unwrap_or('@')
     }
+
+    /// Skips whitespace, and comments unless they are kept, counting lines.
+    /// A loop rather than recursion from `next`, so no amount of either can
+    /// overflow the stack.
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                ' ' | '\t' | '\r' => {}
+                '\n' => self.line_count = self.line_count.saturating_add(1),
+                '/' if !self.keep_comments && self.string[self.end()..].starts_with("//") => {
+                    while self.iter.peek().is_some() && self.peek() != '\n' {
+                        self.iter.next();
+                    }
+                    continue;
+                }
+                _ => return,
+            }
+            self.iter.next();
+        }
+    }
+
+    /// Byte offset of the first character not yet scanned.
+    fn end(&mut self) -> usize {
+        self.iter
+            .peek()
+            .map_or(self.string.len(), |(offset, _)| *offset)
+    }
 }
 
 impl<'a> Iterator for Scanner<'a> {
@@ -45,22 +85,24 @@ impl<'a> Iterator for Scanner<'a> {
         if self.done {
             return None;
         }
+        self.skip_whitespace();
         let next = self.iter.next();
         if next.is_none() {
             self.done = true;
             return Some(Token {
                 content: "",
                 line: self.line_count,
+                offset: self.string.len(),
                 token_type: EndOfFile,
             });
         }
-        let (char_count, next_char) = next.unwrap();
-        self.char_count = char_count;
+        let (start, next_char) = next.unwrap();
         macro_rules! single {
             ($token_type:ident) => {
                 Some(Token {
-                    content: &self.string[char_count..char_count + 1],
+                    content: &self.string[start..start + 1],
                     line: self.line_count,
+                    offset: start,
                     token_type: $token_type,
                 })
             };
@@ -70,14 +112,16 @@ impl<'a> Iterator for Scanner<'a> {
                 Some(if self.peek() == '=' {
                     self.iter.next();
                     Token {
-                        content: &self.string[char_count..char_count + 2],
+                        content: &self.string[start..start + 2],
                         line: self.line_count,
+                        offset: start,
                         token_type: $yes,
                     }
                 } else {
                     Token {
-                        content: &self.string[char_count..char_count + 1],
+                        content: &self.string[start..start + 1],
                         line: self.line_count,
+                        offset: start,
                         token_type: $no,
                     }
                 })
@@ -88,19 +132,19 @@ impl<'a> Iterator for Scanner<'a> {
         }
         match next_char {
             '0'..='9' => {
-                let start = char_count;
-                while '0' <= self.peek() && self.peek() <= '9' {
-                    self.char_count = self.iter.next().unwrap().0;
+                while self.peek().is_ascii_digit() {
+                    self.iter.next();
                 }
                 if self.peek() == '.' {
                     self.iter.next();
-                    while '0' <= self.peek() && self.peek() <= '9' {
-                        self.char_count = self.iter.next().unwrap().0;
+                    while self.peek().is_ascii_digit() {
+                        self.iter.next();
                     }
                 }
                 Some(Token {
                     line: self.line_count,
-                    content: &self.string[start..self.char_count + 1],
+                    offset: start,
+                    content: &self.string[start..self.end()],
                     token_type: Number,
                 })
             }
@@ -115,43 +159,51 @@ impl<'a> Iterator for Scanner<'a> {
             '+' => single!(Plus),
             '*' => single!(Star),
             '/' => {
+                // Only kept comments get here; see `skip_whitespace`.
                 if self.peek() == '/' {
-                    while self.peek() != '\n' {
-                        self.char_count = self.iter.next().unwrap().0;
+                    while self.iter.peek().is_some() && self.peek() != '\n' {
+                        self.iter.next();
                     }
-                    return self.next();
+                    return Some(Token {
+                        content: &self.string[start..self.end()],
+                        line: self.line_count,
+                        offset: start,
+                        token_type: Comment,
+                    });
                 }
                 single!(Slash)
             }
-            '"' => {
-                let start = self.char_count + 1;
-                while self.peek() != '"' {
-                    if self.iter.next().unwrap().1 == '\n' {
-                        self.line_count += 1;
+            '"' => loop {
+                match self.iter.next() {
+                    Some((end, '"')) => {
+                        break Some(Token {
+                            content: &self.string[start + 1..end],
+                            line: self.line_count,
+                            offset: start + 1,
+                            token_type: StringLiteral,
+                        })
+                    }
+                    Some((_, '\n')) => self.line_count = self.line_count.saturating_add(1),
+                    Some(_) => {}
+                    None => {
+                        break Some(Token {
+                            content: &self.string[start..],
+                            line: self.line_count,
+                            offset: start,
+                            token_type: ErrorToken,
+                        })
                     }
                 }
-                self.char_count = self.iter.next().unwrap().0;
-                Some(Token {
-                    content: &self.string[start..self.char_count],
-                    line: self.line_count,
-                    token_type: StringLiteral,
-                })
-            }
+            },
             '!' => double!(BangEqual, Bang),
             '=' => double!(EqualEqual, Equal),
             '<' => double!(LessEqual, Less),
             '>' => double!(GreaterEqual, Greater),
-            ' ' | '\t' | '\r' => self.next(),
-            '\n' => {
-                self.line_count += 1;
-                self.next()
-            }
             _ if is_lead_identifier(next_char) => {
-                let start = self.char_count;
                 while self.peek().is_alphanumeric() {
-                    self.char_count = self.iter.next().unwrap().0;
+                    self.iter.next();
                 }
-                let content = &self.string[start..self.char_count + 1];
+                let content = &self.string[start..self.end()];
                 let rest = |rest: &str, tt: TokenType| -> TokenType {
                     if content == rest {
                         tt
@@ -159,11 +211,12 @@ impl<'a> Iterator for Scanner<'a> {
                         Identifier
                     }
                 };
-                let mut chars = content.chars();
+                let mut chars = content.chars().skip(1);
                 Some(Token {
                     line: self.line_count,
+                    offset: start,
                     content,
-                    token_type: match chars.next().unwrap() {
+                    token_type: match next_char {
                         'a' => rest("and", And),
                         'c' => rest("class", Class),
                         'e' => rest("else", Else),
@@ -175,22 +228,27 @@ impl<'a> Iterator for Scanner<'a> {
                         's' => rest("super", Super),
                         'v' => rest("var", Var),
                         'w' => rest("while", While),
-                        'f' => match chars.next().unwrap() {
-                            'a' => rest("false", False),
-                            'o' => rest("for", For),
-                            'u' => rest("fun", Fun),
+                        'f' => match chars.next() {
+                            Some('a') => rest("false", False),
+                            Some('o') => rest("for", For),
+                            Some('u') => rest("fun", Fun),
                             _ => Identifier,
                         },
-                        't' => match chars.next().unwrap() {
-                            'h' => rest("this", This),
-                            'r' => rest("true", True),
+                        't' => match chars.next() {
+                            Some('h') => rest("this", This),
+                            Some('r') => rest("true", True),
                             _ => Identifier,
                         },
                         _ => Identifier,
                     },
                 })
             }
-            _ => None,
+            _ => Some(Token {
+                content: &self.string[start..start + next_char.len_utf8()],
+                line: self.line_count,
+                offset: start,
+                token_type: ErrorToken,
+            }),
         }
     }
 }
@@ -208,6 +266,70 @@ mod tests {
             println!("{:#?}", t);
         }
     }
+
+    fn tokens(source: &str) -> Vec<String> {
+        source
+            .scanner()
+            .map(|token| format!("{} {}", token.token_type, token.content))
+            .collect()
+    }
+
+    #[test]
+    fn single_letter_identifiers() {
+        assert_eq!(
+            tokens("f t fo"),
+            [
+                "Identifier f",
+                "Identifier t",
+                "Identifier fo",
+                "EndOfFile "
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            tokens("1 @ 2"),
+            ["Number 1", "ErrorToken @", "Number 2", "EndOfFile "]
+        );
+        assert_eq!(
+            tokens("print \"abc\n"),
+            ["Print print", "ErrorToken \"abc\n", "EndOfFile "]
+        );
+        let unterminated = "\"abc".scanner().next().unwrap();
+        assert_eq!(scan_error(&unterminated), "Unterminated string.");
+        let unexpected = "€".scanner().next().unwrap();
+        assert_eq!(scan_error(&unexpected), "Unexpected character.");
+    }
+
+    #[test]
+    fn long_files() {
+        let source = format!("{}x // c\n{}y", "\n".repeat(70_000), " ".repeat(200_000));
+        let lines: Vec<_> = source.as_str().scanner().map(|token| token.line).collect();
+        assert_eq!(lines, [70_001, 70_002, 70_002]);
+    }
+
+    #[test]
+    fn non_ascii() {
+        let source = "var héllo = \"été\"; // ünïcode\n€";
+        assert_eq!(
+            tokens(source),
+            [
+                "Var var",
+                "Identifier héllo",
+                "Equal =",
+                "StringLiteral été",
+                "Semicolon ;",
+                "ErrorToken €",
+                "EndOfFile "
+            ]
+        );
+        for token in source.scanner() {
+            let span = token.span();
+            assert!(source.is_char_boundary(span.start) && source.is_char_boundary(span.end));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -217,13 +339,14 @@ pub struct TokenDebug {
     pub token_type: TokenType,
 }
 
-impl<'a> Into<TokenDebug> for Token<'a> {
-    fn into(self) -> TokenDebug {
+impl<'a> From<Token<'a>> for TokenDebug {
+    fn from(token: Token<'a>) -> TokenDebug {
         let Token {
             content,
             line,
             token_type,
-        } = self;
+            ..
+        } = token;
         TokenDebug {
             content: String::from(content),
             line,
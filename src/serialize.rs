@@ -0,0 +1,535 @@
+//! The `.loxc` precompiled bytecode format. All integers are little-endian.
+//!
+//! ```text
+//! file     := "LOXC" version:u16 flags:u8 [source_hash:u64] chunk
+//! chunk    := count:u32 constant* length:u32 code:u8* runs:u32 (line:u32 count:u32)*
+//! constant := 0 number:f64 | 1 string | 2 function | 3 | 4 | 5
+//! function := has_name:u8 [name:string] arity:u8 upvalue_count:u32 chunk
+//! string   := length:u32 utf8:u8*
+//! ```
+//!
+//! Tags 3, 4 and 5 are `nil`, `false` and `true`. Only the top-level chunk
+//! is the script, so a function's `has_name` must be 1. Bit 0 of `flags` says a
+//! source hash follows, so a loader can spot a stale file.
+use std::fmt;
+
+use crate::{
+    chunk::{Chunk, LineEncoding, Value},
+    object::{Function, Obj},
+    value::ValueKind,
+    verifier::{verify, VerifyError},
+};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 1;
+
+const HAS_SOURCE_HASH: u8 = 1;
+
+/// How deeply function constants may nest. The reader recurses for each
+/// level, so without a limit a small file could overflow the stack.
+pub const MAX_FUNCTION_NESTING: usize = 256;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+const TAG_NIL: u8 = 3;
+const TAG_FALSE: u8 = 4;
+const TAG_TRUE: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    TrailingBytes,
+    UnknownConstantTag(u8),
+    InvalidUtf8,
+    Invalid {
+        offset: usize,
+        message: String,
+    },
+    /// The file is well formed but describes a chunk the VM can't run.
+    Unverified(VerifyError),
+    /// The file was compiled from a different version of its source.
+    StaleSource,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a compiled Lox file."),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {}.", version)
+            }
+            LoadError::UnexpectedEnd => write!(f, "Unexpected end of file."),
+            LoadError::TrailingBytes => write!(f, "Unexpected data after chunk."),
+            LoadError::UnknownConstantTag(tag) => write!(f, "Unknown constant tag {}.", tag),
+            LoadError::InvalidUtf8 => write!(f, "String constant is not valid UTF-8."),
+            LoadError::Invalid { offset, message } => write!(f, "{:04}: {}", offset, message),
+            LoadError::Unverified(error) => write!(f, "{}", error),
+            LoadError::StaleSource => {
+                write!(f, "Compiled from an older version of its source.")
+            }
+        }
+    }
+}
+
+/// Why a chunk couldn't be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveError {
+    /// A length or count doesn't fit in the format's `u32`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::TooLarge(len) => write!(f, "Length {} is too large to save.", len),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub source_hash: Option<u64>,
+}
+
+/// 64-bit FNV-1a, which unlike `std`'s hasher is stable across builds.
+pub fn source_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Chunk {
+    pub fn serialize(&self, source_hash: Option<u64>) -> Result<Vec<u8>, SaveError> {
+        let mut writer = Writer(MAGIC.to_vec());
+        writer.u16(VERSION);
+        match source_hash {
+            Some(hash) => {
+                writer.u8(HAS_SOURCE_HASH);
+                writer.0.extend(hash.to_le_bytes());
+            }
+            None => writer.u8(0),
+        }
+        writer.chunk(self)?;
+        Ok(writer.0)
+    }
+
+    /// Loads a chunk written by `serialize`, checking every instruction so a
+    /// corrupt or hand-crafted file can't make the VM read out of bounds.
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, LoadError> {
+        let mut reader = Reader {
+            bytes,
+            position: 0,
+            depth: 0,
+        };
+        reader.header()?;
+        let chunk = reader.chunk()?;
+        if reader.position != bytes.len() {
+            return Err(LoadError::TrailingBytes);
+        }
+        verify(&chunk).map_err(LoadError::Unverified)?;
+        Ok(chunk)
+    }
+}
+
+pub fn read_header(bytes: &[u8]) -> Result<Header, LoadError> {
+    Reader {
+        bytes,
+        position: 0,
+        depth: 0,
+    }
+    .header()
+}
+
+/// Checks that `bytes` were compiled from `source`. Files saved without a
+/// source hash pass, since there is nothing to compare.
+pub fn check_source(bytes: &[u8], source: &str) -> Result<(), LoadError> {
+    match read_header(bytes)?.source_hash {
+        Some(hash) if hash != source_hash(source) => Err(LoadError::StaleSource),
+        _ => Ok(()),
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, byte: u8) {
+        self.0.push(byte);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: usize) -> Result<(), SaveError> {
+        let value = u32::try_from(value).map_err(|_| SaveError::TooLarge(value))?;
+        self.0.extend(value.to_le_bytes());
+        Ok(())
+    }
+
+    fn string(&mut self, string: &str) -> Result<(), SaveError> {
+        self.u32(string.len())?;
+        self.0.extend(string.as_bytes());
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<(), SaveError> {
+        self.u32(chunk.constants.len())?;
+        for constant in chunk.constants.iter() {
+            self.constant(constant)?;
+        }
+        self.u32(chunk.code.len())?;
+        self.0.extend(chunk.code.iter());
+        self.u32(chunk.lines.len())?;
+        for (line, count) in chunk.lines.runs() {
+            self.u32(line)?;
+            self.u32(count)?;
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self, constant: &Value) -> Result<(), SaveError> {
+        match constant.kind() {
+            ValueKind::Nil => self.u8(TAG_NIL),
+            ValueKind::Bool(false) => self.u8(TAG_FALSE),
+            ValueKind::Bool(true) => self.u8(TAG_TRUE),
+            ValueKind::Number(number) => {
+                self.u8(TAG_NUMBER);
+                self.0.extend(number.to_le_bytes());
+            }
+            ValueKind::Obj(obj) => match obj {
+                Obj::String(string) => {
+                    self.u8(TAG_STRING);
+                    self.string(string)?;
+                }
+                Obj::Function(function) => {
+                    self.u8(TAG_FUNCTION);
+                    match &function.name {
+                        Some(name) => {
+                            self.u8(1);
+                            self.string(name)?;
+                        }
+                        None => self.u8(0),
+                    }
+                    self.u8(function.arity);
+                    self.u32(function.upvalue_count)?;
+                    self.chunk(&function.chunk)?;
+                }
+                // Only the compiler fills constant pools, and it never
+                // creates runtime objects.
+                _ => unreachable!("runtime object in constant pool"),
+            },
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// How many function constants enclose the chunk being read.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(LoadError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn string(&mut self) -> Result<&'a str, LoadError> {
+        let len = self.u32()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| LoadError::InvalidUtf8)
+    }
+
+    fn header(&mut self) -> Result<Header, LoadError> {
+        if self.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(LoadError::BadMagic);
+        }
+        let version = u16::from_le_bytes(self.array()?);
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let flags = self.u8()?;
+        let source_hash = if flags & HAS_SOURCE_HASH != 0 {
+            Some(u64::from_le_bytes(self.array()?))
+        } else {
+            None
+        };
+        Ok(Header {
+            version,
+            source_hash,
+        })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, LoadError> {
+        let mut chunk = Chunk::new_chunk();
+        let constants = self.u32()?;
+        for index in 0..constants {
+            let offset = self.position;
+            let constant = self.constant()?;
+            // The writer's pool is already deduplicated; a repeat would
+            // shift every later index.
+            if chunk.put_constant(constant) != Ok(index) {
+                return Err(invalid(offset, "Duplicate constant in pool."));
+            }
+        }
+        let len = self.u32()?;
+        chunk.code = self.take(len)?.to_vec();
+        let runs = self.u32()?;
+        let mut lines = LineEncoding::default();
+        for _ in 0..runs {
+            let line = self.u32()?;
+            let count = self.u32()?;
+            if count == 0 {
+                return Err(invalid(lines.code_len(), "Empty line table run."));
+            }
+            lines.push_run(line, count);
+        }
+        if lines.code_len() != chunk.code.len() {
+            return Err(invalid(
+                lines.code_len(),
+                "Line table doesn't match code length.",
+            ));
+        }
+        chunk.lines = lines;
+        Ok(chunk)
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        let offset = self.position;
+        Ok(match self.u8()? {
+            TAG_NIL => Value::nil(),
+            TAG_FALSE => Value::bool(false),
+            TAG_TRUE => Value::bool(true),
+            TAG_NUMBER => Value::number(f64::from_le_bytes(self.array()?)),
+            TAG_STRING => Value::string(self.string()?),
+            TAG_FUNCTION => {
+                if self.depth == MAX_FUNCTION_NESTING {
+                    return Err(invalid(offset, "Functions nest too deeply."));
+                }
+                // Only the top-level chunk is the script; every function
+                // the compiler emits has a name.
+                let offset = self.position;
+                if self.u8()? == 0 {
+                    return Err(invalid(offset, "Function constant has no name."));
+                }
+                let mut function = Function::new(Some(self.string()?));
+                function.arity = self.u8()?;
+                function.upvalue_count = self.u32()?;
+                self.depth += 1;
+                function.chunk = self.chunk()?;
+                self.depth -= 1;
+                Value::function(function)
+            }
+            tag => return Err(LoadError::UnknownConstantTag(tag)),
+        })
+    }
+}
+
+fn invalid(offset: usize, message: &str) -> LoadError {
+    LoadError::Invalid {
+        offset,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunk::OpCode, chunk_printer::print_chunk, lowering::Lowered};
+
+    fn disassemble(chunk: &Chunk) -> String {
+        let mut output = vec![];
+        print_chunk(chunk, &mut output, "test").unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    const SOURCE: &str = "fun make(n) { var i = 0; fun next() { i = i + n; return i; } return next; }\n\
+                          var counter = make(2);\nwhile (counter() < 10) print \"again\";\nprint -0.5;";
+
+    #[test]
+    fn round_trip() {
+        let chunk = SOURCE.lower().unwrap();
+        let bytes = chunk.serialize(Some(source_hash(SOURCE))).unwrap();
+        let loaded = Chunk::deserialize(&bytes).unwrap();
+        assert_eq!(disassemble(&loaded), disassemble(&chunk));
+        assert_eq!(loaded.lines, chunk.lines);
+        assert_eq!(
+            read_header(&bytes).unwrap(),
+            Header {
+                version: VERSION,
+                source_hash: Some(source_hash(SOURCE)),
+            }
+        );
+        let bytes = chunk.serialize(None).unwrap();
+        assert_eq!(read_header(&bytes).unwrap().source_hash, None);
+        assert_eq!(loaded.serialize(None).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let bytes = "print 1;".lower().unwrap().serialize(None).unwrap();
+        assert_eq!(Chunk::deserialize(b"LOX").err(), Some(LoadError::BadMagic));
+        let mut future = bytes.clone();
+        future[4] = 9;
+        assert_eq!(
+            Chunk::deserialize(&future).err(),
+            Some(LoadError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            Chunk::deserialize(&bytes[..bytes.len() - 1]).err(),
+            Some(LoadError::UnexpectedEnd)
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Chunk::deserialize(&trailing).err(),
+            Some(LoadError::TrailingBytes)
+        );
+    }
+
+    #[test]
+    fn verifies_code() {
+        let mut chunk = Chunk::new_chunk();
+        chunk.put_constant(1.0.into()).unwrap();
+        for op_code in [OpCode::Constant as u8, 1, OpCode::Return as u8] {
+            chunk.write_operand(op_code, 1);
+        }
+        let error = Chunk::deserialize(&chunk.serialize(None).unwrap()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "<script> 0000: Constant 1 out of range for pool of 1."
+        );
+        chunk.code[1] = 0;
+        assert!(Chunk::deserialize(&chunk.serialize(None).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_constants() {
+        let mut chunk = Chunk::new_chunk();
+        chunk.put_constant(1.0.into()).unwrap();
+        chunk.put_constant(2.0.into()).unwrap();
+        chunk.write_operand(OpCode::Nil as u8, 1);
+        chunk.write_operand(OpCode::Return as u8, 1);
+        let mut bytes = chunk.serialize(None).unwrap();
+        // The second constant's tag follows the header, the count and the
+        // first constant.
+        let offset = MAGIC.len() + 2 + 1 + 4 + 9;
+        bytes[offset + 1..offset + 9].copy_from_slice(&1.0f64.to_le_bytes());
+        assert_eq!(
+            Chunk::deserialize(&bytes).err(),
+            Some(invalid(offset, "Duplicate constant in pool."))
+        );
+    }
+
+    #[test]
+    fn checks_source_hash() {
+        let chunk = SOURCE.lower().unwrap();
+        let bytes = chunk.serialize(Some(source_hash(SOURCE))).unwrap();
+        assert_eq!(check_source(&bytes, SOURCE), Ok(()));
+        assert_eq!(
+            check_source(&bytes, "print 1;"),
+            Err(LoadError::StaleSource)
+        );
+        let bytes = chunk.serialize(None).unwrap();
+        assert_eq!(check_source(&bytes, "print 1;"), Ok(()));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn rejects_lengths_over_u32() {
+        let mut writer = Writer(vec![]);
+        let len = u32::MAX as usize + 1;
+        assert_eq!(writer.u32(len), Err(SaveError::TooLarge(len)));
+        assert!(writer.0.is_empty());
+    }
+
+    #[test]
+    fn rejects_inconsistent_line_tables() {
+        let mut bytes = "print 1;".lower().unwrap().serialize(None).unwrap();
+        let last = bytes.len() - 4;
+        bytes[last] += 1;
+        assert_eq!(
+            Chunk::deserialize(&bytes)
+                .err()
+                .map(|error| error.to_string()),
+            Some("0006: Line table doesn't match code length.".to_string())
+        );
+    }
+
+    /// A file whose script declares `depth` functions, each inside the one
+    /// before, written out flat so the test itself doesn't recurse.
+    fn nested_functions(depth: usize) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.push(0);
+        for _ in 0..depth {
+            bytes.extend(1u32.to_le_bytes());
+            bytes.extend([TAG_FUNCTION, 1]);
+            bytes.extend(1u32.to_le_bytes());
+            bytes.extend([b'f', 0]);
+            bytes.extend(0u32.to_le_bytes());
+        }
+        bytes.extend(0u32.to_le_bytes());
+        for _ in 0..=depth {
+            bytes.extend(2u32.to_le_bytes());
+            bytes.extend([OpCode::Nil as u8, OpCode::Return as u8]);
+            for word in [1u32, 1, 2] {
+                bytes.extend(word.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn limits_function_nesting() {
+        assert!(Chunk::deserialize(&nested_functions(MAX_FUNCTION_NESTING)).is_ok());
+        // The header, then 16 bytes per function before the next pool's
+        // first constant.
+        let offset = MAGIC.len() + 2 + 1 + MAX_FUNCTION_NESTING * 16 + 4;
+        for depth in [MAX_FUNCTION_NESTING + 1, 2000] {
+            assert_eq!(
+                Chunk::deserialize(&nested_functions(depth))
+                    .err()
+                    .map(|error| error.to_string()),
+                Some(format!("{:04}: Functions nest too deeply.", offset))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_nameless_functions() {
+        let mut bytes = nested_functions(1);
+        // The function's has_name byte, after the header and pool count.
+        let offset = MAGIC.len() + 2 + 1 + 4 + 1;
+        bytes[offset] = 0;
+        assert_eq!(
+            Chunk::deserialize(&bytes)
+                .err()
+                .map(|error| error.to_string()),
+            Some(format!("{:04}: Function constant has no name.", offset))
+        );
+    }
+}
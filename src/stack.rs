@@ -0,0 +1,207 @@
+use std::ops::{Index, IndexMut};
+
+use crate::chunk::{OpCode, Value};
+
+/// Returned by `Stack::push` when the stack is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackOverflow;
+
+/// The instruction being executed, recorded in debug builds so an underflow
+/// panic can say which instruction caused it.
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub op_code: OpCode,
+    pub offset: usize,
+    pub line: usize,
+}
+
+/// The VM's value stack: allocated once up front and never grown, so
+/// runaway recursion is reported instead of exhausting memory.
+pub struct Stack {
+    values: Vec<Value>,
+    max: usize,
+    #[cfg(debug_assertions)]
+    location: Option<Location>,
+}
+
+impl Stack {
+    pub fn new(max: usize) -> Self {
+        Self {
+            values: Vec::with_capacity(max),
+            max,
+            #[cfg(debug_assertions)]
+            location: None,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_location(&mut self, location: Location) {
+        self.location = Some(location);
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn check_depth(&self, needed: usize) {
+        if self.values.len() < needed {
+            match self.location {
+                Some(Location {
+                    op_code,
+                    offset,
+                    line,
+                }) => panic!(
+                    "stack underflow: {} at offset {} (line {}) needs {} values but has {}",
+                    op_code,
+                    offset,
+                    line,
+                    needed,
+                    self.values.len()
+                ),
+                None => panic!(
+                    "stack underflow: needs {} values but has {}",
+                    needed,
+                    self.values.len()
+                ),
+            }
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn check_depth(&self, _: usize) {}
+
+    #[inline]
+    pub fn push(&mut self, value: Value) -> Result<(), StackOverflow> {
+        if self.values.len() == self.max {
+            return overflow();
+        }
+        self.values.push(value);
+        Ok(())
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn pop(&mut self) -> Value {
+        self.check_depth(1);
+        self.values.pop().unwrap()
+    }
+
+    /// Drops the top `count` values.
+    #[inline]
+    #[track_caller]
+    pub fn pop_n(&mut self, count: usize) {
+        self.check_depth(count);
+        self.values.truncate(self.values.len() - count);
+    }
+
+    /// Pops `count` values and pushes `value` in their place, which can't
+    /// overflow.
+    #[inline]
+    #[track_caller]
+    pub fn replace(&mut self, count: usize, value: Value) {
+        self.check_depth(count);
+        self.values.truncate(self.values.len() - count);
+        self.values.push(value);
+    }
+
+    /// The value `distance` slots below the top; `peek(0)` is the top.
+    #[inline]
+    #[track_caller]
+    pub fn peek(&self, distance: usize) -> &Value {
+        self.check_depth(distance + 1);
+        &self.values[self.values.len() - 1 - distance]
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn peek_mut(&mut self, distance: usize) -> &mut Value {
+        self.check_depth(distance + 1);
+        let top = self.values.len() - 1;
+        &mut self.values[top - distance]
+    }
+
+    /// The top `count` values, bottom first.
+    #[track_caller]
+    pub fn top(&self, count: usize) -> &[Value] {
+        self.check_depth(count);
+        &self.values[self.values.len() - count..]
+    }
+
+    /// Drops everything from slot `len` up.
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+/// Kept out of line so the compiler lays out `push` for the common case;
+/// an inline `Err` here made the whole dispatch loop markedly slower.
+#[cold]
+fn overflow() -> Result<(), StackOverflow> {
+    Err(StackOverflow)
+}
+
+impl Index<usize> for Stack {
+    type Output = Value;
+
+    fn index(&self, slot: usize) -> &Value {
+        &self.values[slot]
+    }
+}
+
+impl IndexMut<usize> for Stack {
+    fn index_mut(&mut self, slot: usize) -> &mut Value {
+        &mut self.values[slot]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_and_peek() {
+        let mut stack = Stack::new(3);
+        for n in 0..3 {
+            stack.push((n as f64).into()).unwrap();
+        }
+        assert_eq!(stack.push(Value::nil()), Err(StackOverflow));
+        assert_eq!(*stack.peek(0), Value::number(2.0));
+        assert_eq!(*stack.peek(2), Value::number(0.0));
+        *stack.peek_mut(1) = Value::nil();
+        assert_eq!(stack[1], Value::nil());
+        stack.replace(2, Value::bool(true));
+        assert_eq!(stack.peek(0), &Value::bool(true));
+        stack.pop_n(1);
+        assert_eq!(stack.pop(), Value::number(0.0));
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stack underflow: Add at offset 4 (line 2) needs 2 values but has 1")]
+    fn underflow_names_instruction() {
+        let mut stack = Stack::new(8);
+        stack.push(Value::nil()).unwrap();
+        stack.set_location(Location {
+            op_code: OpCode::Add,
+            offset: 4,
+            line: 2,
+        });
+        stack.peek(1);
+    }
+}
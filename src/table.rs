@@ -0,0 +1,308 @@
+use std::{mem::size_of, rc::Rc};
+
+use crate::{chunk::Value, heap, object::Obj};
+
+/// Grow once more than three quarters of the slots are used, counting
+/// tombstones, so probe sequences always end at an empty slot.
+const MAX_LOAD_NUMERATOR: usize = 3;
+const MAX_LOAD_DENOMINATOR: usize = 4;
+const MIN_CAPACITY: usize = 8;
+
+#[derive(Clone)]
+enum Entry {
+    Empty,
+    /// A deleted entry. Lookups probe past it; inserts may reuse it.
+    Tombstone,
+    Occupied(Rc<Obj>, Value),
+}
+
+/// An open-addressing hash table with linear probing, keyed on interned
+/// strings. Every key is an `Obj::String` from `object::intern`, so keys
+/// compare by pointer and hash with the hash cached in the string.
+#[derive(Default)]
+pub struct Table {
+    /// Always empty or a power of two long.
+    entries: Vec<Entry>,
+    /// Occupied entries plus tombstones.
+    used: usize,
+    len: usize,
+}
+
+fn hash(key: &Obj) -> u32 {
+    match key {
+        Obj::String(string) => string.hash(),
+        _ => unreachable!("table keys are strings"),
+    }
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the next insert of a new key would grow the table.
+    pub fn is_full(&self) -> bool {
+        (self.used + 1) * MAX_LOAD_DENOMINATOR > self.entries.len() * MAX_LOAD_NUMERATOR
+    }
+
+    /// The slot holding `key`, or else the slot it would be inserted in:
+    /// the first tombstone on its probe sequence if there is one.
+    fn find_slot(&self, key: &Obj) -> usize {
+        let mask = self.entries.len() - 1;
+        let mut index = hash(key) as usize & mask;
+        let mut tombstone = None;
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => return tombstone.unwrap_or(index),
+                Entry::Tombstone => {
+                    tombstone.get_or_insert(index);
+                }
+                Entry::Occupied(existing, _) if std::ptr::eq(existing.as_ref(), key) => {
+                    return index
+                }
+                Entry::Occupied(..) => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    pub fn get(&self, key: &Obj) -> Option<&Value> {
+        if self.len == 0 {
+            return None;
+        }
+        match &self.entries[self.find_slot(key)] {
+            Entry::Occupied(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &Obj) -> Option<&mut Value> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = self.find_slot(key);
+        match &mut self.entries[index] {
+            Entry::Occupied(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Inserts or overwrites `key`, returning whether it was new.
+    pub fn set(&mut self, key: Rc<Obj>, value: Value) -> bool {
+        debug_assert!(
+            matches!(&*key, Obj::String(string) if string.is_interned()),
+            "table keys are interned strings"
+        );
+        if self.is_full() {
+            let capacity = (self.entries.len() * 2).max(MIN_CAPACITY);
+            self.rebuild(capacity);
+        }
+        let index = self.find_slot(&key);
+        let entry = &mut self.entries[index];
+        let is_new = !matches!(entry, Entry::Occupied(..));
+        if is_new {
+            self.len += 1;
+            if matches!(entry, Entry::Empty) {
+                self.used += 1;
+            }
+        }
+        *entry = Entry::Occupied(key, value);
+        is_new
+    }
+
+    /// Removes `key`, leaving a tombstone so longer probe sequences through
+    /// its slot still work. Returns whether it was present.
+    pub fn delete(&mut self, key: &Obj) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        let index = self.find_slot(key);
+        let entry = &mut self.entries[index];
+        if !matches!(entry, Entry::Occupied(..)) {
+            return false;
+        }
+        *entry = Entry::Tombstone;
+        self.len -= 1;
+        true
+    }
+
+    /// Looks a key up by contents rather than identity, which is how a new
+    /// string finds its interned copy.
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<&Rc<Obj>> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.entries.len() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => return None,
+                Entry::Occupied(key, _) => match key.as_ref() {
+                    Obj::String(string) if string.hash() == hash && string.as_str() == chars => {
+                        return Some(key)
+                    }
+                    _ => {}
+                },
+                Entry::Tombstone => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Copies every entry into `to`, overwriting keys it already has.
+    pub fn add_all(&self, to: &mut Table) {
+        for (key, value) in self.iter() {
+            to.set(key.clone(), value.clone());
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Rc<Obj>, &Value)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Occupied(key, value) => Some((key, value)),
+            _ => None,
+        })
+    }
+
+    /// Drops the entries `keep` rejects. The table is rebuilt without them
+    /// or any tombstones, and grows if it would still be over half full so
+    /// that a caller sweeping whenever the table fills up stays amortized
+    /// constant time per insert.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Rc<Obj>, &Value) -> bool) {
+        for entry in self.entries.iter_mut() {
+            if let Entry::Occupied(key, value) = entry {
+                if !keep(key, value) {
+                    *entry = Entry::Tombstone;
+                    self.len -= 1;
+                }
+            }
+        }
+        let mut capacity = self.entries.len();
+        if self.len * 2 > capacity {
+            capacity *= 2;
+        }
+        self.rebuild(capacity);
+    }
+
+    fn rebuild(&mut self, capacity: usize) {
+        heap::allocated(capacity * size_of::<Entry>());
+        let entries = std::mem::replace(&mut self.entries, vec![Entry::Empty; capacity]);
+        heap::freed(entries.len() * size_of::<Entry>());
+        self.used = self.len;
+        for entry in entries {
+            if let Entry::Occupied(key, value) = entry {
+                let index = self.find_slot(&key);
+                self.entries[index] = Entry::Occupied(key, value);
+            }
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        heap::freed(self.entries.len() * size_of::<Entry>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::intern;
+
+    #[test]
+    fn set_get_overwrite() {
+        let mut table = Table::new();
+        let (a, b) = (intern("a"), intern("b"));
+        assert_eq!(table.get(&a), None);
+        assert!(table.set(a.clone(), Value::number(1.0)));
+        assert!(!table.set(a.clone(), Value::number(2.0)));
+        assert!(table.set(b.clone(), Value::nil()));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(&a), Some(&Value::number(2.0)));
+        *table.get_mut(&b).unwrap() = Value::bool(true);
+        assert_eq!(table.get(&b), Some(&Value::bool(true)));
+    }
+
+    #[test]
+    fn grows() {
+        let mut table = Table::new();
+        let keys: Vec<_> = (0..1000).map(|n| intern(&n.to_string())).collect();
+        for (n, key) in keys.iter().enumerate() {
+            table.set(key.clone(), Value::number(n as f64));
+        }
+        assert_eq!(table.len(), 1000);
+        assert!(table.entries.len().is_power_of_two());
+        for (n, key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(&Value::number(n as f64)));
+        }
+    }
+
+    #[test]
+    fn delete_leaves_tombstones() {
+        let mut table = Table::new();
+        let keys: Vec<_> = (0..6).map(|n| intern(&format!("k{}", n))).collect();
+        for key in keys.iter() {
+            table.set(key.clone(), Value::nil());
+        }
+        // Deleting every other key must not cut off the probe sequences of
+        // the keys after it.
+        for key in keys.iter().step_by(2) {
+            assert!(table.delete(key));
+            assert!(!table.delete(key));
+        }
+        assert_eq!(table.len(), 3);
+        for (n, key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key).is_some(), n % 2 == 1);
+        }
+        // Reinserting reuses tombstones instead of using up empty slots.
+        let used = table.used;
+        for key in keys.iter().step_by(2) {
+            assert!(table.set(key.clone(), Value::nil()));
+        }
+        assert_eq!(table.used, used);
+        assert_eq!(table.len(), 6);
+    }
+
+    #[test]
+    fn find_string() {
+        let mut table = Table::new();
+        let key = intern("name");
+        table.set(key.clone(), Value::nil());
+        let Obj::String(string) = key.as_ref() else {
+            unreachable!()
+        };
+        let found = table.find_string("name", string.hash()).unwrap();
+        assert!(Rc::ptr_eq(found, &key));
+        assert!(table.find_string("other", string.hash()).is_none());
+        table.delete(&key);
+        assert!(table.find_string("name", string.hash()).is_none());
+    }
+
+    #[test]
+    fn add_all_and_retain() {
+        let (a, b, c) = (intern("a"), intern("b"), intern("c"));
+        let mut from = Table::new();
+        from.set(a.clone(), Value::number(1.0));
+        from.set(b.clone(), Value::number(2.0));
+        let mut to = Table::new();
+        to.set(b.clone(), Value::nil());
+        to.set(c.clone(), Value::nil());
+        from.add_all(&mut to);
+        assert_eq!(to.len(), 3);
+        assert_eq!(to.get(&b), Some(&Value::number(2.0)));
+        to.retain(|_, value| *value != Value::nil());
+        assert_eq!(to.len(), 2);
+        assert_eq!(to.used, 2);
+        assert_eq!(to.get(&c), None);
+        let mut keys: Vec<_> = to.iter().map(|(key, _)| key.to_string()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b"]);
+    }
+}
@@ -1,10 +1,43 @@
 use super::token_type::TokenType;
 
-pub type LineNo = u16;
+pub type LineNo = u32;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Token<'a> {
     pub line: LineNo,
+    pub offset: usize,
     pub content: &'a str,
     pub token_type: TokenType,
 }
+
+/// Location of a token or syntax node in the source: the line it starts on
+/// and the byte range it covers.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub line: LineNo,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            line: self.line.min(other.line),
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl<'a> Token<'a> {
+    pub fn span(&self) -> Span {
+        // String literal content excludes its quotes; the span includes them.
+        let quotes = (self.token_type == TokenType::StringLiteral) as usize;
+        Span {
+            line: self.line,
+            start: self.offset - quotes,
+            end: self.offset + self.content.len() + quotes,
+        }
+    }
+}
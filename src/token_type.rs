@@ -41,5 +41,6 @@ pub enum TokenType {
     This,
     Var,
     While,
+    Comment,
     ErrorToken,
 }
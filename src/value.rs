@@ -0,0 +1,330 @@
+use std::{any::Any, fmt};
+
+use crate::object::{intern, Function, Obj, Opaque};
+
+// Both representations have the same API, so code that builds with one
+// builds with the other.
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed;
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged;
+
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxed::Value;
+#[cfg(not(feature = "nan-boxing"))]
+pub use tagged::Value;
+
+/// A value taken apart for matching on, from `Value::kind`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ValueKind<'a> {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(&'a Obj),
+}
+
+/// Values, whichever representation is built, stay on their thread: sharing
+/// them would share the non-atomic counts of the `Rc`s they hold.
+///
+/// ```compile_fail
+/// let value = rlox::Value::from("shared");
+/// std::thread::spawn(move || drop(value));
+/// ```
+///
+/// Nor can hosts reach into either representation; they go through the
+/// constructors, accessors and conversions below.
+///
+/// ```compile_fail
+/// let value = rlox::Value::Nil;
+/// ```
+impl Value {
+    pub fn string(chars: &str) -> Self {
+        Value::obj(intern(chars))
+    }
+    pub(crate) fn function(function: Function) -> Self {
+        Value::obj(Obj::Function(function).alloc())
+    }
+    /// Wraps a host value for passing through Lox code.
+    pub fn opaque<T: Any>(value: T) -> Self {
+        let opaque = Opaque {
+            type_name: std::any::type_name::<T>(),
+            value: Box::new(value),
+        };
+        Value::obj(Obj::Opaque(opaque).alloc())
+    }
+    pub fn as_number(&self) -> Option<f64> {
+        match self.kind() {
+            ValueKind::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+    pub(crate) fn as_obj(&self) -> Option<&Obj> {
+        match self.kind() {
+            ValueKind::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self.as_obj()? {
+            Obj::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+    /// The host value inside an `opaque` value, if it holds a `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        match self.as_obj()? {
+            Obj::Opaque(opaque) => opaque.value.downcast_ref(),
+            _ => None,
+        }
+    }
+    pub fn is_falsey(&self) -> bool {
+        matches!(self.kind(), ValueKind::Nil | ValueKind::Bool(false))
+    }
+
+    /// The name Lox's `type()` gives this value.
+    pub fn type_name(&self) -> &'static str {
+        match self.kind() {
+            ValueKind::Nil => "nil",
+            ValueKind::Bool(_) => "boolean",
+            ValueKind::Number(_) => "number",
+            ValueKind::Obj(obj) => match obj {
+                Obj::String(_) => "string",
+                Obj::Class(_) => "class",
+                Obj::Instance(_) => "instance",
+                Obj::Opaque(_) => "opaque",
+                Obj::Function(_) | Obj::Closure(_) | Obj::BoundMethod(_) | Obj::Native(_) => {
+                    "function"
+                }
+            },
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.kind(), other.kind()) {
+            (ValueKind::Nil, ValueKind::Nil) => true,
+            (ValueKind::Bool(a), ValueKind::Bool(b)) => a == b,
+            (ValueKind::Number(a), ValueKind::Number(b)) => a == b,
+            // Equal interned strings are the same object, but a string that
+            // skipped the pool has to be compared by contents.
+            (ValueKind::Obj(Obj::String(a)), ValueKind::Obj(Obj::String(b)))
+                if !(a.is_interned() && b.is_interned()) =>
+            {
+                a.as_str() == b.as_str()
+            }
+            (ValueKind::Obj(a), ValueKind::Obj(b)) => std::ptr::eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::bool(b)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::number(n.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(chars: &str) -> Self {
+        Value::string(chars)
+    }
+}
+
+impl From<String> for Value {
+    fn from(chars: String) -> Self {
+        Value::string(&chars)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::nil()
+    }
+}
+
+/// `None` becomes `nil`.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::nil(), Into::into)
+    }
+}
+
+/// A `TryFrom<Value>` conversion given a value of the wrong type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a {} but got {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+macro_rules! try_from_value {
+    ($type:ty, $expected:literal, $value:ident => $convert:expr) => {
+        impl TryFrom<Value> for $type {
+            type Error = ConversionError;
+
+            fn try_from($value: Value) -> Result<Self, ConversionError> {
+                $convert.ok_or(ConversionError {
+                    expected: $expected,
+                    found: $value.type_name(),
+                })
+            }
+        }
+    };
+}
+
+try_from_value!(f64, "number", value => value.as_number());
+try_from_value!(String, "string", value => value.as_str().map(str::to_string));
+try_from_value!(bool, "boolean", value => match value.kind() {
+    ValueKind::Bool(b) => Some(b),
+    _ => None,
+});
+try_from_value!((), "nil", value => matches!(value.kind(), ValueKind::Nil).then_some(()));
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind() {
+            ValueKind::Nil => write!(f, "nil"),
+            ValueKind::Bool(b) => write!(f, "{}", b),
+            ValueKind::Number(n) => write!(f, "{}", n),
+            ValueKind::Obj(obj) => write!(f, "{}", obj),
+        }
+    }
+}
+
+/// Disassembly form: numbers keep their decimal point so constants read
+/// unambiguously in chunk dumps.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind() {
+            ValueKind::Number(n) => write!(f, "{:?}", n),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn equality() {
+        assert_eq!(Value::string("a"), Value::string("a"));
+        assert_ne!(Value::string("a"), Value::string("b"));
+        assert_ne!(Value::nil(), Value::bool(false));
+        assert_eq!(Value::number(1.0), 1.0.into());
+        assert_ne!(Value::number(f64::NAN), Value::number(f64::NAN));
+        assert_eq!(Value::number(0.0), Value::number(-0.0));
+    }
+
+    #[test]
+    fn strings_are_interned() {
+        let concatenated = ["a", "b"].concat();
+        let (a, b) = (Value::string("ab"), Value::string(&concatenated));
+        assert!(Rc::ptr_eq(&a.to_obj().unwrap(), &b.to_obj().unwrap()));
+    }
+
+    #[test]
+    fn uninterned_strings_compare_by_contents() {
+        let joined = Value::obj(crate::object::uninterned(["a", "b"].concat()));
+        assert_eq!(joined, Value::string("ab"));
+        assert_eq!(Value::string("ab"), joined);
+        assert_ne!(joined, Value::string("ba"));
+        let again = Value::obj(crate::object::uninterned("ab".to_string()));
+        assert_eq!(joined, again);
+    }
+
+    #[test]
+    fn round_trips() {
+        for n in [0.0, -0.0, 1.5, -2.0, f64::INFINITY, f64::MIN_POSITIVE] {
+            assert_eq!(
+                Value::number(n).as_number().map(f64::to_bits),
+                Some(n.to_bits())
+            );
+        }
+        // A NaN with a payload that looks like a boxed object must stay a
+        // number.
+        let nan = f64::from_bits(0xffff_0000_0000_1234);
+        assert!(Value::number(nan).as_number().unwrap().is_nan());
+        assert!(matches!(Value::nil().kind(), ValueKind::Nil));
+        assert!(matches!(Value::bool(true).kind(), ValueKind::Bool(true)));
+        assert!(matches!(Value::bool(false).kind(), ValueKind::Bool(false)));
+        assert_eq!(Value::string("s").as_str(), Some("s"));
+    }
+
+    #[test]
+    fn reference_counts() {
+        let obj = Obj::Function(Function::new(Some("f"))).alloc();
+        let value = Value::obj(obj.clone());
+        assert_eq!(Rc::strong_count(&obj), 2);
+        let copy = value.clone();
+        assert_eq!(Rc::strong_count(&obj), 3);
+        assert!(Rc::ptr_eq(&copy.to_obj().unwrap(), &obj));
+        drop((value, copy));
+        assert_eq!(Rc::strong_count(&obj), 1);
+    }
+
+    #[test]
+    fn size() {
+        let expected = if cfg!(feature = "nan-boxing") { 8 } else { 16 };
+        assert_eq!(std::mem::size_of::<Value>(), expected);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Value::from(2), Value::number(2.0));
+        assert_eq!(Value::from("s"), Value::string("s"));
+        assert_eq!(Value::from(None::<f64>), Value::nil());
+        assert_eq!(Value::from(Some(true)), Value::bool(true));
+        assert_eq!(f64::try_from(Value::number(1.5)), Ok(1.5));
+        assert_eq!(String::try_from(Value::from("s")), Ok("s".to_string()));
+        assert_eq!(bool::try_from(Value::bool(false)), Ok(false));
+        assert_eq!(<()>::try_from(Value::nil()), Ok(()));
+        let error = f64::try_from(Value::from("1")).unwrap_err();
+        assert_eq!(error.to_string(), "expected a number but got string");
+    }
+
+    #[test]
+    fn opaque() {
+        struct Handle(u32);
+        let value = Value::opaque(Handle(7));
+        assert_eq!(
+            value.downcast_ref::<Handle>().map(|handle| handle.0),
+            Some(7)
+        );
+        assert!(value.downcast_ref::<u32>().is_none());
+        assert!(Value::number(7.0).downcast_ref::<Handle>().is_none());
+        assert_eq!(value.type_name(), "opaque");
+        assert_eq!(value, value.clone());
+        assert_ne!(value, Value::opaque(Handle(7)));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::number(3.0).to_string(), "3");
+        assert_eq!(format!("{:?}", Value::number(3.0)), "3.0");
+        assert_eq!(Value::nil().to_string(), "nil");
+        assert_eq!(Value::string("hi").to_string(), "hi");
+    }
+}
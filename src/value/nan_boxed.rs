@@ -0,0 +1,114 @@
+use std::{marker::PhantomData, mem::ManuallyDrop, rc::Rc};
+
+use super::ValueKind;
+use crate::object::Obj;
+
+// Numbers are stored as their own bits. Everything else hides in the
+// payload of a quiet NaN that arithmetic never produces: the quiet bit, the
+// bit above it and all exponent bits set. Objects also set the sign bit and
+// keep their pointer in the low 48 bits; nil and the booleans are small
+// tags.
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const OBJ: u64 = SIGN_BIT | QNAN;
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+/// A Lox value NaN-boxed into 8 bytes. An object value owns one strong
+/// count of its `Rc`, taken by `obj` and `clone` and given back on drop.
+/// The marker makes it `!Send` and `!Sync` like the `Rc` it stands for.
+pub struct Value(u64, PhantomData<Rc<Obj>>);
+
+impl Value {
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        Value(bits, PhantomData)
+    }
+    #[inline]
+    pub fn nil() -> Self {
+        Value::from_bits(NIL)
+    }
+    #[inline]
+    pub fn bool(b: bool) -> Self {
+        Value::from_bits(if b { TRUE } else { FALSE })
+    }
+    #[inline]
+    pub fn number(n: f64) -> Self {
+        // Any NaN could carry a payload that reads as a tag, so they all
+        // become the one NaN arithmetic produces.
+        if n.is_nan() {
+            Value::from_bits(f64::NAN.to_bits())
+        } else {
+            Value::from_bits(n.to_bits())
+        }
+    }
+    #[inline]
+    pub(crate) fn obj(obj: Rc<Obj>) -> Self {
+        let address = Rc::into_raw(obj) as u64;
+        // A pointer using the tag bits would read back as another object.
+        assert_eq!(address & OBJ, 0, "pointer doesn't fit in 48 bits");
+        Value::from_bits(OBJ | address)
+    }
+    #[inline]
+    fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+    #[inline]
+    fn is_obj(&self) -> bool {
+        self.0 & OBJ == OBJ
+    }
+    #[inline]
+    fn pointer(&self) -> *const Obj {
+        (self.0 & !OBJ) as *const Obj
+    }
+    #[inline]
+    pub(crate) fn kind(&self) -> ValueKind<'_> {
+        if self.is_number() {
+            ValueKind::Number(f64::from_bits(self.0))
+        } else if self.is_obj() {
+            // SAFETY: this value holds a strong count, so the object lives
+            // at least as long as the borrow of `self`.
+            ValueKind::Obj(unsafe { &*self.pointer() })
+        } else {
+            match self.0 {
+                NIL => ValueKind::Nil,
+                FALSE => ValueKind::Bool(false),
+                TRUE => ValueKind::Bool(true),
+                _ => unreachable!("invalid NaN-boxed value {:#x}", self.0),
+            }
+        }
+    }
+    /// Another handle to the object this value refers to.
+    #[inline]
+    pub(crate) fn to_obj(&self) -> Option<Rc<Obj>> {
+        if !self.is_obj() {
+            return None;
+        }
+        // SAFETY: the pointer came from `Rc::into_raw` and this value still
+        // owns its count; the `ManuallyDrop` leaves that count in place.
+        let obj = ManuallyDrop::new(unsafe { Rc::from_raw(self.pointer()) });
+        Some(Rc::clone(&obj))
+    }
+}
+
+impl Clone for Value {
+    #[inline]
+    fn clone(&self) -> Self {
+        if self.is_obj() {
+            // SAFETY: as for `to_obj`; the new value owns the new count.
+            unsafe { Rc::increment_strong_count(self.pointer()) };
+        }
+        Value::from_bits(self.0)
+    }
+}
+
+impl Drop for Value {
+    #[inline]
+    fn drop(&mut self) {
+        if self.is_obj() {
+            // SAFETY: gives back the count this value owns.
+            unsafe { Rc::decrement_strong_count(self.pointer()) };
+        }
+    }
+}
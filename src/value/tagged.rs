@@ -0,0 +1,67 @@
+use std::rc::Rc;
+
+use super::ValueKind;
+use crate::object::Obj;
+
+/// A Lox value as a tagged enum: 16 bytes.
+///
+/// The enum is private so the public API is the same as under nan-boxing,
+/// where its variants don't exist.
+pub struct Value(Repr);
+
+enum Repr {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(Rc<Obj>),
+}
+
+impl Clone for Value {
+    #[inline]
+    fn clone(&self) -> Self {
+        match &self.0 {
+            Repr::Obj(obj) => Value(Repr::Obj(obj.clone())),
+            // SAFETY: the other variants own nothing, so a bitwise copy is
+            // an independent value. Rebuilding them variant by variant
+            // copies the bool's padding byte by byte, which made the
+            // dispatch loop half again as slow.
+            _ => unsafe { std::ptr::read(self) },
+        }
+    }
+}
+
+impl Value {
+    #[inline]
+    pub fn nil() -> Self {
+        Value(Repr::Nil)
+    }
+    #[inline]
+    pub fn bool(b: bool) -> Self {
+        Value(Repr::Bool(b))
+    }
+    #[inline]
+    pub fn number(n: f64) -> Self {
+        Value(Repr::Number(n))
+    }
+    #[inline]
+    pub(crate) fn obj(obj: Rc<Obj>) -> Self {
+        Value(Repr::Obj(obj))
+    }
+    #[inline]
+    pub(crate) fn kind(&self) -> ValueKind<'_> {
+        match &self.0 {
+            Repr::Nil => ValueKind::Nil,
+            Repr::Bool(b) => ValueKind::Bool(*b),
+            Repr::Number(n) => ValueKind::Number(*n),
+            Repr::Obj(obj) => ValueKind::Obj(obj),
+        }
+    }
+    /// Another handle to the object this value refers to.
+    #[inline]
+    pub(crate) fn to_obj(&self) -> Option<Rc<Obj>> {
+        match &self.0 {
+            Repr::Obj(obj) => Some(obj.clone()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,709 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+};
+
+use crate::{
+    chunk::{Chunk, OpCode, Value},
+    object::{Function, Obj},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    UnknownOpcode(u8),
+    TruncatedInstruction(OpCode),
+    ConstantOutOfRange { index: usize, len: usize },
+    ExpectedString { index: usize },
+    ExpectedFunction { index: usize },
+    LocalOutOfRange { slot: usize, depth: usize },
+    UpvalueOutOfRange { index: usize, count: usize },
+    InvalidUpvalueDescriptor(u8),
+    JumpOutOfBounds { target: isize },
+    JumpIntoInstruction { target: usize },
+    StackUnderflow { needed: usize, depth: usize },
+    StackMismatch { expected: usize, found: usize },
+    CapturedLocalPopped { slot: usize },
+    FallsOffEnd,
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VerifyErrorKind::*;
+        match self {
+            UnknownOpcode(byte) => write!(f, "Unknown opcode {}.", byte),
+            TruncatedInstruction(op_code) => write!(f, "Truncated {} instruction.", op_code),
+            ConstantOutOfRange { index, len } => {
+                write!(f, "Constant {} out of range for pool of {}.", index, len)
+            }
+            ExpectedString { index } => write!(f, "Constant {} is not a string.", index),
+            ExpectedFunction { index } => write!(f, "Constant {} is not a function.", index),
+            LocalOutOfRange { slot, depth } => write!(
+                f,
+                "Local slot {} out of range for stack depth {}.",
+                slot, depth
+            ),
+            UpvalueOutOfRange { index, count } => {
+                write!(f, "Upvalue {} out of range for {} upvalues.", index, count)
+            }
+            InvalidUpvalueDescriptor(byte) => write!(f, "Invalid upvalue descriptor {}.", byte),
+            JumpOutOfBounds { target } => write!(f, "Jump to {} is out of bounds.", target),
+            JumpIntoInstruction { target } => {
+                write!(f, "Jump to {} lands inside an instruction.", target)
+            }
+            StackUnderflow { needed, depth } => write!(
+                f,
+                "Stack underflow: needs {} values but depth is {}.",
+                needed, depth
+            ),
+            StackMismatch { expected, found } => write!(
+                f,
+                "Stack depth {} here disagrees with {} on another path.",
+                found, expected
+            ),
+            CapturedLocalPopped { slot } => {
+                write!(
+                    f,
+                    "Local slot {} is popped without closing its upvalue.",
+                    slot
+                )
+            }
+            FallsOffEnd => write!(f, "Execution runs past the end of the chunk."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// `<script>` or `<fn name>` of the chunk the error is in.
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:04}: {}", self.function, self.offset, self.kind)
+    }
+}
+
+/// Stack slot zero holds the callee, so a function's locals start at one.
+const RESERVED_SLOTS: usize = 1;
+
+/// Checks a chunk and every function in its constant pool before the VM
+/// runs any of it, returning the deepest the value stack can get.
+pub fn verify(chunk: &Chunk) -> Result<usize, VerifyError> {
+    let max_depth = Verifier {
+        chunk,
+        function: &Function::new(None),
+        script: true,
+    }
+    .verify()?;
+    // Nested functions come off a worklist rather than recursion, so no
+    // depth of nesting can overflow the stack, and each is checked once
+    // however many pools share it.
+    let mut pending: Vec<_> = functions(chunk).collect();
+    let mut seen = HashSet::new();
+    while let Some(function) = pending.pop() {
+        if seen.insert(function as *const Function) {
+            Verifier {
+                chunk: &function.chunk,
+                function,
+                script: false,
+            }
+            .verify()?;
+            pending.extend(functions(&function.chunk));
+        }
+    }
+    Ok(max_depth)
+}
+
+fn functions(chunk: &Chunk) -> impl Iterator<Item = &Function> {
+    chunk
+        .constants
+        .iter()
+        .filter_map(|constant| match constant.as_obj() {
+            Some(Obj::Function(function)) => Some(function),
+            _ => None,
+        })
+}
+
+struct Verifier<'a> {
+    chunk: &'a Chunk,
+    function: &'a Function,
+    /// Whether this is the chunk `verify` was given rather than one in its
+    /// constant pool, which is the only one the VM runs as the script.
+    script: bool,
+}
+
+/// How an instruction passes control on.
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Return,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.function.to_string(),
+            offset,
+            kind,
+        }
+    }
+
+    fn verify(&self) -> Result<usize, VerifyError> {
+        let code = &self.chunk.code;
+        let mut boundaries = vec![false; code.len()];
+        let mut offset = 0;
+        while offset < code.len() {
+            boundaries[offset] = true;
+            offset += self.instruction_len(offset)?;
+        }
+        let mut max_depth = RESERVED_SLOTS + self.function.arity as usize;
+        // The stack depth before each instruction, and the local slots that
+        // may have open upvalues there on some path.
+        let mut states: Vec<Option<(usize, BTreeSet<usize>)>> = vec![None; code.len()];
+        let mut pending = vec![(0, max_depth, BTreeSet::new())];
+        while let Some((offset, depth, captured)) = pending.pop() {
+            if offset >= code.len() {
+                return Err(self.error(offset, VerifyErrorKind::FallsOffEnd));
+            }
+            let mut captured = match &mut states[offset] {
+                Some((expected, _)) if *expected != depth => {
+                    return Err(self.error(
+                        offset,
+                        VerifyErrorKind::StackMismatch {
+                            expected: *expected,
+                            found: depth,
+                        },
+                    ))
+                }
+                Some((_, seen)) if captured.is_subset(seen) => continue,
+                Some((_, seen)) => {
+                    seen.extend(captured);
+                    seen.clone()
+                }
+                None => {
+                    states[offset] = Some((depth, captured.clone()));
+                    captured
+                }
+            };
+            let (after, flow) = self.instruction(offset, depth)?;
+            max_depth = max_depth.max(after);
+            self.track_captures(offset, depth, after, &mut captured)?;
+            let next = offset + self.instruction_len(offset)?;
+            let target = |target: usize| {
+                if target < code.len() && !boundaries[target] {
+                    return Err(self.error(offset, VerifyErrorKind::JumpIntoInstruction { target }));
+                }
+                Ok(target)
+            };
+            match flow {
+                Flow::Next => pending.push((next, after, captured)),
+                Flow::Jump(jump) => pending.push((target(jump)?, after, captured)),
+                Flow::Branch(jump) => {
+                    pending.push((target(jump)?, after, captured.clone()));
+                    pending.push((next, after, captured));
+                }
+                Flow::Return => {}
+            }
+        }
+        Ok(max_depth)
+    }
+
+    fn instruction_len(&self, offset: usize) -> Result<usize, VerifyError> {
+        let byte = self.chunk.code[offset];
+        let op_code = OpCode::try_from(byte)
+            .map_err(|_| self.error(offset, VerifyErrorKind::UnknownOpcode(byte)))?;
+        if op_code == OpCode::Closure {
+            if let Some(index) = self.chunk.code.get(offset + 1) {
+                self.function_constant(offset, *index as usize)?;
+            }
+        }
+        self.chunk
+            .instruction_len(offset)
+            .filter(|len| offset + len <= self.chunk.code.len())
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::TruncatedInstruction(op_code)))
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<&'a Value, VerifyError> {
+        let constants = &self.chunk.constants;
+        constants.get(index).ok_or_else(|| {
+            self.error(
+                offset,
+                VerifyErrorKind::ConstantOutOfRange {
+                    index,
+                    len: constants.len(),
+                },
+            )
+        })
+    }
+
+    fn string_constant(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        match self.constant(offset, index)?.as_str() {
+            Some(_) => Ok(()),
+            None => Err(self.error(offset, VerifyErrorKind::ExpectedString { index })),
+        }
+    }
+
+    fn function_constant(&self, offset: usize, index: usize) -> Result<&'a Function, VerifyError> {
+        match self.constant(offset, index)?.as_obj() {
+            Some(Obj::Function(function)) => Ok(function),
+            _ => Err(self.error(offset, VerifyErrorKind::ExpectedFunction { index })),
+        }
+    }
+
+    /// Updates the slots with open upvalues for the instruction at `offset`,
+    /// which takes the stack from `depth` to `after` values. The VM only
+    /// closes upvalues on `CloseUpvalue` and `Return`, so any other
+    /// instruction dropping a captured slot would leave its upvalue pointing
+    /// past the stack.
+    fn track_captures(
+        &self,
+        offset: usize,
+        depth: usize,
+        after: usize,
+        captured: &mut BTreeSet<usize>,
+    ) -> Result<(), VerifyError> {
+        let code = &self.chunk.code;
+        let kept = match OpCode::try_from(code[offset]).unwrap() {
+            OpCode::Closure => {
+                let descriptors = &code[offset + 2..offset + self.instruction_len(offset)?];
+                for pair in descriptors.chunks(2).filter(|pair| pair[0] == 1) {
+                    captured.insert(pair[1] as usize);
+                }
+                after
+            }
+            OpCode::CloseUpvalue => {
+                captured.remove(&(depth - 1));
+                after
+            }
+            // `Return` closes the frame's upvalues, but only after popping
+            // its result.
+            OpCode::Return => depth - 1,
+            _ => after,
+        };
+        match captured.range(kept..).next() {
+            Some(&slot) => Err(self.error(offset, VerifyErrorKind::CapturedLocalPopped { slot })),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks one instruction's operands against a stack of `depth` values,
+    /// returning the depth afterwards and where control goes.
+    fn instruction(&self, offset: usize, depth: usize) -> Result<(usize, Flow), VerifyError> {
+        let code = &self.chunk.code;
+        let op_code = OpCode::try_from(code[offset]).unwrap();
+        let byte = || code[offset + 1] as usize;
+        let short = || (byte() << 8) | code[offset + 2] as usize;
+        let needs = |needed: usize| {
+            if depth < needed {
+                Err(self.error(offset, VerifyErrorKind::StackUnderflow { needed, depth }))
+            } else {
+                Ok(())
+            }
+        };
+        let local = |slot: usize, depth: usize| {
+            if slot >= depth {
+                Err(self.error(offset, VerifyErrorKind::LocalOutOfRange { slot, depth }))
+            } else {
+                Ok(())
+            }
+        };
+        let upvalue = |index: usize| {
+            let count = self.function.upvalue_count;
+            if index >= count {
+                Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange { index, count }))
+            } else {
+                Ok(())
+            }
+        };
+        // Values popped, values pushed.
+        let (pops, pushes) = match op_code {
+            OpCode::Constant => {
+                self.constant(offset, byte())?;
+                (0, 1)
+            }
+            OpCode::ConstantLong => {
+                self.constant(offset, self.chunk.long_constant_index(offset).unwrap())?;
+                (0, 1)
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1),
+            OpCode::Negate | OpCode::Not => (1, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less => (2, 1),
+            OpCode::Print | OpCode::Pop | OpCode::CloseUpvalue => (1, 0),
+            OpCode::PopN => (byte(), 0),
+            OpCode::DefineGlobal => {
+                self.string_constant(offset, byte())?;
+                (1, 0)
+            }
+            OpCode::GetGlobal | OpCode::Class => {
+                self.string_constant(offset, byte())?;
+                (0, 1)
+            }
+            OpCode::SetGlobal | OpCode::GetProperty => {
+                self.string_constant(offset, byte())?;
+                (1, 1)
+            }
+            OpCode::SetProperty | OpCode::GetSuper => {
+                self.string_constant(offset, byte())?;
+                (2, 1)
+            }
+            OpCode::Method => {
+                self.string_constant(offset, byte())?;
+                (2, 1)
+            }
+            OpCode::Inherit => (2, 1),
+            OpCode::GetLocal => {
+                local(byte(), depth)?;
+                (0, 1)
+            }
+            OpCode::SetLocal => {
+                local(byte(), depth)?;
+                (1, 1)
+            }
+            OpCode::GetUpvalue => {
+                upvalue(byte())?;
+                (0, 1)
+            }
+            OpCode::SetUpvalue => {
+                upvalue(byte())?;
+                (1, 1)
+            }
+            OpCode::Call => (byte() + 1, 1),
+            OpCode::Closure => {
+                self.function_constant(offset, byte())?;
+                for pair in code[offset + 2..offset + self.instruction_len(offset)?].chunks(2) {
+                    match pair[0] {
+                        // A local function naming itself captures the slot
+                        // the closure is about to be pushed to.
+                        1 => local(pair[1] as usize, depth + 1)?,
+                        0 => upvalue(pair[1] as usize)?,
+                        byte => {
+                            return Err(
+                                self.error(offset, VerifyErrorKind::InvalidUpvalueDescriptor(byte))
+                            )
+                        }
+                    }
+                }
+                (0, 1)
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                needs(if op_code == OpCode::JumpIfFalse { 1 } else { 0 })?;
+                let next = (offset + 3) as isize;
+                let target = if op_code == OpCode::Loop {
+                    next - short() as isize
+                } else {
+                    next + short() as isize
+                };
+                if target < 0 || target as usize > code.len() {
+                    return Err(self.error(offset, VerifyErrorKind::JumpOutOfBounds { target }));
+                }
+                let target = target as usize;
+                let flow = match op_code {
+                    OpCode::JumpIfFalse => Flow::Branch(target),
+                    _ => Flow::Jump(target),
+                };
+                return Ok((depth, flow));
+            }
+            OpCode::Return => {
+                // A function's result replaces its callee slot in the
+                // caller, so it has to be above that slot. The script's
+                // frame is simply dropped.
+                match self.script {
+                    true => needs(1)?,
+                    false => needs(RESERVED_SLOTS + 1)?,
+                }
+                return Ok((depth, Flow::Return));
+            }
+        };
+        needs(pops)?;
+        Ok((depth - pops + pushes, Flow::Next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lowering::Lowered, optimizer::optimize};
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new_chunk();
+        chunk.put_constant(1.0.into()).unwrap();
+        chunk.put_constant(Value::string("name")).unwrap();
+        for byte in code {
+            chunk.write_operand(*byte, 1);
+        }
+        chunk
+    }
+
+    fn kind(code: &[u8]) -> VerifyErrorKind {
+        verify(&chunk(code)).unwrap_err().kind
+    }
+
+    use OpCode::*;
+
+    #[test]
+    fn accepts_compiled_programs() {
+        for source in [
+            "var a = 1; { var b = a + 2; print b; }",
+            "for (var i = 0; i < 10; i = i + 1) { if (i > 5 and i < 8) print i; else print -i; }",
+            "fun outer(x) { fun inner(y) { return x + y; } return inner; } print outer(1)(2);",
+            "class A { init(n) { this.n = n; } get() { return this.n; } }\n\
+             class B < A { get() { return super.get() * 2; } }\nprint B(3).get();",
+            "{ fun count(n) { if (n > 0) count(n - 1); } count(3); }",
+        ] {
+            let chunk = source.lower().unwrap();
+            assert!(verify(&chunk).is_ok(), "{}", source);
+        }
+        let chunk = "{ var a = 1; var b = 2; print a + b; }".lower().unwrap();
+        assert_eq!(verify(&chunk), Ok(5));
+    }
+
+    #[test]
+    fn accepts_sample_programs() {
+        for file in std::fs::read_dir("tests/programs").unwrap() {
+            let path = file.unwrap().path();
+            let source = std::fs::read_to_string(&path).unwrap();
+            if let Ok(chunk) = source.as_str().lower() {
+                assert_eq!(verify(&chunk).err(), None, "{}", path.display());
+                assert_eq!(verify(&optimize(&chunk)).err(), None, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_instructions() {
+        assert_eq!(kind(&[0xee]), VerifyErrorKind::UnknownOpcode(0xee));
+        assert_eq!(
+            kind(&[Constant as u8]),
+            VerifyErrorKind::TruncatedInstruction(Constant)
+        );
+        assert_eq!(
+            kind(&[Constant as u8, 2, Return as u8]),
+            VerifyErrorKind::ConstantOutOfRange { index: 2, len: 2 }
+        );
+        assert_eq!(
+            kind(&[GetGlobal as u8, 0, Return as u8]),
+            VerifyErrorKind::ExpectedString { index: 0 }
+        );
+        assert_eq!(
+            kind(&[Closure as u8, 1, Return as u8]),
+            VerifyErrorKind::ExpectedFunction { index: 1 }
+        );
+        assert_eq!(
+            kind(&[GetLocal as u8, 1, Return as u8]),
+            VerifyErrorKind::LocalOutOfRange { slot: 1, depth: 1 }
+        );
+        assert_eq!(
+            kind(&[GetUpvalue as u8, 0, Return as u8]),
+            VerifyErrorKind::UpvalueOutOfRange { index: 0, count: 0 }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_jumps() {
+        assert_eq!(
+            kind(&[Jump as u8, 0, 1, Constant as u8, 0, Return as u8]),
+            VerifyErrorKind::JumpIntoInstruction { target: 4 }
+        );
+        assert_eq!(
+            kind(&[Jump as u8, 0, 9, Return as u8]),
+            VerifyErrorKind::JumpOutOfBounds { target: 12 }
+        );
+        assert_eq!(
+            kind(&[Loop as u8, 0, 4]),
+            VerifyErrorKind::JumpOutOfBounds { target: -1 }
+        );
+        assert_eq!(kind(&[Jump as u8, 0, 0]), VerifyErrorKind::FallsOffEnd);
+    }
+
+    #[test]
+    fn checks_stack_depth() {
+        assert_eq!(
+            kind(&[Pop as u8, Pop as u8, Return as u8]),
+            VerifyErrorKind::StackUnderflow {
+                needed: 1,
+                depth: 0
+            }
+        );
+        assert_eq!(
+            kind(&[Nil as u8, Add as u8, Add as u8, Return as u8]),
+            VerifyErrorKind::StackUnderflow {
+                needed: 2,
+                depth: 1
+            }
+        );
+        // The false branch skips the push, so the paths meet unbalanced.
+        assert_eq!(
+            kind(&[True as u8, JumpIfFalse as u8, 0, 1, Nil as u8, Return as u8]),
+            VerifyErrorKind::StackMismatch {
+                expected: 3,
+                found: 2
+            }
+        );
+        assert_eq!(
+            verify(&chunk(&[Nil as u8, Nil as u8, PopN as u8, 2, Return as u8])),
+            Ok(3)
+        );
+    }
+
+    /// A script declaring 1000 functions, each inside the one before and
+    /// declaring it twice from one shared constant, around `innermost`.
+    fn nested_functions(innermost: &[u8]) -> Chunk {
+        let mut function = Function::new(Some("f"));
+        function.chunk = chunk(innermost);
+        for _ in 0..1000 {
+            let inner = Value::function(function);
+            let mut chunk = Chunk::new_chunk();
+            chunk.put_constant(inner.clone()).unwrap();
+            chunk.put_constant(inner).unwrap();
+            let closures = [Closure as u8, 0, Pop as u8, Closure as u8, 1, Pop as u8];
+            for byte in closures.into_iter().chain([Nil as u8, Return as u8]) {
+                chunk.write_operand(byte, 1);
+            }
+            function = Function::new(Some("f"));
+            function.chunk = chunk;
+        }
+        function.chunk
+    }
+
+    #[test]
+    fn verifies_nested_functions_without_recursing() {
+        assert_eq!(verify(&nested_functions(&[Nil as u8, Return as u8])), Ok(2));
+        assert_eq!(
+            verify(&nested_functions(&[Return as u8])),
+            Err(VerifyError {
+                function: "<fn f>".to_string(),
+                offset: 0,
+                kind: VerifyErrorKind::StackUnderflow {
+                    needed: 2,
+                    depth: 1
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn checks_captured_locals_are_closed() {
+        let mut function = Function::new(Some("inner"));
+        function.upvalue_count = 1;
+        function.chunk = chunk(&[Nil as u8, Return as u8]);
+        let script = |code: &[u8]| {
+            let mut script = chunk(&[]);
+            script
+                .put_constant(Value::function(function.clone()))
+                .unwrap();
+            for byte in code {
+                script.write_operand(*byte, 1);
+            }
+            verify(&script)
+        };
+        // Capturing a local, then closing it or returning, is fine.
+        assert!(script(&[
+            Nil as u8,
+            Closure as u8,
+            2,
+            1,
+            1,
+            Pop as u8,
+            CloseUpvalue as u8,
+            Nil as u8,
+            Return as u8
+        ])
+        .is_ok());
+        assert!(script(&[Nil as u8, Closure as u8, 2, 1, 1, Return as u8]).is_ok());
+        assert_eq!(
+            script(&[Closure as u8, 2, 1, 1, Return as u8])
+                .unwrap_err()
+                .kind,
+            VerifyErrorKind::CapturedLocalPopped { slot: 1 }
+        );
+        // A local function capturing the slot it is about to be stored in.
+        assert!(script(&[
+            Closure as u8,
+            2,
+            1,
+            1,
+            CloseUpvalue as u8,
+            Nil as u8,
+            Return as u8
+        ])
+        .is_ok());
+        assert_eq!(
+            script(&[
+                Nil as u8,
+                Closure as u8,
+                2,
+                1,
+                1,
+                PopN as u8,
+                2,
+                Nil as u8,
+                Return as u8
+            ])
+            .unwrap_err()
+            .kind,
+            VerifyErrorKind::CapturedLocalPopped { slot: 1 }
+        );
+    }
+
+    #[test]
+    fn functions_return_above_their_callee() {
+        let mut function = Function::new(Some("callee"));
+        function.chunk = chunk(&[Return as u8]);
+        let mut script = Chunk::new_chunk();
+        let index = script.put_constant(Value::function(function)).unwrap();
+        for byte in [Closure as u8, index as u8, Return as u8] {
+            script.write_operand(byte, 1);
+        }
+        assert_eq!(
+            verify(&script).unwrap_err().kind,
+            VerifyErrorKind::StackUnderflow {
+                needed: 2,
+                depth: 1
+            }
+        );
+    }
+
+    #[test]
+    fn only_the_top_level_chunk_is_the_script() {
+        let mut function = Function::new(None);
+        function.chunk = chunk(&[Return as u8]);
+        let mut script = Chunk::new_chunk();
+        let index = script.put_constant(Value::function(function)).unwrap();
+        for byte in [Closure as u8, index as u8, Return as u8] {
+            script.write_operand(byte, 1);
+        }
+        assert_eq!(
+            verify(&script).unwrap_err().kind,
+            VerifyErrorKind::StackUnderflow {
+                needed: 2,
+                depth: 1
+            }
+        );
+    }
+
+    #[test]
+    fn reports_nested_function() {
+        let mut function = Function::new(Some("broken"));
+        function.chunk = chunk(&[Pop as u8, Pop as u8, Return as u8]);
+        let mut script = Chunk::new_chunk();
+        let index = script.put_constant(Value::function(function)).unwrap();
+        for byte in [Closure as u8, index as u8, Return as u8] {
+            script.write_operand(byte, 1);
+        }
+        let error = verify(&script).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "<fn broken> 0001: Stack underflow: needs 1 values but depth is 0."
+        );
+    }
+}
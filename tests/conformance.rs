@@ -0,0 +1,163 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs, io};
+
+/// Exit codes `rlox run` uses, as in clox.
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+
+/// What the comments in a test say running it should do, following the
+/// craftinginterpreters test suite.
+#[derive(Default)]
+struct Expectations {
+    /// Lines from `// expect: ...`.
+    output: Vec<String>,
+    /// Line and message from `// Error at ...: ...` and
+    /// `// [line N] Error ...: ...`.
+    compile_errors: Vec<(usize, String)>,
+    /// Line and message from `// expect runtime error: ...`.
+    runtime_error: Option<(usize, String)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let Some((_, comment)) = line.split_once("// ") else {
+                continue;
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((index + 1, message.to_string()));
+            } else if let Some(error) = compile_error(comment, index + 1) {
+                expectations.compile_errors.push(error);
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        match (self.compile_errors.is_empty(), &self.runtime_error) {
+            (false, _) => EXIT_COMPILE_ERROR,
+            (true, Some(_)) => EXIT_RUNTIME_ERROR,
+            (true, None) => 0,
+        }
+    }
+}
+
+/// Parses `Error at 'x': message`, `Error at end: message` or
+/// `Error: message`, optionally prefixed by `[line N]` or `[c line N]` to
+/// expect it on another line. Errors only the Java implementation reports
+/// are ignored.
+fn compile_error(comment: &str, mut line: usize) -> Option<(usize, String)> {
+    let mut error = comment;
+    if let Some(rest) = comment.strip_prefix('[') {
+        let (prefix, rest) = rest.split_once("] ")?;
+        line = match prefix.split_whitespace().collect::<Vec<_>>()[..] {
+            ["line", number] | ["c", "line", number] => number.parse().ok()?,
+            _ => return None,
+        };
+        error = rest;
+    }
+    let error = error.strip_prefix("Error")?;
+    let message = match error.strip_prefix(" at '") {
+        Some(rest) => rest.split_once("': ")?.1,
+        None => error.split_once(": ")?.1,
+    };
+    Some((line, message.to_string()))
+}
+
+/// Runs the test at `path`, describing the first way it didn't do what its
+/// comments expect.
+fn check(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let expected = Expectations::parse(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("run")
+        .arg(path)
+        .output()
+        .map_err(|error| error.to_string())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let prefix = format!("{}:", path.display());
+    let compile_errors: Vec<_> = stderr
+        .lines()
+        .filter_map(|line| {
+            let (line, message) = line.strip_prefix(&prefix)?.split_once(": ")?;
+            Some((line.parse().ok()?, message.to_string()))
+        })
+        .collect();
+    if compile_errors != expected.compile_errors {
+        return Err(format!(
+            "expected compile errors {:?}, got {:?}",
+            expected.compile_errors, compile_errors
+        ));
+    }
+    if let Some((line, message)) = &expected.runtime_error {
+        let mut lines = stderr.lines();
+        if lines.next() != Some(message.as_str()) {
+            return Err(format!(
+                "expected runtime error {:?}, got {:?}",
+                message, stderr
+            ));
+        }
+        let trace = format!("[line {}]", line);
+        if !lines.any(|frame| frame.starts_with(&trace)) {
+            return Err(format!(
+                "expected {} in the stack trace, got {:?}",
+                trace, stderr
+            ));
+        }
+    }
+    let printed: Vec<_> = stdout.lines().collect();
+    if printed != expected.output {
+        return Err(format!(
+            "expected output {:?}, got {:?}",
+            expected.output, printed
+        ));
+    }
+    match output.status.code() {
+        Some(code) if code == expected.exit_code() => Ok(()),
+        code => Err(format!(
+            "expected exit code {}, got {:?} with {:?}",
+            expected.exit_code(),
+            code,
+            stderr
+        )),
+    }
+}
+
+fn lox_files(directory: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            lox_files(&path, files)?;
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Runs every program under `tests/conformance` against the expectations
+/// in its comments, reporting each file as it goes.
+#[test]
+fn conformance() -> io::Result<()> {
+    let mut files = vec![];
+    lox_files(Path::new("tests/conformance"), &mut files)?;
+    files.sort();
+    let mut failures = vec![];
+    for path in files.iter() {
+        match check(path) {
+            Ok(()) => println!("PASS {}", path.display()),
+            Err(reason) => {
+                println!("FAIL {}: {}", path.display(), reason);
+                failures.push(path.display().to_string());
+            }
+        }
+    }
+    println!("{} of {} passed", files.len() - failures.len(), files.len());
+    assert!(failures.is_empty(), "failed: {}", failures.join(", "));
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::{env, fs, process};
+
+const PROGRAM: &str = "\
+var base = 10;
+fun adder(n) {
+  fun add(m) {
+    var sum = n + m + base;
+    return sum;
+  }
+  return add;
+}
+var add5 = adder(5);
+print add5(1);
+print \"done\";
+";
+
+/// A scripted editor talking to `rlox dap`.
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: u64,
+    /// Events read while waiting for something else.
+    events: VecDeque<Value>,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .arg("dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            seq: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn read(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            assert!(
+                self.stdout.read_line(&mut header).unwrap() > 0,
+                "adapter hung up"
+            );
+            match header.trim_end().strip_prefix("Content-Length: ") {
+                Some(value) => length = value.parse().unwrap(),
+                None if header.trim_end().is_empty() => break,
+                None => panic!("unexpected header {:?}", header),
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Sends a request and returns its response, keeping events for later.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+        loop {
+            let message = self.read();
+            if message["type"] == "event" {
+                self.events.push_back(message);
+                continue;
+            }
+            assert_eq!(message["request_seq"], self.seq);
+            assert_eq!(message["command"], command);
+            return message;
+        }
+    }
+
+    /// The body of the next `event` event, skipping none of the others.
+    fn event(&mut self, event: &str) -> Value {
+        loop {
+            if let Some(index) = self.events.iter().position(|e| e["event"] == event) {
+                return self.events.remove(index).unwrap()["body"].take();
+            }
+            let message = self.read();
+            assert_eq!(message["type"], "event", "unexpected {}", message);
+            self.events.push_back(message);
+        }
+    }
+
+    /// Everything the script has printed so far.
+    fn output(&mut self) -> String {
+        let mut output = String::new();
+        self.events.retain(|event| {
+            if event["event"] != "output" {
+                return true;
+            }
+            output.push_str(event["body"]["output"].as_str().unwrap());
+            false
+        });
+        output
+    }
+
+    fn launch(&mut self, program: &PathBuf, stop_on_entry: bool, breakpoints: &[u64]) {
+        let response = self.request("initialize", json!({ "adapterID": "rlox" }));
+        assert_eq!(response["success"], true);
+        assert_eq!(response["body"]["supportsConfigurationDoneRequest"], true);
+        self.event("initialized");
+        let arguments = json!({ "program": program, "stopOnEntry": stop_on_entry });
+        assert_eq!(self.request("launch", arguments)["success"], true);
+        let breakpoints: Vec<_> = breakpoints
+            .iter()
+            .map(|line| json!({ "line": line }))
+            .collect();
+        let arguments = json!({ "source": { "path": program }, "breakpoints": breakpoints });
+        self.request("setBreakpoints", arguments);
+        self.request("configurationDone", json!({}));
+    }
+
+    fn variables(&mut self, reference: u64) -> Vec<(String, String)> {
+        let response = self.request("variables", json!({ "variablesReference": reference }));
+        response["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| {
+                (
+                    v["name"].as_str().unwrap().into(),
+                    v["value"].as_str().unwrap().into(),
+                )
+            })
+            .collect()
+    }
+
+    fn finish(mut self) -> ExitStatus {
+        drop(self.stdin);
+        self.child.wait().unwrap()
+    }
+}
+
+fn program(name: &str, source: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("rlox-dap-{}-{}.lox", process::id(), name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn breakpoints_and_variables() {
+    let path = program("breakpoints", PROGRAM);
+    let mut client = Client::start();
+    let response = client.request("initialize", json!({}));
+    assert_eq!(response["success"], true);
+    client.event("initialized");
+    client.request("launch", json!({ "program": path }));
+    let response = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": path }, "breakpoints": [{ "line": 5 }, { "line": 40 }] }),
+    );
+    assert_eq!(
+        response["body"]["breakpoints"],
+        json!([{ "verified": true, "line": 5 }, { "verified": false, "line": 40 }])
+    );
+    client.request("configurationDone", json!({}));
+
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["reason"], "breakpoint");
+    assert_eq!(stopped["threadId"], 1);
+    let threads = client.request("threads", json!({}));
+    assert_eq!(
+        threads["body"]["threads"],
+        json!([{ "id": 1, "name": "main" }])
+    );
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    let frames = trace["body"]["stackFrames"].as_array().unwrap();
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|f| (f["name"].clone(), f["line"].clone()))
+        .collect();
+    assert_eq!(
+        frames,
+        [(json!("add"), json!(5)), (json!("script"), json!(10))]
+    );
+    assert_eq!(
+        trace["body"]["stackFrames"][0]["source"]["path"],
+        json!(path)
+    );
+
+    let scopes = client.request("scopes", json!({ "frameId": 0 }));
+    let scopes = scopes["body"]["scopes"].as_array().unwrap();
+    let names: Vec<_> = scopes.iter().map(|scope| scope["name"].clone()).collect();
+    assert_eq!(names, ["Locals", "Closure", "Globals"]);
+    let reference = |index: usize| scopes[index]["variablesReference"].as_u64().unwrap();
+    let (locals, closure, globals) = (reference(0), reference(1), reference(2));
+    assert_eq!(
+        client.variables(locals),
+        pairs(&[("m", "1"), ("sum", "16")])
+    );
+    assert_eq!(client.variables(closure), pairs(&[("n", "5")]));
+    assert_eq!(
+        client.variables(globals),
+        pairs(&[
+            ("add5", "<fn add>"),
+            ("adder", "<fn adder>"),
+            ("base", "10")
+        ])
+    );
+    let scopes = client.request("scopes", json!({ "frameId": 1 }));
+    let names: Vec<_> = scopes["body"]["scopes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|scope| scope["name"].clone())
+        .collect();
+    assert_eq!(names, ["Locals", "Globals"]);
+
+    assert_eq!(
+        client.request("next", json!({ "threadId": 1 }))["success"],
+        true
+    );
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(client.output(), "16\n");
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(trace["body"]["stackFrames"][0]["line"], 11);
+
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("exited")["exitCode"], 0);
+    client.event("terminated");
+    assert_eq!(client.output(), "done\n");
+    let response = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(response["success"], false);
+    client.request("disconnect", json!({}));
+    assert!(client.finish().success());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stepping() {
+    let path = program("stepping", PROGRAM);
+    let mut client = Client::start();
+    client.launch(&path, true, &[4]);
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["reason"], "entry");
+    let line = |client: &mut Client| {
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        let frame = &trace["body"]["stackFrames"][0];
+        (
+            frame["name"].as_str().unwrap().to_string(),
+            frame["line"].as_u64().unwrap(),
+        )
+    };
+    assert_eq!(line(&mut client), ("script".to_string(), 1));
+
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(line(&mut client), ("add".to_string(), 4));
+    client.request("stepIn", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(line(&mut client), ("add".to_string(), 5));
+    client.request("stepOut", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(line(&mut client), ("script".to_string(), 10));
+
+    // Disconnecting stops the script without running the rest of it.
+    client.request("disconnect", json!({}));
+    assert!(client.finish().success());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn errors() {
+    let path = program("errors", "print 1;\nprint -\"x\";\n");
+    let mut client = Client::start();
+    client.request("initialize", json!({}));
+    let response = client.request("launch", json!({ "program": "/no/such/file.lox" }));
+    assert_eq!(response["success"], false);
+    assert!(response["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Could not read"));
+    let broken = program("broken", "print ;");
+    let response = client.request("launch", json!({ "program": broken }));
+    assert_eq!(response["success"], false);
+    assert!(response["message"]
+        .as_str()
+        .unwrap()
+        .contains("Expect expression."));
+    let response = client.request("evaluate", json!({ "expression": "1" }));
+    assert_eq!(response["message"], "Unsupported request 'evaluate'.");
+
+    client.request("launch", json!({ "program": path }));
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.event("exited")["exitCode"], 70);
+    client.event("terminated");
+    let output: Vec<_> = client
+        .events
+        .iter()
+        .filter(|event| event["event"] == "output")
+        .map(|event| {
+            (
+                event["body"]["category"].clone(),
+                event["body"]["output"].clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        output,
+        [
+            (json!("stdout"), json!("1\n")),
+            (json!("stderr"), json!("Operand must be a number.\n")),
+            (json!("stderr"), json!("[line 2] in script\n")),
+        ]
+    );
+    client.request("disconnect", json!({}));
+    assert!(client.finish().success());
+    fs::remove_file(path).unwrap();
+    fs::remove_file(broken).unwrap();
+}
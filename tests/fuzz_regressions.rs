@@ -0,0 +1,80 @@
+//! Replays every input a fuzz target has crashed on, kept in
+//! `fuzz/regressions/<target>`, through the same calls the target makes.
+
+use std::io;
+use std::path::PathBuf;
+use std::{fs, str};
+
+use rlox::internals::{optimize, Checked, Scanned, VM};
+use rlox::{verify, Capabilities, Chunk, Formatted, Lowered};
+
+fn inputs(target: &str) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut inputs: Vec<_> = fs::read_dir(format!("fuzz/regressions/{}", target))
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let bytes = fs::read(&path).unwrap();
+            (path, bytes)
+        })
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no regressions for {}", target);
+    inputs
+}
+
+fn sources(target: &str) -> Vec<(PathBuf, String)> {
+    inputs(target)
+        .into_iter()
+        .map(|(path, bytes)| (path, String::from_utf8(bytes).unwrap()))
+        .collect()
+}
+
+/// The VM the `run` and `deserialize` targets use: no host access, and
+/// limits so that no input can run for long.
+fn vm() -> VM {
+    VM::with_output(Box::new(io::sink()), Box::new(io::sink()))
+        .with_capabilities(Capabilities::sandboxed())
+        .with_fuel(100_000)
+        .with_max_heap(1 << 20)
+}
+
+#[test]
+fn scan() {
+    for (_, source) in sources("scan") {
+        source.as_str().scan();
+        let _ = source.as_str().format();
+    }
+}
+
+#[test]
+fn compile() {
+    for (path, source) in sources("compile") {
+        let source = source.as_str();
+        source.check();
+        if let Ok(chunk) = source.lower() {
+            assert_eq!(verify(&chunk).err(), None, "{}", path.display());
+            assert_eq!(verify(&optimize(&chunk)).err(), None, "{}", path.display());
+        }
+    }
+}
+
+#[test]
+fn run() {
+    for (_, source) in sources("scan").into_iter().chain(sources("compile")) {
+        let Ok(chunk) = source.as_str().lower() else {
+            continue;
+        };
+        for chunk in [optimize(&chunk), chunk] {
+            vm().interpret_chunk(&chunk);
+        }
+    }
+}
+
+#[test]
+fn deserialize() {
+    for (_, bytes) in inputs("deserialize") {
+        if let Ok(chunk) = Chunk::deserialize(&bytes) {
+            vm().interpret_chunk(&chunk);
+        }
+    }
+}
@@ -1,14 +1,20 @@
 use goldenfile::Mint;
-use rlox::chunk_printer::print_chunk;
-use rlox::compiler::Compiled;
-use rlox::scanner::Scanned;
-use std::io::{ErrorKind, Result, Write};
-use std::{fs, io};
+use rlox::internals::{print_chunk, Scanned};
+use rlox::{Formatted, Lowered};
+use std::io::{Result, Write};
+use std::process::Command;
+use std::{env, fs, io};
+
+/// A comment that keeps a program out of the `runs` goldens, for programs
+/// whose output isn't the same from run to run.
+const SKIP_RUN: &str = "// golden: skip-run";
 
 #[test]
 fn golden_tests() -> Result<()> {
     let mut scan_mint = Mint::new("tests/goldenfiles/scans");
     let mut compile_mint = Mint::new("tests/goldenfiles/chunks");
+    let mut format_mint = Mint::new("tests/goldenfiles/formatted");
+    let mut run_mint = Mint::new("tests/goldenfiles/runs");
     for file in fs::read_dir("tests/programs")? {
         let path = file?.path();
         let path_clone = path.clone();
@@ -19,16 +25,45 @@ fn golden_tests() -> Result<()> {
         for token in scanned {
             writeln!(minted, "{:#?}", token)?;
         }
-        if !vec!["num.lox".to_string()].contains(&file_name_string.to_string()) {
-            continue;
+        let mut format_minted = format_mint.new_goldenfile(file_name_string)?;
+        let formatted = program
+            .as_str()
+            .format()
+            .map_err(|e| io::Error::other(e.message))?;
+        write!(format_minted, "{}", formatted)?;
+        let reformatted = formatted
+            .as_str()
+            .format()
+            .map_err(|e| io::Error::other(e.message))?;
+        assert_eq!(
+            reformatted, formatted,
+            "formatting {} again changed it",
+            file_name_string
+        );
+        if !program.contains(SKIP_RUN) {
+            let mut run_minted = run_mint.new_goldenfile(file_name_string)?;
+            let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+                .arg("run")
+                .arg(&path)
+                .output()?;
+            match output.status.code() {
+                Some(code) => writeln!(run_minted, "exit status: {}", code)?,
+                None => writeln!(run_minted, "exit status: killed by a signal")?,
+            }
+            writeln!(run_minted, "--- stdout")?;
+            run_minted.write_all(&output.stdout)?;
+            writeln!(run_minted, "--- stderr")?;
+            run_minted.write_all(&output.stderr)?;
         }
         let mut compile_minted = compile_mint.new_goldenfile(file_name_string)?;
-
-        let scanned = program
-            .as_str()
-            .compile()
-            .map_err(|e| io::Error::new(ErrorKind::Other, e.message))?;
-        print_chunk(&scanned, &mut compile_minted, file_name_string)?;
+        match program.as_str().lower() {
+            Ok(chunk) => print_chunk(&chunk, &mut compile_minted, file_name_string)?,
+            Err(errors) => {
+                for error in errors.iter() {
+                    writeln!(compile_minted, "{}", error)?;
+                }
+            }
+        }
     }
     Ok(())
 }
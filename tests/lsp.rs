@@ -0,0 +1,56 @@
+use goldenfile::Mint;
+use std::io::{BufRead, BufReader, Read, Result, Write};
+use std::process::{Command, Stdio};
+use std::{env, fs, thread};
+
+/// Replays each fixture's client messages, one per line, into `rlox lsp`
+/// and records everything the server sends back.
+#[test]
+fn lsp_fixtures() -> Result<()> {
+    let mut mint = Mint::new("tests/goldenfiles/lsp");
+    for file in fs::read_dir("tests/lsp")? {
+        let path = file?.path();
+        let fixture = fs::read_to_string(&path)?;
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .arg("lsp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        // Written from another thread so a chatty server can't block on a
+        // full pipe while this one is still writing.
+        let writer = thread::spawn(move || -> Result<()> {
+            for message in fixture.lines() {
+                write!(
+                    stdin,
+                    "Content-Length: {}\r\n\r\n{}",
+                    message.len(),
+                    message
+                )?;
+            }
+            Ok(())
+        });
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let mut minted = mint.new_goldenfile(path.with_extension("json").file_name().unwrap())?;
+        loop {
+            let mut header = String::new();
+            if stdout.read_line(&mut header)? == 0 {
+                break;
+            }
+            let length: usize = header
+                .trim_end()
+                .strip_prefix("Content-Length: ")
+                .and_then(|length| length.parse().ok())
+                .expect("a Content-Length header");
+            stdout.read_line(&mut header)?;
+            let mut body = vec![0; length];
+            stdout.read_exact(&mut body)?;
+            let message: serde_json::Value = serde_json::from_slice(&body)?;
+            writeln!(minted, "{}", serde_json::to_string_pretty(&message)?)?;
+        }
+        writer.join().unwrap()?;
+        assert!(child.wait()?.success(), "{} didn't exit cleanly", file_name);
+    }
+    Ok(())
+}